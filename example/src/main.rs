@@ -1,4 +1,4 @@
-use vfs::filesys::Filesys;
+use vfs::filesys::{Filesys, FilesysBuilder};
 
 static mut FILESYS: Filesys = Filesys::init();
 
@@ -12,15 +12,14 @@ fn main() {
   unsafe {
     /* Initialise a new disk. Alternatively, load an existing one  */
     let _ = std::fs::remove_file(PATH);
-    FILESYS.new_disk(PATH, DISK_BLOCKS);
-    FILESYS.init_free_map();
+    FILESYS = FilesysBuilder::new(PATH, DISK_BLOCKS).build();
     
     /* File should not already exist */
     assert!(FILESYS.open_file("a.txt").is_none());
 
     /* Create a file to store our data */
-    let success = FILESYS.create_file("a.txt", SAMPLE_DATA.len() as u64);
-    assert!(success);
+    let created = FILESYS.create_file("a.txt", SAMPLE_DATA.len() as u64);
+    assert!(created.is_ok());
 
     /* We should see the file listed */
     let files = FILESYS.list("/").expect("directory exists");
@@ -46,6 +45,6 @@ fn main() {
     assert_eq!(SAMPLE_DATA, buf);
 
     /* Display number of read and write calls to DISK */
-    FILESYS.display_disk_stats();
+    FILESYS.print_disk_stats();
   }
 }
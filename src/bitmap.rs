@@ -9,7 +9,7 @@ pub struct Bitmap {
 }
 
 fn byte_count(count: Size) -> usize {
-  (count as usize).div_ceil(std::mem::size_of::<Elem>())
+  count.div_ceil(ELEM_BITS) as usize
 }
 
 fn byte_index(bit: Size) -> usize {
@@ -32,6 +32,17 @@ impl Bitmap {
     self.count
   }
 
+  /* Grow or shrink to `new_count` bits. New bits are clear. False if a set bit would be dropped */
+  pub fn resize(&mut self, new_count: Size) -> bool {
+    if self.iter_set().any(|bit| bit >= new_count) {
+      return false;
+    }
+
+    self.elems.resize(byte_count(new_count), 0);
+    self.count = new_count;
+    true
+  }
+
   pub fn test(&self, bit: Size) -> bool {
     assert!(bit < self.count);
 
@@ -72,6 +83,75 @@ impl Bitmap {
     *byte &= !mask;
   }
 
+  /* Set `len` bits from `start`, a whole element at a time where the range is aligned */
+  pub fn set_all(&mut self, start: Size, len: Size, value: bool) {
+    let end = start + len;
+    assert!(end <= self.count);
+
+    let mut bit = start;
+    while bit < end {
+      if bit.is_multiple_of(ELEM_BITS) && bit + ELEM_BITS <= end {
+        self.elems[byte_index(bit)] = if value { Elem::MAX } else { 0 };
+        bit += ELEM_BITS;
+      } else {
+        self.set(bit, value);
+        bit += 1;
+      }
+    }
+  }
+
+  pub fn set_range(&mut self, start: Size, len: Size) {
+    self.set_all(start, len, true);
+  }
+
+  pub fn clear_range(&mut self, start: Size, len: Size) {
+    self.set_all(start, len, false);
+  }
+
+  /* Indices of all set bits in ascending order, skipping elements with none set */
+  pub fn iter_set(&self) -> impl Iterator<Item = Size> + '_ {
+    self
+      .elems
+      .iter()
+      .enumerate()
+      .filter(|(_, &elem)| elem != 0)
+      .flat_map(|(idx, &elem)| {
+        (0..ELEM_BITS)
+          .filter(move |bit| elem & (1 << bit) != 0)
+          .map(move |bit| idx as Size * ELEM_BITS + bit)
+      })
+  }
+
+  /* Start and length of each maximal run of clear bits, in ascending order */
+  pub fn iter_clear_runs(&self) -> impl Iterator<Item = (Size, Size)> + '_ {
+    self
+      .iter_set()
+      .chain(std::iter::once(self.count))
+      .scan(0, |start, set| {
+        let run = (*start, set - *start);
+        *start = set + 1;
+        Some(run)
+      })
+      .filter(|&(_, len)| len > 0)
+  }
+
+  /* Little-endian serialisation, for storing the bitmap on disk */
+  pub fn to_bytes(&self) -> Vec<u8> {
+    self.elems.iter().flat_map(|e| e.to_le_bytes()).collect()
+  }
+
+  pub fn from_bytes(count: Size, bytes: &[u8]) -> Self {
+    let mut bitmap = Bitmap::new(count);
+    for (elem, chunk) in bitmap
+      .elems
+      .iter_mut()
+      .zip(bytes.chunks_exact(std::mem::size_of::<Elem>()))
+    {
+      *elem = Elem::from_le_bytes(chunk.try_into().expect("internal err"));
+    }
+    bitmap
+  }
+
   pub fn compare_and_flip(&mut self, bit: Size) -> bool {
     if !self.test(bit) {
       self.mark(bit);
@@ -81,3 +161,78 @@ impl Bitmap {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set_bits(bitmap: &Bitmap) -> Vec<Size> {
+    (0..bitmap.count()).filter(|&bit| bitmap.test(bit)).collect()
+  }
+
+  #[test]
+  fn set_range_changes_only_the_range_across_elements() {
+    let mut bitmap = Bitmap::new(200);
+    bitmap.set_range(30, 100);
+    assert_eq!(set_bits(&bitmap), (30..130).collect::<Vec<_>>());
+
+    bitmap.clear_range(31, 98);
+    assert_eq!(set_bits(&bitmap), vec![30, 129]);
+
+    bitmap.set_all(0, 200, true);
+    assert_eq!(set_bits(&bitmap).len(), 200);
+  }
+
+  #[test]
+  #[should_panic]
+  fn set_range_past_the_end_is_refused() {
+    Bitmap::new(64).set_range(60, 5);
+  }
+
+  #[test]
+  fn iter_set_yields_scattered_bits_in_order() {
+    let mut bitmap = Bitmap::new(300);
+    [299, 0, 33, 31, 160].iter().for_each(|&bit| bitmap.mark(bit));
+
+    assert_eq!(bitmap.iter_set().collect::<Vec<_>>(), vec![0, 31, 33, 160, 299]);
+    assert_eq!(Bitmap::new(300).iter_set().count(), 0);
+  }
+
+  #[test]
+  fn clear_runs_stop_at_set_bits_and_the_end() {
+    let mut bitmap = Bitmap::new(100);
+    bitmap.set_range(0, 10);
+    bitmap.set_range(40, 30);
+    bitmap.mark(99);
+    assert_eq!(bitmap.iter_clear_runs().collect::<Vec<_>>(), vec![(10, 30), (70, 29)]);
+
+    assert_eq!(Bitmap::new(70).iter_clear_runs().collect::<Vec<_>>(), vec![(0, 70)]);
+    bitmap.set_all(0, 100, true);
+    assert_eq!(bitmap.iter_clear_runs().count(), 0);
+  }
+
+  #[test]
+  fn growing_keeps_bits_and_clears_new_ones() {
+    let mut bitmap = Bitmap::new(40);
+    bitmap.mark(39);
+    assert!(bitmap.resize(100));
+
+    assert_eq!(bitmap.count(), 100);
+    assert_eq!(bitmap.iter_set().collect::<Vec<_>>(), vec![39]);
+    assert!((40..100).all(|bit| !bitmap.test(bit)));
+  }
+
+  #[test]
+  fn shrinking_past_a_set_bit_is_rejected() {
+    let mut bitmap = Bitmap::new(100);
+    bitmap.mark(70);
+
+    assert!(!bitmap.resize(64));
+    assert_eq!(bitmap.count(), 100);
+    assert!(bitmap.test(70));
+
+    bitmap.reset(70);
+    assert!(bitmap.resize(64));
+    assert_eq!(bitmap.count(), 64);
+  }
+}
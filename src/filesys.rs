@@ -1,30 +1,195 @@
-use crate::{Ofs, Size};
-use block::{BlockManager, DeviceType};
+use crate::{bitmap::Bitmap, Ofs, Size};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use block::{BlockManager, BlockOperations, CountedDisk, DeviceType};
+use cache::{FifoCacheDisk, LfuCacheDisk};
 use directory::Dir;
+#[cfg(any(test, feature = "faults"))]
+use faulty_disk::FaultyDisk;
 use free_map::FreeMap;
 use inode::InodeManager;
-use std::borrow::BorrowMut;
+use path_cache::PathCache;
 use vdisk::VDisk;
 use vfile::VFile;
 
+pub use block::{Block, IoCounts, TraceEvent, TraceOp, BLOCK_SIZE};
+pub use cache::{BufferCacheStrategy, CacheStats, EvictHook};
+#[cfg(any(test, feature = "faults"))]
+pub use faulty_disk::Fault;
+pub use free_map::AllocPolicy;
+pub use directory::{DirError, DirHash, NameError, NAME_MAX};
+pub use inode::{FileType, MAX_FILE_SIZE};
+pub use session::DiskSession;
+
 mod block;
+mod cache;
 mod directory;
+#[cfg(any(test, feature = "faults"))]
+mod faulty_disk;
 mod free_map;
 mod inode;
+mod path_cache;
+mod session;
+#[cfg(test)]
+mod tests;
 mod vdisk;
 mod vfile;
 
 pub struct Filesys<'a> {
   inodes: InodeManager,
   block_devs: BlockManager<'a>,
-  free_map: Option<FreeMap<'a>>,
+  free_map: Option<FreeMap>,
+  unsafe_raw: bool,
+  /* Usage ratio above which allocating operations raise `full_warning` */
+  full_threshold: Option<f64>,
+  full_warning: bool,
+  /* Operations staged since `begin`, if a transaction is open */
+  transaction: Option<Vec<StagedOp>>,
+  sync_policy: SyncPolicy,
+  /* Sync after every directory change, whatever `sync_policy` says about data */
+  sync_dirs: bool,
+  /* Recent lookups by `resolve` and `open_file`, dropped when the path is renamed or removed */
+  path_cache: PathCache,
+  /* State of each disk mounted by name, swapped with the fields above while it is in use */
+  mounts: Vec<(String, Volume)>,
+}
+
+/* What a `Filesys` keeps for each disk rather than for itself */
+struct Volume {
+  inodes: InodeManager,
+  free_map: Option<FreeMap>,
+  path_cache: PathCache,
+}
+
+/* When metadata and data are pushed to the host without an explicit `sync` */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SyncPolicy {
+  /* Only on `sync`, or when the cache is dropped */
+  #[default]
+  Never,
+  /* Whenever a handle is closed */
+  OnClose,
+  /* After every creation, write and close */
+  Always,
+}
+
+/* An operation held back until its transaction commits */
+enum StagedOp {
+  Create { path: String, data: Vec<u8> },
+  Write { path: String, offset: Ofs, data: Vec<u8> },
+  Remove { path: String },
+}
+
+/* Puts back what an applied operation changed, if a later one in the same commit fails */
+enum UndoOp {
+  Remove { path: String },
+  Restore { path: String, data: Vec<u8> },
+  /* The bytes overwritten at `offset`, and the length before any growth */
+  Unwrite { path: String, offset: Ofs, data: Vec<u8>, len: Size },
+}
+
+/* Formats a new disk and initialises the free map in a single step */
+pub struct FilesysBuilder<'p> {
+  host_path: &'p str,
+  block_count: Size,
+  cache: BufferCacheStrategy,
+  evict_hook: Option<EvictHook>,
+}
+
+/* Outcome of the integrity pass over a loaded image */
+#[derive(Debug, Default)]
+pub struct LoadReport {
+  /* Reserved or directory-referenced inodes whose magic is wrong */
+  pub bad_inodes: Vec<Size>,
+  /* Blocks marked in the free map that no inode references */
+  pub leaked_blocks: Vec<Size>,
+  /* Blocks an inode references that the free map considers free */
+  pub unmarked_blocks: Vec<Size>,
+}
+
+/* Metadata of a single file */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileStat {
+  pub inumber: Size,
+  pub file_type: FileType,
+  pub len: Size,
+  /* Blocks owned by the file, including the inode and pointer blocks */
+  pub blocks: Size,
 }
 
+/* Everything a single lookup of a path learns about it */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedPath {
+  pub inumber: Size,
+  /* Inode of the directory holding the entry */
+  pub parent: Size,
+  /* Name of the entry within its parent */
+  pub name: String,
+  pub file_type: FileType,
+}
+
+/* Usage summary of the disk, as reported by `disk_info` */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskInfo {
+  pub total_blocks: Size,
+  /* Unallocated blocks that can hold data, which leaves out any inode table */
+  pub free_blocks: Size,
+  /* Unused inode table slots, or `free_blocks` when inodes share the data blocks */
+  pub free_inodes: Size,
+  pub used_blocks: Size,
+  pub block_size: Size,
+  /* Distinct regular files currently open, however many handles each has */
+  pub open_files: usize,
+}
+
+/* How scattered the files in the root directory are, as reported by `fragmentation_report` */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FragReport {
+  /* Each file with the number of contiguous runs its blocks form, excluding the inode block */
+  pub files: Vec<(String, usize)>,
+  /* Fraction of consecutive block pairs, across all files, that are not adjacent on disk */
+  pub score: f64,
+}
+
+/* An open inode, as listed by `open_handles` */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenHandleInfo {
+  pub inumber: Size,
+  pub open_count: usize,
+  /* None for an inode no directory names, such as the free map or a removed file */
+  pub path: Option<String>,
+}
+
+/* Why `rename` refused, leaving both names as they were */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameError {
+  Name(NameError),
+  /* Nothing is named `from` */
+  NotFound,
+  /* `to` exists and `overwrite` was not set */
+  Exists,
+  /* `to` names something other than a regular file, which is never replaced */
+  NotRegular,
+  /* A transaction is open, and names only change when it commits */
+  InTransaction,
+}
+
+const PATH_CACHE_CAPACITY: usize = 32;
+
 const ROOT_INODE: Size = 0;
 const FREE_MAP_INODE: Size = 1;
 
 const NO_DISK_ERR: &str = "disk not found";
 const NO_FREE_MAP_ERR: &str = "free map not initialised";
+const NO_SCRATCH_ERR: &str = "scratch device not found";
+const NO_TRANSACTION_ERR: &str = "no transaction in progress";
+const CREATED_ERR: &str = "internal error: created file not found";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/* Whether debug builds verify each host write and the `debug` feature logs inode I/O */
+static DEBUG_IO: AtomicBool = AtomicBool::new(true);
 
 impl<'a> Filesys<'a> {
   
@@ -37,46 +202,379 @@ impl<'a> Filesys<'a> {
       inodes: InodeManager::init(),
       block_devs: BlockManager::init(),
       free_map: None,
+      unsafe_raw: false,
+      full_threshold: None,
+      full_warning: false,
+      transaction: None,
+      sync_policy: SyncPolicy::Never,
+      sync_dirs: false,
+      path_cache: PathCache::init(PATH_CACHE_CAPACITY),
+      mounts: Vec::new(),
+    }
+  }
+
+  pub fn new_disk(&mut self, host_path: &str, disk_block_count: Size) {
+    self.new_cached_disk(host_path, disk_block_count, BufferCacheStrategy::None);
+  }
+
+  /* As `new_disk`, with a buffer cache in front of the host file */
+  pub fn new_cached_disk(
+    &mut self,
+    host_path: &str,
+    disk_block_count: Size,
+    strategy: BufferCacheStrategy,
+  ) {
+    let vdisk = CountedDisk::new(VDisk::new(host_path, disk_block_count));
+
+    match strategy {
+      BufferCacheStrategy::None => self.format_disk(vdisk, disk_block_count),
+      BufferCacheStrategy::Lfu { capacity } => {
+        self.format_disk(LfuCacheDisk::new(vdisk, capacity), disk_block_count)
+      }
+      BufferCacheStrategy::Fifo { capacity } => {
+        self.format_disk(FifoCacheDisk::new(vdisk, capacity), disk_block_count)
+      }
     }
   }
 
-  pub fn new_disk(&'a mut self, host_path: &str, disk_block_count: Size) {
-    let vdisk = VDisk::new(host_path, disk_block_count);
+  /* As `new_disk`, with every access passing through a `FaultyDisk` */
+  #[cfg(any(test, feature = "faults"))]
+  pub fn new_faulty_disk(&mut self, host_path: &str, disk_block_count: Size, fault: Fault) {
+    let vdisk = CountedDisk::new(VDisk::new(host_path, disk_block_count));
+    self.format_disk(FaultyDisk::new(vdisk, fault), disk_block_count);
+  }
+
+  fn format_disk<B: BlockOperations + 'a>(&mut self, ops: B, disk_block_count: Size) {
+    self
+      .block_devs
+      .register("DISK", disk_block_count, ops, DeviceType::Disk);
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    self.inodes.format_inode(ROOT_INODE, FileType::Directory, disk);
+    self.inodes.format_inode(FREE_MAP_INODE, FileType::Regular, disk);
+    self.pin_root();
+  }
+
+  /*
+    Directory indexes are not persisted, and are rebuilt by scanning a directory the first time it
+    is used after its inode is opened. The root is held open for as long as the disk is mounted, so
+    its index is built once per mount rather than on every operation
+  */
+  fn pin_root(&mut self) {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    self.inodes.open_pinned(ROOT_INODE, disk);
+  }
+
+  /*
+    Load an existing image, reading the free map back from the state saved by `sync`, if any. The
+    buffer cache is chosen as in `new_cached_disk`
+  */
+  pub fn load_disk(&mut self, host_path: &str, strategy: BufferCacheStrategy) {
+    let (vdisk, disk_block_count) = VDisk::identify(host_path);
+    let vdisk = CountedDisk::new(vdisk);
+
+    match strategy {
+      BufferCacheStrategy::None => self.open_disk(vdisk, disk_block_count),
+      BufferCacheStrategy::Lfu { capacity } => {
+        self.open_disk(LfuCacheDisk::new(vdisk, capacity), disk_block_count)
+      }
+      BufferCacheStrategy::Fifo { capacity } => {
+        self.open_disk(FifoCacheDisk::new(vdisk, capacity), disk_block_count)
+      }
+    }
+  }
 
+  fn open_disk<B: BlockOperations + 'a>(&mut self, ops: B, disk_block_count: Size) {
     self
       .block_devs
-      .register("DISK", disk_block_count, vdisk, DeviceType::Disk);
+      .register("DISK", disk_block_count, ops, DeviceType::Disk);
+    self.open_volume(disk_block_count);
+  }
+
+  /* Read the free map of the disk just registered and pin its root */
+  fn open_volume(&mut self, disk_block_count: Size) {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let inode = self.inodes.open_inode(FREE_MAP_INODE, disk);
+    let synced = inode.borrow().length() > 0;
+    self.free_map = Some(FreeMap::open(&inode.borrow(), disk_block_count, disk));
+    self.inodes.close_inumber(FREE_MAP_INODE);
+    self.path_cache.clear();
+    self.pin_root();
+
+    /*
+      The free map's file stays empty until the first sync, so on an image never synced every block
+      would load as free. Rebuild the map from the blocks reachable from the root instead
+    */
+    if !synced {
+      let unmarked = self.integrity_report().unmarked_blocks;
+      let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+      unmarked
+        .into_iter()
+        .filter(|&b| b < disk_block_count)
+        .for_each(|b| free_map.mark_allocated(b));
+    }
   }
 
-  pub fn load_disk(&'a mut self, host_path: &str) {
+  /*
+    Mounts
+    Further images can be loaded alongside the disk, each under a name. `with_mount` runs
+    operations against one of them, and everything else uses the disk. Options such as the sync
+    policy are the filesystem's own and apply to every volume
+  */
+
+  /*
+    Load an existing image under `name`, as `load_disk` would. False if the name is taken or there
+    is no disk loaded to mount it beside
+  */
+  pub fn mount(&mut self, name: &str, host_path: &str, strategy: BufferCacheStrategy) -> bool {
+    if self.mounts.iter().any(|(n, _)| n == name)
+      || self.block_devs.get_by_role(DeviceType::Disk).is_none()
+    {
+      return false;
+    }
+
     let (vdisk, disk_block_count) = VDisk::identify(host_path);
+    let vdisk = CountedDisk::new(vdisk);
+
+    let mounted = match strategy {
+      BufferCacheStrategy::None => {
+        self
+          .block_devs
+          .mount(name, disk_block_count, vdisk, DeviceType::Disk)
+      }
+      BufferCacheStrategy::Lfu { capacity } => self.block_devs.mount(
+        name,
+        disk_block_count,
+        LfuCacheDisk::new(vdisk, capacity),
+        DeviceType::Disk,
+      ),
+      BufferCacheStrategy::Fifo { capacity } => self.block_devs.mount(
+        name,
+        disk_block_count,
+        FifoCacheDisk::new(vdisk, capacity),
+        DeviceType::Disk,
+      ),
+    };
+    assert!(mounted);
 
+    self.mounts.push((
+      name.to_string(),
+      Volume {
+        inodes: InodeManager::init(),
+        free_map: None,
+        path_cache: PathCache::init(PATH_CACHE_CAPACITY),
+      },
+    ));
     self
+      .with_mount(name, |filesys| filesys.open_volume(disk_block_count))
+      .is_some()
+  }
+
+  /* Sync the volume mounted as `name` and drop it. False if nothing is mounted under it */
+  pub fn unmount(&mut self, name: &str) -> bool {
+    if self.with_mount(name, |filesys| filesys.sync()).is_none() {
+      return false;
+    }
+
+    self.mounts.retain(|(n, _)| n != name);
+    self.block_devs.unmount(name)
+  }
+
+  /*
+    Run `f` with the volume mounted as `name` in place of the disk, so every operation it makes
+    reaches that volume. None if nothing is mounted under it, or a transaction is open on the disk
+  */
+  pub fn with_mount<R>(&mut self, name: &str, f: impl FnOnce(&mut Self) -> R) -> Option<R> {
+    if self.staging() || !self.swap_volume(name) {
+      return None;
+    }
+
+    let result = f(self);
+    assert!(self.swap_volume(name));
+    Some(result)
+  }
+
+  /* Exchange the disk and its state with the volume mounted as `name`. Swapping again undoes it */
+  fn swap_volume(&mut self, name: &str) -> bool {
+    let Some((_, volume)) = self.mounts.iter_mut().find(|(n, _)| n == name) else {
+      return false;
+    };
+    if !self.block_devs.swap_mounted(name) {
+      return false;
+    }
+
+    std::mem::swap(&mut self.inodes, &mut volume.inodes);
+    std::mem::swap(&mut self.free_map, &mut volume.free_map);
+    std::mem::swap(&mut self.path_cache, &mut volume.path_cache);
+    true
+  }
+
+  /*
+    Copy a regular file between volumes, None naming the disk itself. False if `path` is missing,
+    a volume is not mounted, or `dest_path` could not be created
+  */
+  pub fn copy_between(
+    &mut self,
+    from: Option<&str>,
+    path: &str,
+    to: Option<&str>,
+    dest_path: &str,
+  ) -> bool {
+    let data = match from {
+      None => self.read_regular(path),
+      Some(name) => self.with_mount(name, |filesys| filesys.read_regular(path)).flatten(),
+    };
+    let Some(data) = data else {
+      return false;
+    };
+
+    match to {
+      None => self.create_file_with_data(dest_path, &data).is_ok(),
+      Some(name) => self
+        .with_mount(name, |filesys| filesys.create_file_with_data(dest_path, &data).is_ok())
+        .unwrap_or(false),
+    }
+  }
+
+  /* As `load_disk` without a buffer cache, then check the image with a lightweight fsck */
+  pub fn load_disk_verified(&mut self, host_path: &str) -> LoadReport {
+    self.load_disk(host_path, BufferCacheStrategy::None);
+    self.integrity_report()
+  }
+
+  /* Compare the free map against the blocks reachable from the root and free-map inodes */
+  fn integrity_report(&mut self) -> LoadReport {
+    let disk = self
       .block_devs
-      .register("DISK", disk_block_count, vdisk, DeviceType::Disk);
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_ref().expect(NO_FREE_MAP_ERR);
+
+    let mut report = LoadReport::default();
+    let mut referenced = Bitmap::new(disk.max_size());
+
+    let mut inumbers = vec![ROOT_INODE, FREE_MAP_INODE];
+    if self.inodes.inode_blocks(ROOT_INODE, disk).is_some() {
+      let root = Dir::open_root(&mut self.inodes, disk);
+      inumbers.extend(root.entries(disk).into_iter().map(|(_, inumber)| inumber));
+    }
+
+    for inumber in inumbers {
+      let in_range = inumber < referenced.count();
+      let Some(blocks) = in_range.then(|| self.inodes.inode_blocks(inumber, disk)).flatten() else {
+        report.bad_inodes.push(inumber);
+        continue;
+      };
 
-    todo!("ensure free map reads from disk")
+      for block in blocks {
+        if block < referenced.count() {
+          referenced.mark(block);
+        } else {
+          report.unmarked_blocks.push(block);
+        }
+      }
+    }
+
+    report.leaked_blocks.extend(free_map.allocated_blocks().filter(|&b| !referenced.test(b)));
+    report.unmarked_blocks.extend(referenced.iter_set().filter(|&b| !free_map.is_allocated(b)));
+
+    report
+  }
+
+  /*
+    Blocks where the free map disagrees with a walk of the inode tree, in order: either allocated
+    but unreferenced, or referenced but free. Blocks kept by `reserve_block` are not counted
+  */
+  pub fn verify_free_map(&mut self) -> Vec<Size> {
+    let report = self.integrity_report();
+    let free_map = self.free_map.as_ref().expect(NO_FREE_MAP_ERR);
+
+    let mut mismatches: Vec<Size> = report
+      .leaked_blocks
+      .into_iter()
+      .filter(|&b| !free_map.is_reserved(b))
+      .chain(report.unmarked_blocks)
+      .collect();
+    mismatches.sort_unstable();
+    mismatches.dedup();
+    mismatches
   }
 
-  pub fn init_free_map(&'a mut self) {
+  /*
+    Drop directory entries that do not point at an intact inode, such as one whose creation was cut
+    short, returning how many were dropped. Directories are not nested yet, so only the root is
+    scanned
+  */
+  pub fn repair_dangling_entries(&mut self) -> usize {
+    if self.staging() {
+      return 0;
+    }
+
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    let block_count = disk.max_size();
+    let mut dir = Dir::open_root(&mut self.inodes, disk);
+    let dangling: Vec<String> = dir
+      .entries(disk)
+      .into_iter()
+      .filter(|&(_, inumber)| !inode::holds_inode(inumber, disk))
+      .map(|(name, _)| name)
+      .collect();
 
-    let inode = self.inodes.open_inode(FREE_MAP_INODE, disk);
-    let file = VFile::open(inode);
+    for name in &dangling {
+      dir.remove(name, disk);
+      self.path_cache.invalidate(name);
+    }
+
+    if !dangling.is_empty() {
+      self.sync_dir_update();
+    }
+    dangling.len()
+  }
+
+  pub fn init_free_map(&mut self) {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let block_count = disk.max_size();
 
-    self.free_map = Some(FreeMap::init(file, block_count));
+    self.free_map = Some(FreeMap::init(FREE_MAP_INODE, block_count));
   }
 
   /*
     File operations
   */
 
-  pub fn create_file(&'a mut self, path: &str, length: Size) -> bool {
+  /*
+    Run `f` against the disk, free map and inodes under a single borrow. None while a transaction
+    is open, as the session changes the disk directly
+  */
+  pub fn with_disk<R>(&mut self, f: impl FnOnce(&mut DiskSession<'_, 'a>) -> R) -> Option<R> {
+    if self.staging() {
+      return None;
+    }
+    Some(self.session(f))
+  }
+
+  /* As `with_disk`, for callers that have already checked the operation is allowed */
+  fn session<R>(&mut self, f: impl FnOnce(&mut DiskSession<'_, 'a>) -> R) -> R {
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
@@ -84,77 +582,1407 @@ impl<'a> Filesys<'a> {
 
     let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
 
-    let inode = self
-      .inodes
-      .borrow_mut()
-      .create_inode(length, disk, free_map);
-    let inumber = inode.borrow().inumber();
+    let result = f(&mut DiskSession::new(
+      &mut self.inodes,
+      free_map,
+      disk,
+      &mut self.path_cache,
+    ));
 
-    if let Some(mut dir) = Dir::open_path(&mut self.inodes, disk, path) {
-      dir.add(path, inumber, free_map, disk)
-    } else {
-      false
+    self.check_full();
+    result
+  }
+
+  /*
+    Why `path` would be refused as a name by `create_file`, `rename` and the like, which only
+    report false
+  */
+  pub fn check_name(path: &str) -> Result<(), NameError> {
+    directory::check_name(path)
+  }
+
+  /*
+    Largest file the inode's direct, indirect and doubly indirect pointers can address, whatever
+    the size of the disk. Creating or growing a file past it fails
+  */
+  pub const fn max_file_size() -> Size {
+    MAX_FILE_SIZE
+  }
+
+  /* Create `path` as a file of `length` zero bytes, saying why if it could not be */
+  pub fn create_file(&mut self, path: &str, length: Size) -> Result<(), DirError> {
+    if self.staging() {
+      return Err(DirError::InTransaction);
+    }
+
+    self.session(|session| session.create_file(path, length))?;
+
+    if self.sync_dirs || self.sync_policy == SyncPolicy::Always {
+      self.sync();
     }
+    Ok(())
   }
 
-  pub fn open_file(&'a mut self, path: &str) -> Option<VFile<'a>> {
+  /* Create `path` holding exactly `data`. Nothing is left behind on failure */
+  pub fn create_file_with_data(&mut self, path: &str, data: &[u8]) -> Result<(), DirError> {
+    self.create_file(path, data.len() as Size)?;
+
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
-    dir
-      .open_file(path, disk)
-      .map(|i| VFile::open(self.inodes.open_inode(i, disk)))
+    let inumber = Dir::open_path(&mut self.inodes, disk, path)
+      .and_then(|dir| dir.open_file(path, disk))
+      .expect(CREATED_ERR);
+
+    /* Every block was allocated up front, so the write cannot run short */
+    let inode = self.inodes.open_inode(inumber, disk);
+    inode.borrow().write_at(data, 0, disk);
+    self.inodes.close_inumber(inumber);
+
+    if self.sync_policy == SyncPolicy::Always {
+      self.sync();
+    }
+    Ok(())
+  }
+
+  /*
+    Copy a regular file into another filesystem, which may be on a different image. For an image
+    mounted on this one, see `copy_between`. False if `path` is missing or `dest_path` could not be
+    created
+  */
+  pub fn copy_file_to(&mut self, path: &str, dest: &mut Filesys, dest_path: &str) -> bool {
+    self
+      .read_regular(path)
+      .is_some_and(|data| dest.create_file_with_data(dest_path, &data).is_ok())
+  }
+
+  /* The whole contents of `path`, if it is a regular file */
+  fn read_regular(&mut self, path: &str) -> Option<Vec<u8>> {
+    let len = self
+      .stat(path)
+      .filter(|s| s.file_type == FileType::Regular)?
+      .len;
+    self.read_range(path, 0, len)
+  }
+
+  /*
+    Write a new image at `new_host_path` holding only the live files, packed from the start of the
+    disk and truncated just past the last block in use. False if any file could not be copied
+  */
+  pub fn clone_to(&mut self, new_host_path: &str) -> bool {
+    let block_count = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .max_size();
+
+    let names: Vec<String> = self.iter_files().map(|(name, _)| name).collect();
+
+    let mut clone: Filesys = FilesysBuilder::new(new_host_path, block_count).build();
+    clone.set_alloc_policy(AllocPolicy::FirstFit);
+
+    if !names
+      .iter()
+      .all(|name| self.copy_file_to(name, &mut clone, name))
+    {
+      return false;
+    }
+
+    /* Nothing to reclaim only if the clone is as full as the original, which is still a clone */
+    clone.shrink_disk();
+    clone.sync()
+  }
+
+  /*
+    Replace the contents of an existing regular file with `data`, as if written to a temporary file
+    renamed over it: a crash part way leaves the old contents intact. False if `path` is not a
+    regular file or the new contents do not fit, in which case nothing changes
+  */
+  pub fn rewrite_file(&mut self, path: &str, data: &[u8]) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let rewritten = self.session(|session| session.rewrite(path, data));
+
+    if rewritten && (self.sync_dirs || self.sync_policy == SyncPolicy::Always) {
+      self.sync();
+    }
+    rewritten
   }
 
-  pub fn file_read(&'a mut self, file: &mut VFile, buffer: &mut [u8], offset: Ofs) -> Ofs {
+  /* Create `path` as an empty file if it does not exist. Inodes carry no timestamps to update */
+  pub fn touch(&mut self, path: &str) -> Result<(), DirError> {
+    directory::check_name(path).map_err(DirError::Name)?;
+
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    file.read(buffer, offset, disk)
+    let exists = Dir::open_path(&mut self.inodes, disk, path)
+      .and_then(|dir| dir.open_file(path, disk))
+      .is_some();
+    if exists {
+      return Ok(());
+    }
+    self.create_file(path, 0)
   }
 
-  pub fn file_write(&'a mut self, file: &mut VFile, buffer: &[u8], offset: Ofs) -> Ofs {
+  pub fn open_file(&'a mut self, path: &str) -> Option<VFile<'a>> {
+    self.open_file_mode(path, true)
+  }
+
+  /* As `open_file`. Without `write`, writes through the handle are refused and write nothing */
+  pub fn open_file_mode(&'a mut self, path: &str, write: bool) -> Option<VFile<'a>> {
+    let inumber = self.lookup(path)?;
+
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    file.write(buffer, offset, disk)
-  }
+    /* Directories are not readable as plain files */
+    if self.inodes.file_type(inumber, disk) != Some(FileType::Regular) {
+      return None;
+    }
 
-  pub fn _remove_file(&mut self, _path: &str) -> bool {
-    todo!()
+    Some(VFile::open(self.inodes.open_verified(inumber, disk)?, write))
   }
 
   /*
-    Directory operations
+    An unnamed file for scratch data. It never appears in a directory, and its blocks are freed
+    when the handle is closed. Handles from `dup_file` keep it alive until they close too
   */
+  pub fn create_temp(&'a mut self) -> Option<VFile<'a>> {
+    if self.staging() {
+      return None;
+    }
 
-  pub fn list(&'a mut self, path: &str) -> Option<Vec<String>> {
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
-    Some(dir.list(disk))
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let inode = self.inodes.open_anonymous(FileType::Regular, disk, free_map)?;
+    Some(VFile::open(inode, true))
+  }
+
+  /* Each handle needs its own `close_file` */
+  pub fn dup_file(&'a mut self, file: &VFile) -> VFile<'a> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    file.dup(&mut self.inodes, disk)
+  }
+
+  pub fn file_read(&mut self, file: &mut VFile, buffer: &mut [u8], offset: Ofs) -> Ofs {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    file.read(buffer, offset, disk)
+  }
+
+  pub fn file_peek(&mut self, file: &VFile, buffer: &mut [u8]) -> Ofs {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    file.peek(buffer, disk)
+  }
+
+  pub fn file_read_at(&mut self, file: &VFile, offset: Ofs, buffer: &mut [u8]) -> Ofs {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    file.read_at_abs(offset, buffer, disk)
+  }
+
+  /* Iterate over the rest of the file a block at a time, advancing its position */
+  pub fn file_chunks<'s, 'f>(
+    &'s mut self,
+    file: &'s mut VFile<'f>,
+  ) -> impl Iterator<Item = Vec<u8>> + use<'s, 'a, 'f> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    file.chunks(disk)
   }
 
   /*
-    Misc operations
+    Writes past the end of the file extend it, as far as free space allows. Without the space, the
+    write stops at the current end and returns the short count
   */
+  pub fn file_write(&mut self, file: &mut VFile, buffer: &[u8], offset: Ofs) -> Ofs {
+    if self.staging() {
+      return 0;
+    }
 
-  pub fn display_disk_stats(&'a mut self) {
     let disk = self
       .block_devs
       .get_by_role(DeviceType::Disk)
       .expect(NO_DISK_ERR);
 
-    println!("{}", disk);
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let fits = if file.extend(buffer.len() as Size, offset, free_map, disk) {
+      buffer.len()
+    } else {
+      (file.length() as Ofs - file.tell())
+        .saturating_sub(offset)
+        .clamp(0, buffer.len() as Ofs) as usize
+    };
+    let bytes_written = file.write(&buffer[..fits], offset, disk);
+
+    self.check_full();
+    /* Under `SyncPolicy::Always`, a write that could not be made durable counts as none */
+    if self.sync_policy == SyncPolicy::Always && !self.sync() {
+      return 0;
+    }
+    bytes_written
+  }
+
+  /* Write `data` at `offset`, growing the file if needed, without the caller managing a handle */
+  fn write_path(&mut self, path: &str, offset: Ofs, data: &[u8]) -> bool {
+    self.session(|session| session.write_at(path, offset, data))
+  }
+
+  /*
+    Copy `len` bytes within `path` from `src_offset` to `dst_offset`, growing the file if the
+    destination runs past its end. The ranges may overlap. Returns the bytes copied, which stop
+    short at the end of the source
+  */
+  pub fn copy_range(&mut self, path: &str, src_offset: Ofs, dst_offset: Ofs, len: Size) -> Ofs {
+    if self.staging() {
+      return 0;
+    }
+
+    self.session(|session| session.copy_range(path, src_offset, dst_offset, len))
   }
+
+  /*
+    Grow or shrink a file to `new_len` bytes, allocating or releasing blocks to match, as with
+    `ftruncate`. Grown bytes read as zero. False if the file is missing or the blocks could not be
+    allocated, in which case its length is unchanged, or if a sync under `SyncPolicy::Always` fails
+  */
+  pub fn set_file_len(&mut self, path: &str, new_len: Size) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let resized = self.session(|session| session.set_len(path, new_len));
+
+    if resized && self.sync_policy == SyncPolicy::Always {
+      return self.sync();
+    }
+    resized
+  }
+
+  /* Read up to `len` bytes at `offset`, without the caller managing a handle */
+  pub fn read_range(&mut self, path: &str, offset: Ofs, len: Size) -> Option<Vec<u8>> {
+    self.session(|session| session.read_range(path, offset, len))
+  }
+
+  /*
+    Warm the buffer cache with the blocks holding `len` bytes at `offset`, ahead of reads the
+    caller knows are coming. Does nothing without a cache. False if `path` is not a regular file
+  */
+  pub fn prefetch(&mut self, path: &str, offset: Ofs, len: Size) -> bool {
+    self.session(|session| session.prefetch(path, offset, len))
+  }
+
+  /* The whole of a regular file as text. None if it is missing or not valid UTF-8 */
+  pub fn cat(&mut self, path: &str) -> Option<String> {
+    String::from_utf8(self.read_regular(path)?).ok()
+  }
+
+  /* As `cat`, replacing invalid UTF-8 with U+FFFD rather than failing */
+  pub fn cat_lossy(&mut self, path: &str) -> Option<String> {
+    let bytes = self.read_regular(path)?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+  }
+
+  /*
+    Look a path up once, for callers that need several facts about it. None if it does not exist
+    or its inode's type is not recognised. Directories are not nested yet, so the parent is always
+    the root and the name is the whole path
+  */
+  pub fn resolve(&mut self, path: &str) -> Option<ResolvedPath> {
+    let inumber = self.lookup(path)?;
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    Some(ResolvedPath {
+      inumber,
+      parent: ROOT_INODE,
+      name: path.to_string(),
+      file_type: self.inodes.file_type(inumber, disk)?,
+    })
+  }
+
+  /* Inode named by `path`, without reading the directory if it was looked up recently */
+  fn lookup(&mut self, path: &str) -> Option<Size> {
+    if let Some(inumber) = self.path_cache.get(path) {
+      return Some(inumber);
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
+    let inumber = dir.open_file(path, disk)?;
+
+    self.path_cache.insert(path, inumber);
+    Some(inumber)
+  }
+
+  pub fn stat(&mut self, path: &str) -> Option<FileStat> {
+    let resolved = self.resolve(path)?;
+    self.stat_inumber(resolved.inumber)
+  }
+
+  /* True if `path` names a regular file. False for a directory or a missing path */
+  pub fn is_file(&mut self, path: &str) -> bool {
+    self.is_type(path, FileType::Regular)
+  }
+
+  /* True if `path` names a directory. False for a regular file or a missing path */
+  pub fn is_dir(&mut self, path: &str) -> bool {
+    self.is_type(path, FileType::Directory)
+  }
+
+  fn is_type(&mut self, path: &str, file_type: FileType) -> bool {
+    self
+      .resolve(path)
+      .is_some_and(|resolved| resolved.file_type == file_type)
+  }
+
+  /* Open an inode just long enough to describe it. None if it is not a valid inode */
+  fn stat_inumber(&mut self, inumber: Size) -> Option<FileStat> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let file_type = self.inodes.file_type(inumber, disk)?;
+
+    let inode = self.inodes.open_verified(inumber, disk)?;
+    let stat = FileStat {
+      inumber,
+      file_type,
+      len: inode.borrow().length(),
+      blocks: inode.borrow().allocated_blocks(disk),
+    };
+
+    self.inodes.close_inumber(inumber);
+    Some(stat)
+  }
+
+  /* FNV-1a digest of a file's current contents, read a block at a time */
+  pub fn file_checksum(&mut self, path: &str) -> Option<u64> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
+    let inumber = dir.open_file(path, disk)?;
+
+    if self.inodes.file_type(inumber, disk) != Some(FileType::Regular) {
+      return None;
+    }
+
+    let inode = self.inodes.open_inode(inumber, disk);
+    let mut hash = FNV_OFFSET;
+    let mut offset: Ofs = 0;
+    let mut chunk = block::EMPTY_BLOCK;
+
+    loop {
+      let bytes_read = inode.borrow().read_at(&mut chunk, offset, disk);
+      if bytes_read == 0 {
+        break;
+      }
+
+      hash = fnv1a(hash, &chunk[..bytes_read as usize]);
+      offset += bytes_read;
+    }
+
+    self.inodes.close_inumber(inumber);
+    Some(hash)
+  }
+
+  /* Type recorded in a file's inode, without opening it. None if the path does not exist */
+  pub fn file_type(&mut self, path: &str) -> Option<FileType> {
+    Some(self.resolve(path)?.file_type)
+  }
+
+  /* Handles currently open on a file. Looking it up does not count as one */
+  pub fn open_count(&mut self, path: &str) -> Option<usize> {
+    let resolved = self.resolve(path)?;
+    Some(self.inodes.open_count(resolved.inumber))
+  }
+
+  /* Every open inode, directories included, with its handle count and a name if it has one */
+  pub fn open_handles(&mut self) -> Vec<OpenHandleInfo> {
+    let open = self.inodes.open_handles();
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let entries = Dir::open_root(&mut self.inodes, disk).entries(disk);
+
+    open
+      .into_iter()
+      .map(|(inumber, open_count)| OpenHandleInfo {
+        inumber,
+        open_count,
+        path: match inumber {
+          ROOT_INODE => Some(String::from("/")),
+          _ => entries.iter().find(|&&(_, e)| e == inumber).map(|(name, _)| name.clone()),
+        },
+      })
+      .collect()
+  }
+
+  /* Closing the last handle on a removed file releases its blocks */
+  pub fn close_file(&mut self, file: VFile) {
+    file.close(&mut self.inodes);
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+    self.inodes.reap(free_map, disk);
+
+    if self.sync_policy != SyncPolicy::Never {
+      self.sync();
+    }
+  }
+
+  /* Rename `from` to `to`, failing if `to` exists. Open handles follow the file */
+  pub fn rename_file(&mut self, from: &str, to: &str) -> bool {
+    self.rename(from, to, false).is_ok()
+  }
+
+  /*
+    Rename `from` to `to`. An existing regular file at `to` is only replaced when `overwrite` is
+    set, and its blocks are freed. If it is still open, that waits until its last handle closes.
+    Only directory entries change: handles hold their inode rather than a name, so any open on
+    `from` keep reading and writing the same file. Returns the replaced inode, if there was one
+  */
+  pub fn rename(
+    &mut self,
+    from: &str,
+    to: &str,
+    overwrite: bool,
+  ) -> Result<Option<Size>, RenameError> {
+    if self.staging() {
+      return Err(RenameError::InTransaction);
+    }
+    directory::check_name(from).map_err(RenameError::Name)?;
+    directory::check_name(to).map_err(RenameError::Name)?;
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    /* A missing source is reported before anything about the destination */
+    let Some(dir) = Dir::open_path(&mut self.inodes, disk, from) else {
+      return Err(RenameError::NotFound);
+    };
+    if dir.open_file(from, disk).is_none() {
+      return Err(RenameError::NotFound);
+    }
+
+    let Some(dir) = Dir::open_path(&mut self.inodes, disk, to) else {
+      return Err(RenameError::NotFound);
+    };
+    let replaced = dir.open_file(to, disk).filter(|_| from != to);
+
+    if let Some(inumber) = replaced {
+      if self.inodes.file_type(inumber, disk) != Some(FileType::Regular) {
+        return Err(RenameError::NotRegular);
+      }
+      if !overwrite {
+        return Err(RenameError::Exists);
+      }
+    }
+
+    let Some(mut dir) = Dir::open_path(&mut self.inodes, disk, from) else {
+      return Err(RenameError::NotFound);
+    };
+    /* The names were checked above, so only a missing `from` is refused */
+    if !dir.rename(from, to, disk) {
+      return Err(RenameError::NotFound);
+    }
+    self.path_cache.invalidate(from);
+    self.path_cache.invalidate(to);
+
+    if let Some(inumber) = replaced {
+      self.inodes.unlink(inumber, free_map, disk);
+    }
+
+    self.sync_dir_update();
+    Ok(replaced)
+  }
+
+  /*
+    Remove a regular file from its directory. Handles already open on it stay usable, and its
+    blocks are freed when the last of them closes
+  */
+  pub fn remove_file(&mut self, path: &str) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let Some(dir) = Dir::open_path(&mut self.inodes, disk, path) else {
+      return false;
+    };
+    let Some(inumber) = dir.open_file(path, disk) else {
+      return false;
+    };
+
+    if self.inodes.file_type(inumber, disk) != Some(FileType::Regular) {
+      return false;
+    }
+
+    let Some(mut dir) = Dir::open_path(&mut self.inodes, disk, path) else {
+      return false;
+    };
+    dir.remove(path, disk);
+    self.path_cache.invalidate(path);
+
+    self.inodes.unlink(inumber, free_map, disk);
+    self.sync_dir_update();
+    true
+  }
+
+  /*
+    Transactions
+    Operations are staged in memory and only reach the disk on `commit`, which checks them all
+    before applying any, and undoes those already applied if a later one still fails. Changes made
+    directly through `Filesys` are refused while a transaction is open. There is no journal, so a
+    crash part way through a commit can leave some of its operations applied
+  */
+
+  /* Start staging operations. Panics if a transaction is already open */
+  pub fn begin(&mut self) {
+    assert!(self.transaction.is_none(), "transaction already in progress");
+    self.transaction = Some(Vec::new());
+  }
+
+  pub fn stage_create(&mut self, path: &str, data: &[u8]) {
+    self.stage(StagedOp::Create {
+      path: path.to_string(),
+      data: data.to_vec(),
+    });
+  }
+
+  pub fn stage_write(&mut self, path: &str, offset: Ofs, data: &[u8]) {
+    self.stage(StagedOp::Write {
+      path: path.to_string(),
+      offset,
+      data: data.to_vec(),
+    });
+  }
+
+  pub fn stage_remove(&mut self, path: &str) {
+    self.stage(StagedOp::Remove {
+      path: path.to_string(),
+    });
+  }
+
+  fn stage(&mut self, op: StagedOp) {
+    self.transaction.as_mut().expect(NO_TRANSACTION_ERR).push(op);
+  }
+
+  fn staging(&self) -> bool {
+    self.transaction.is_some()
+  }
+
+  /*
+    Apply the staged operations in order, closing the transaction. False if any fails, in which
+    case the disk is left as it was at `begin`: nothing is applied if the names or offsets are
+    wrong, and the operations applied before one that ran out of space are undone
+  */
+  pub fn commit(&mut self) -> bool {
+    let staged = self.transaction.take().expect(NO_TRANSACTION_ERR);
+    if !self.validate(&staged) {
+      return false;
+    }
+
+    let mut undo = Vec::new();
+    if staged.iter().all(|op| self.apply(op, &mut undo)) {
+      return true;
+    }
+
+    for op in undo.into_iter().rev() {
+      let undone = match op {
+        UndoOp::Remove { path } => self.remove_file(&path),
+        UndoOp::Restore { path, data } => self.create_file_with_data(&path, &data).is_ok(),
+        UndoOp::Unwrite {
+          path,
+          offset,
+          data,
+          len,
+        } => self.write_path(&path, offset, &data) && self.set_file_len(&path, len),
+      };
+      assert!(undone, "could not undo a staged operation");
+    }
+    false
+  }
+
+  /* Discard the staged operations. The disk is left as it was at `begin` */
+  pub fn rollback(&mut self) {
+    self.transaction.take().expect(NO_TRANSACTION_ERR);
+  }
+
+  /* Whether each operation names a valid target, given the ones staged before it */
+  fn validate(&mut self, staged: &[StagedOp]) -> bool {
+    /* Files created or removed by earlier operations, and whether they exist after them */
+    let mut staged_files: HashMap<&str, bool> = HashMap::new();
+
+    for op in staged {
+      let (StagedOp::Create { path, .. } | StagedOp::Write { path, .. } | StagedOp::Remove { path }) =
+        op;
+      if directory::check_name(path).is_err() {
+        return false;
+      }
+
+      let valid = match op {
+        StagedOp::Create { data, .. } => {
+          let taken = match staged_files.get(path.as_str()) {
+            Some(&exists) => exists,
+            None => self.resolve(path).is_some(),
+          };
+          !taken && data.len() as Size <= MAX_FILE_SIZE
+        }
+        StagedOp::Write { offset, data, .. } => {
+          let end = (*offset as Size).checked_add(data.len() as Size);
+          self.is_file_staged(path, &staged_files)
+            && *offset >= 0
+            && end.is_some_and(|end| end <= MAX_FILE_SIZE)
+        }
+        StagedOp::Remove { .. } => self.is_file_staged(path, &staged_files),
+      };
+      if !valid {
+        return false;
+      }
+
+      match op {
+        StagedOp::Create { .. } => staged_files.insert(path, true),
+        StagedOp::Remove { .. } => staged_files.insert(path, false),
+        StagedOp::Write { .. } => None,
+      };
+    }
+    true
+  }
+
+  /* Files created by a staged operation are regular */
+  fn is_file_staged(&mut self, path: &str, staged_files: &HashMap<&str, bool>) -> bool {
+    match staged_files.get(path) {
+      Some(&exists) => exists,
+      None => self.is_file(path),
+    }
+  }
+
+  /* Apply one staged operation, recording how to undo it if it succeeds */
+  fn apply(&mut self, op: &StagedOp, undo: &mut Vec<UndoOp>) -> bool {
+    match op {
+      StagedOp::Create { path, data } => {
+        if self.create_file_with_data(path, data).is_err() {
+          return false;
+        }
+        undo.push(UndoOp::Remove { path: path.clone() });
+      }
+      StagedOp::Write { path, offset, data } => {
+        let Some(len) = self.stat(path).map(|stat| stat.len) else {
+          return false;
+        };
+        let old = self
+          .read_range(path, *offset, data.len() as Size)
+          .unwrap_or_default();
+        if !self.write_path(path, *offset, data) {
+          return false;
+        }
+        undo.push(UndoOp::Unwrite {
+          path: path.clone(),
+          offset: *offset,
+          data: old,
+          len,
+        });
+      }
+      StagedOp::Remove { path } => {
+        let Some(data) = self
+          .stat(path)
+          .and_then(|stat| self.read_range(path, 0, stat.len))
+        else {
+          return false;
+        };
+        if !self.remove_file(path) {
+          return false;
+        }
+        undo.push(UndoOp::Restore {
+          path: path.clone(),
+          data,
+        });
+      }
+    }
+    true
+  }
+
+  /*
+    Directory operations
+  */
+
+  pub fn list(&'a mut self, path: &str) -> Option<Vec<String>> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
+    Some(dir.list(disk))
+  }
+
+  /* Drop the unused slots left in a directory by removed entries */
+  pub fn compact_dir(&mut self, path: &str) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let compacted = match Dir::open_path(&mut self.inodes, disk, path) {
+      Some(mut dir) => dir.compact(free_map, disk),
+      None => false,
+    };
+
+    if compacted {
+      self.sync_dir_update();
+    }
+    compacted
+  }
+
+  /*
+    First path referring to an inode, in the form other operations accept. Directories are not
+    nested yet, so only the root needs searching
+  */
+  pub fn path_of_inode(&mut self, inumber: Size) -> Option<String> {
+    if inumber == ROOT_INODE {
+      return Some(String::from("/"));
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_root(&mut self.inodes, disk);
+    dir
+      .entries(disk)
+      .into_iter()
+      .find(|&(_, entry)| entry == inumber)
+      .map(|(name, _)| name)
+  }
+
+  /*
+    Every regular file in the tree with its metadata, each inode read only when the iterator
+    reaches it. Directories are not nested yet, so the tree is just the root
+  */
+  pub fn iter_files(&mut self) -> impl Iterator<Item = (String, FileStat)> + use<'_, 'a> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let entries = Dir::open_root(&mut self.inodes, disk).entries(disk);
+
+    entries.into_iter().filter_map(|(name, inumber)| {
+      let stat = self.stat_inumber(inumber)?;
+      (stat.file_type == FileType::Regular).then_some((name, stat))
+    })
+  }
+
+  /* As `list`, in byte order, or ignoring ASCII case when `ignore_case` is set */
+  pub fn list_sorted(&mut self, path: &str, ignore_case: bool) -> Option<Vec<String>> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
+    let mut names = dir.list(disk);
+
+    if ignore_case {
+      /* Stable, and names differing only in case fall back to byte order */
+      names.sort();
+      names.sort_by_key(|name| name.to_ascii_lowercase());
+    } else {
+      names.sort();
+    }
+    Some(names)
+  }
+
+  /* As `list`, with each entry's metadata. Entries whose inode is invalid are left out */
+  pub fn list_with_stat(&mut self, path: &str) -> Option<Vec<(String, FileStat)>> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let dir = Dir::open_path(&mut self.inodes, disk, path)?;
+    let entries = dir.entries(disk);
+
+    Some(
+      entries
+        .into_iter()
+        .filter_map(|(name, inumber)| Some((name, self.stat_inumber(inumber)?)))
+        .collect(),
+    )
+  }
+
+  /*
+    Move the blocks of every file on the disk into contiguous runs. Directories are not nested yet,
+    so that is every entry of the root. False if some file could not be moved
+  */
+  pub fn defragment(&mut self) -> bool {
+    self.defragment_dir("/")
+  }
+
+  /*
+    Move the blocks of every file in the directory `path` into contiguous runs. False if some file
+    could not be moved, because it is open or no free run is large enough
+  */
+  pub fn defragment_dir(&mut self, path: &str) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let Some(dir) = Dir::open_path(&mut self.inodes, disk, path) else {
+      return false;
+    };
+    let entries = dir.entries(disk);
+
+    let mut moved = true;
+    for (_, inumber) in entries {
+      if self.inodes.inode_blocks(inumber, disk).is_some() {
+        moved &= self.inodes.defragment(inumber, free_map, disk);
+      }
+    }
+    moved
+  }
+
+  /* Measure fragmentation from inode metadata alone, without moving anything */
+  pub fn fragmentation_report(&mut self) -> FragReport {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let root = Dir::open_root(&mut self.inodes, disk);
+    let entries = root.entries(disk);
+
+    let mut report = FragReport::default();
+    let (mut breaks, mut pairs) = (0, 0);
+
+    for (name, inumber) in entries {
+      let Some(blocks) = self.inodes.inode_blocks(inumber, disk) else {
+        continue;
+      };
+
+      let gaps = blocks[1..].windows(2).filter(|w| w[1] != w[0] + 1).count();
+      breaks += gaps;
+      pairs += blocks.len().saturating_sub(2);
+
+      let runs = if blocks.len() > 1 { gaps + 1 } else { 0 };
+      report.files.push((name, runs));
+    }
+
+    if pairs > 0 {
+      report.score = breaks as f64 / pairs as f64;
+    }
+    report
+  }
+
+  /*
+    Raw block operations
+    Bypass inodes and the free map. Reserved blocks and the inode table are refused unless
+    `unsafe_raw` is set
+  */
+
+  pub fn set_unsafe_raw(&mut self, unsafe_raw: bool) {
+    self.unsafe_raw = unsafe_raw;
+  }
+
+  /*
+    Keep `pos` from ever being allocated to a file, e.g. for a boot sector written with
+    `write_block` under `set_unsafe_raw`. False if a file already uses it
+  */
+  pub fn reserve_block(&mut self, pos: Size) -> bool {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    pos < disk.max_size() && self.free_map.as_mut().expect(NO_FREE_MAP_ERR).reserve(pos)
+  }
+
+  pub fn read_block(&mut self, pos: Size, buffer: &mut Block) -> bool {
+    if !self.raw_accessible(pos) {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    disk.read(buffer, pos);
+    true
+  }
+
+  pub fn write_block(&mut self, pos: Size, buffer: &Block) -> bool {
+    if self.staging() || !self.raw_accessible(pos) {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    disk.write(buffer, pos);
+    true
+  }
+
+  fn raw_accessible(&mut self, pos: Size) -> bool {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_ref().expect(NO_FREE_MAP_ERR);
+    let reserved = pos == ROOT_INODE
+      || pos == FREE_MAP_INODE
+      || free_map.is_reserved(pos)
+      || pos < free_map.inode_limit();
+    pos < disk.max_size() && (self.unsafe_raw || !reserved)
+  }
+
+  /*
+    Hex and ASCII dump of a block, including reserved ones, for inspecting on-disk layouts. None if
+    `pos` is past the end of the disk
+  */
+  pub fn dump_block(&mut self, pos: Size) -> Option<String> {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    if pos >= disk.max_size() {
+      return None;
+    }
+
+    let mut buffer = block::EMPTY_BLOCK;
+    disk.read(&mut buffer, pos);
+    Some(block::hexdump(&buffer))
+  }
+
+  /*
+    Scratch operations
+    Raw blocks on a second device, outside the filesystem tree
+  */
+
+  pub fn new_scratch(&mut self, host_path: &str, block_count: Size) {
+    let vdisk = VDisk::new(host_path, block_count);
+
+    self
+      .block_devs
+      .register("SCRATCH", block_count, vdisk, DeviceType::Scratch);
+  }
+
+  pub fn scratch_read(&mut self, buffer: &mut Block, block_num: Size) {
+    let scratch = self
+      .block_devs
+      .get_by_role(DeviceType::Scratch)
+      .expect(NO_SCRATCH_ERR);
+
+    scratch.read(buffer, block_num);
+  }
+
+  pub fn scratch_write(&mut self, buffer: &Block, block_num: Size) {
+    let scratch = self
+      .block_devs
+      .get_by_role(DeviceType::Scratch)
+      .expect(NO_SCRATCH_ERR);
+
+    scratch.write(buffer, block_num);
+  }
+
+  /*
+    Misc operations
+  */
+
+  /*
+    Durability barrier. Persists the free map and every open inode, then flushes the disk. False if
+    the free map's file could not be allocated
+  */
+  pub fn sync(&mut self) -> bool {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let inumber = free_map.inode();
+    let inode = self.inodes.open_inode(inumber, disk);
+    let persisted = free_map.flush(&mut inode.borrow_mut(), disk);
+    self.inodes.close_inumber(inumber);
+
+    self.inodes.flush_all(disk);
+    disk.flush();
+    persisted
+  }
+
+  pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+    self.sync_policy = policy;
+  }
+
+  /*
+    Sync whenever a directory entry is added, removed or renamed, so a crash cannot lose a name
+    that was reported created even while file data waits on `sync_policy`
+  */
+  pub fn set_sync_dirs(&mut self, sync: bool) {
+    self.sync_dirs = sync;
+  }
+
+  fn sync_dir_update(&mut self) {
+    if self.sync_dirs {
+      self.sync();
+    }
+  }
+
+  /*
+    Truncate the disk, and its host file, to just past the highest allocated block. False if
+    nothing could be reclaimed or the host file could not be truncated
+  */
+  pub fn shrink_disk(&mut self) -> bool {
+    if self.staging() {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    /*
+      The free map's file keeps the blocks it was first given, which may now be the highest in use.
+      Emptied, the sync below gives it blocks afresh. An empty file loads as a map to rebuild
+    */
+    let inumber = free_map.inode();
+    self.inodes.open_inode(inumber, disk).borrow_mut().set_len(0, free_map, disk);
+    self.inodes.close_inumber(inumber);
+
+    /* Settles where the free map's own file lives before measuring */
+    if !self.sync() {
+      return false;
+    }
+
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let free_map = self.free_map.as_mut().expect(NO_FREE_MAP_ERR);
+
+    let new_size = free_map.allocated_blocks().max().map_or(0, |b| b + 1);
+    if new_size >= disk.max_size() || !disk.resize(new_size) {
+      return false;
+    }
+    assert!(free_map.resize(new_size));
+
+    /* The bitmap itself got shorter, which can only release blocks */
+    self.sync()
+  }
+
+  /*
+    Give inodes the blocks below `limit` as a table of their own, and data the rest, so the two
+    never interleave. False if a block below `limit` is already in use, other than by the root or
+    free-map inode. Not saved with the image
+  */
+  pub fn set_inode_limit(&mut self, limit: Size) -> bool {
+    self.free_map.as_mut().expect(NO_FREE_MAP_ERR).set_inode_limit(limit)
+  }
+
+  pub fn set_dir_hash(&mut self, hash: DirHash) {
+    self.inodes.set_dir_hash(hash);
+  }
+
+  pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+    self.free_map.as_mut().expect(NO_FREE_MAP_ERR).set_policy(policy);
+  }
+
+  /*
+    Zero every block a file is given before it is used, so unwritten parts of a new file never
+    expose a deleted file's data. Costs a write per allocated block
+  */
+  pub fn set_zero_new_blocks(&mut self, zero: bool) {
+    self.free_map.as_mut().expect(NO_FREE_MAP_ERR).set_zero_new(zero);
+  }
+
+  /* Blocks still available for file data. Free slots in an inode table are not counted */
+  pub fn free_blocks(&self) -> Size {
+    self.free_map.as_ref().expect(NO_FREE_MAP_ERR).available()
+  }
+
+  /* Inodes that can still be created, from the inode table if there is one */
+  pub fn free_inodes(&self) -> Size {
+    self
+      .free_map
+      .as_ref()
+      .expect(NO_FREE_MAP_ERR)
+      .free_inode_slots()
+  }
+
+  /* Start and length of each run of free blocks, in disk order, showing how free space is split */
+  pub fn free_extents(&self) -> Vec<(Size, Size)> {
+    self
+      .free_map
+      .as_ref()
+      .expect(NO_FREE_MAP_ERR)
+      .free_extents()
+      .collect()
+  }
+
+  /* Fraction of the blocks data can use that are in use, so an inode table is left out */
+  pub fn usage_ratio(&mut self) -> f64 {
+    let info = self.disk_info();
+    let table = self.free_map.as_ref().expect(NO_FREE_MAP_ERR).inode_limit();
+    let data_blocks = info.total_blocks - table;
+    (data_blocks - info.free_blocks) as f64 / data_blocks as f64
+  }
+
+  /*
+    Once usage exceeds `ratio`, file creation and writes raise a warning for `take_full_warning`.
+    They still succeed until space actually runs out. None turns the warning off
+  */
+  pub fn set_full_threshold(&mut self, ratio: Option<f64>) {
+    self.full_threshold = ratio;
+  }
+
+  /* Whether usage crossed the threshold during an operation since the last call. Clears it */
+  pub fn take_full_warning(&mut self) -> bool {
+    std::mem::take(&mut self.full_warning)
+  }
+
+  fn check_full(&mut self) {
+    if let Some(threshold) = self.full_threshold {
+      self.full_warning |= self.usage_ratio() > threshold;
+    }
+  }
+
+  pub fn disk_info(&mut self) -> DiskInfo {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    let total_blocks = disk.max_size();
+    let free_blocks = self.free_blocks();
+    let free_inodes = self.free_inodes();
+
+    /* Free table slots are unused too, though `free_blocks` leaves them out */
+    let free_table = match self.free_map.as_ref().expect(NO_FREE_MAP_ERR).inode_limit() {
+      0 => 0,
+      _ => free_inodes,
+    };
+
+    DiskInfo {
+      total_blocks,
+      free_blocks,
+      free_inodes,
+      used_blocks: total_blocks - free_blocks - free_table,
+      block_size: BLOCK_SIZE,
+      open_files: self.inodes.open_regular_files(),
+    }
+  }
+
+  pub fn display_disk_stats(&mut self) -> String {
+    let disk = self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR);
+
+    disk.to_string()
+  }
+
+  pub fn print_disk_stats(&mut self) {
+    println!("{}", self.display_disk_stats());
+  }
+
+  /* None if the disk has no buffer cache */
+  pub fn cache_stats(&mut self) -> Option<CacheStats> {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .cache_stats()
+  }
+
+  /*
+    Replaces any earlier hook. Blocks written back by a flush or sync are not evictions. False if
+    the disk has no buffer cache
+  */
+  pub fn set_evict_hook(&mut self, hook: EvictHook) -> bool {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .set_evict_hook(hook)
+  }
+
+  /* Disk accesses that reached the host file, after any buffer cache */
+  pub fn host_counts(&mut self) -> Option<IoCounts> {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .host_counts()
+  }
+
+  /*
+    Zero the disk's access counts, cache statistics and host counts together, e.g. between the
+    warm-up and measured phases of a benchmark
+  */
+  pub fn reset_counters(&mut self) {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .reset_counters();
+  }
+
+  /* Record the last `capacity` disk accesses. Zero turns tracing off */
+  pub fn set_trace(&mut self, capacity: usize) {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .set_trace(capacity);
+  }
+
+  pub fn trace_log(&mut self) -> Vec<TraceEvent> {
+    self
+      .block_devs
+      .get_by_role(DeviceType::Disk)
+      .expect(NO_DISK_ERR)
+      .trace_log()
+  }
+}
+
+impl LoadReport {
+  pub fn is_clean(&self) -> bool {
+    self.bad_inodes.is_empty() && self.leaked_blocks.is_empty() && self.unmarked_blocks.is_empty()
+  }
+}
+
+impl<'p> FilesysBuilder<'p> {
+  pub fn new(host_path: &'p str, block_count: Size) -> Self {
+    FilesysBuilder {
+      host_path,
+      block_count,
+      cache: BufferCacheStrategy::None,
+      evict_hook: None,
+    }
+  }
+
+  pub fn cache(mut self, strategy: BufferCacheStrategy) -> Self {
+    self.cache = strategy;
+    self
+  }
+
+  /* Ignored without a buffer cache, which never evicts */
+  pub fn evict_hook(mut self, hook: EvictHook) -> Self {
+    self.evict_hook = Some(hook);
+    self
+  }
+
+  pub fn build<'a>(self) -> Filesys<'a> {
+    let mut filesys = Filesys::init();
+    filesys.new_cached_disk(self.host_path, self.block_count, self.cache);
+    if let Some(hook) = self.evict_hook {
+      filesys.set_evict_hook(hook);
+    }
+    filesys.init_free_map();
+    filesys
+  }
+}
+
+/*
+  Turn the debug I/O checks on or off for every filesystem in the process, whatever the build
+  profile: the read-back after each host write and the inode operation logging. On by default
+*/
+pub fn set_debug_io(enabled: bool) {
+  DEBUG_IO.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(any(debug_assertions, feature = "debug"))]
+pub(crate) fn debug_io() -> bool {
+  DEBUG_IO.load(Ordering::Relaxed)
+}
+
+/* Continue an FNV-1a hash over `bytes` */
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+  bytes
+    .iter()
+    .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
 }
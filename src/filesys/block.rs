@@ -1,5 +1,7 @@
 use core::fmt;
+use std::collections::VecDeque;
 
+use super::cache::{CacheStats, EvictHook};
 use crate::Size;
 
 pub const BLOCK_SIZE: Size = 1 << 10;
@@ -8,45 +10,113 @@ pub const BLOCK_USIZE: usize = BLOCK_SIZE as usize;
 pub type Block = [u8; BLOCK_USIZE];
 pub const EMPTY_BLOCK: [u8; BLOCK_USIZE] = [0u8; BLOCK_SIZE as usize];
 
+const DUMP_WIDTH: usize = 16;
+
 pub struct BlockManager<'a> {
   blocks_by_role: [Option<BlockDevice<'a>>; DeviceType::MaxCount as usize],
+  /* Disks mounted by name, each swapped into its role's slot while it is the one in use */
+  mounted: Vec<(String, BlockDevice<'a>)>,
 }
 
 pub struct BlockDevice<'a> {
-  name: &'static str,
+  name: String,
   size: Size,
   ops: Box<dyn BlockOperations + 'a>,
   read_count: usize,
   write_count: usize,
   role: DeviceType,
+  trace: Option<Trace>,
+}
+
+/* Most recent accesses in order, the oldest dropped first once `capacity` is reached */
+struct Trace {
+  capacity: usize,
+  events: VecDeque<TraceEvent>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceOp {
+  Read,
+  Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceEvent {
+  pub op: TraceOp,
+  pub block: Size,
+  /* Operations the device had performed before this one */
+  pub seq: usize,
 }
 
 #[allow(clippy::type_complexity)]
 pub trait BlockOperations {
   fn read(&mut self, buf: &mut [u8; BLOCK_USIZE], pos: Size);
   fn write(&mut self, buf: &[u8; BLOCK_USIZE], pos: Size);
+
+  /* Persist any buffered writes */
+  fn flush(&mut self) {}
+
+  /* Load `pos` ahead of a read that will want it, for operations that cache blocks */
+  fn prefetch(&mut self, _pos: Size) {}
+
+  /* Change the number of blocks backing the device. False if the backend cannot */
+  fn resize(&mut self, _blocks: Size) -> bool {
+    false
+  }
+
+  /* Hit and miss counts, for operations that cache blocks */
+  fn cache_stats(&self) -> Option<CacheStats> {
+    None
+  }
+
+  /* Observe evictions, for operations that cache blocks. False if there is nothing to evict */
+  fn set_evict_hook(&mut self, _hook: EvictHook) -> bool {
+    false
+  }
+
+  /* Accesses that reached the host, for operations that count them or wrap one that does */
+  fn host_counts(&self) -> Option<IoCounts> {
+    None
+  }
+
+  /* Zero any statistics kept by the operations or those they wrap */
+  fn reset_counters(&mut self) {}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct IoCounts {
+  pub reads: usize,
+  pub writes: usize,
+}
+
+/* Counts the accesses passed through to `inner`, beneath any cache */
+pub struct CountedDisk<B: BlockOperations> {
+  inner: B,
+  counts: IoCounts,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum DeviceType {
   Disk,
+  Scratch,
   MaxCount,
 }
 
 impl<'a> BlockManager<'a> {
   pub const fn init() -> Self {
     BlockManager {
-      blocks_by_role: [None; DeviceType::MaxCount as usize],
+      blocks_by_role: [const { None }; DeviceType::MaxCount as usize],
+      mounted: Vec::new(),
     }
   }
 
-  pub fn get_by_role(&'a mut self, role: DeviceType) -> Option<&'a mut BlockDevice> {
+  pub fn get_by_role(&mut self, role: DeviceType) -> Option<&mut BlockDevice<'a>> {
     assert_ne!(role, DeviceType::MaxCount);
     self.blocks_by_role[role as usize].as_mut()
   }
 
   pub fn register<B: BlockOperations + 'a>(
-    &'a mut self,
+    &mut self,
     name: &'static str,
     size: Size,
     ops: B,
@@ -57,14 +127,50 @@ impl<'a> BlockManager<'a> {
     let idx: usize = role.clone() as usize;
     assert!(self.blocks_by_role[idx].is_none());
 
-    self.blocks_by_role[idx] = Some(BlockDevice {
-      name,
-      size,
-      ops: Box::new(ops),
-      read_count: 0,
-      write_count: 0,
-      role,
-    })
+    self.blocks_by_role[idx] = Some(BlockDevice::new(name, size, ops, role))
+  }
+
+  /* Keep a device under `name` until it is swapped in. False if the name is taken */
+  pub fn mount<B: BlockOperations + 'a>(
+    &mut self,
+    name: &str,
+    size: Size,
+    ops: B,
+    role: DeviceType,
+  ) -> bool {
+    assert_ne!(role, DeviceType::MaxCount);
+
+    if self.mounted.iter().any(|(n, _)| n == name) {
+      return false;
+    }
+    self.mounted.push((name.to_string(), BlockDevice::new(name, size, ops, role)));
+    true
+  }
+
+  /* Drop the device mounted under `name`. False if there is none */
+  pub fn unmount(&mut self, name: &str) -> bool {
+    let Some(idx) = self.mounted.iter().position(|(n, _)| n == name) else {
+      return false;
+    };
+
+    self.mounted.swap_remove(idx);
+    true
+  }
+
+  /*
+    Exchange the device mounted under `name` with the one registered for its role, which then
+    waits under `name` instead. Swapping again puts both back. False if either is missing
+  */
+  pub fn swap_mounted(&mut self, name: &str) -> bool {
+    let Some((_, mounted)) = self.mounted.iter_mut().find(|(n, _)| n == name) else {
+      return false;
+    };
+    let Some(registered) = self.blocks_by_role[mounted.role.clone() as usize].as_mut() else {
+      return false;
+    };
+
+    std::mem::swap(mounted, registered);
+    true
   }
 }
 
@@ -74,19 +180,154 @@ size: Size,
 ops: Box<dyn BlockOperations + 'a>,
 role: DeviceType, */
 impl<'a> BlockDevice<'a> {
+  fn new<B: BlockOperations + 'a>(name: &str, size: Size, ops: B, role: DeviceType) -> Self {
+    BlockDevice {
+      name: name.to_string(),
+      size,
+      ops: Box::new(ops),
+      read_count: 0,
+      write_count: 0,
+      role,
+      trace: None,
+    }
+  }
+
   pub fn read(&mut self, buffer: &mut [u8; BLOCK_USIZE], block_num: Size) {
+    self.check_range(block_num);
+    self.record(TraceOp::Read, block_num);
     self.ops.read(buffer, block_num);
     self.read_count += 1;
   }
 
   pub fn write(&mut self, buffer: &[u8; BLOCK_USIZE], block_num: Size) {
+    self.check_range(block_num);
+    self.record(TraceOp::Write, block_num);
     self.ops.write(buffer, block_num);
     self.write_count += 1;
   }
 
+  /* Caught before the backend, which would otherwise fail opaquely or grow the host file */
+  fn check_range(&self, block_num: Size) {
+    assert!(
+      block_num < self.size,
+      "block out of range: {} on device '{}' of {} blocks",
+      block_num,
+      self.name,
+      self.size
+    );
+  }
+
+  /* Keep the last `capacity` accesses. Zero turns tracing off and drops the log */
+  pub fn set_trace(&mut self, capacity: usize) {
+    self.trace = (capacity > 0).then(|| Trace {
+      capacity,
+      events: VecDeque::with_capacity(capacity),
+    });
+  }
+
+  pub fn trace_log(&self) -> Vec<TraceEvent> {
+    self
+      .trace
+      .as_ref()
+      .map_or_else(Vec::new, |trace| trace.events.iter().copied().collect())
+  }
+
+  fn record(&mut self, op: TraceOp, block: Size) {
+    let seq = self.read_count + self.write_count;
+
+    if let Some(trace) = self.trace.as_mut() {
+      if trace.events.len() == trace.capacity {
+        trace.events.pop_front();
+      }
+      trace.events.push_back(TraceEvent { op, block, seq });
+    }
+  }
+
+  pub fn flush(&mut self) {
+    self.ops.flush();
+  }
+
+  /* Not recorded as an access, as the caller did not ask for the data */
+  pub fn prefetch(&mut self, block_num: Size) {
+    self.check_range(block_num);
+    self.ops.prefetch(block_num);
+  }
+
   pub fn max_size(&self) -> Size {
     self.size
   }
+
+  /* Buffered writes are flushed first, so none are lost beyond the new end */
+  pub fn resize(&mut self, blocks: Size) -> bool {
+    self.ops.flush();
+    if !self.ops.resize(blocks) {
+      return false;
+    }
+
+    self.size = blocks;
+    true
+  }
+
+  pub fn cache_stats(&self) -> Option<CacheStats> {
+    self.ops.cache_stats()
+  }
+
+  pub fn set_evict_hook(&mut self, hook: EvictHook) -> bool {
+    self.ops.set_evict_hook(hook)
+  }
+
+  pub fn host_counts(&self) -> Option<IoCounts> {
+    self.ops.host_counts()
+  }
+
+  /* Zero the access counts, and those of any cache or counter beneath. The trace is kept */
+  pub fn reset_counters(&mut self) {
+    self.read_count = 0;
+    self.write_count = 0;
+    self.ops.reset_counters();
+  }
+}
+
+impl<B: BlockOperations> CountedDisk<B> {
+  pub fn new(inner: B) -> Self {
+    CountedDisk {
+      inner,
+      counts: IoCounts::default(),
+    }
+  }
+}
+
+impl<B: BlockOperations> BlockOperations for CountedDisk<B> {
+  fn read(&mut self, buf: &mut [u8; BLOCK_USIZE], pos: Size) {
+    self.counts.reads += 1;
+    self.inner.read(buf, pos);
+  }
+
+  fn write(&mut self, buf: &[u8; BLOCK_USIZE], pos: Size) {
+    self.counts.writes += 1;
+    self.inner.write(buf, pos);
+  }
+
+  fn flush(&mut self) {
+    self.inner.flush();
+  }
+
+  fn prefetch(&mut self, pos: Size) {
+    self.inner.prefetch(pos);
+  }
+
+  fn resize(&mut self, blocks: Size) -> bool {
+    self.inner.resize(blocks)
+  }
+
+  fn host_counts(&self) -> Option<IoCounts> {
+    Some(self.counts)
+  }
+
+  fn reset_counters(&mut self) {
+    self.counts = IoCounts::default();
+    self.inner.reset_counters();
+  }
 }
 
 impl fmt::Display for BlockDevice<'_> {
@@ -95,6 +336,53 @@ impl fmt::Display for BlockDevice<'_> {
       f,
       "Device '{}' assigned to '{:?}' has performed {} read and {} write operations",
       self.name, self.role, self.read_count, self.write_count
-    )
+    )?;
+
+    if let Some(stats) = self.cache_stats() {
+      write!(
+        f,
+        ", with {} cache hits, {} misses and {} evictions",
+        stats.hits, stats.misses, stats.evictions
+      )?;
+    }
+
+    if let Some(counts) = self.host_counts() {
+      write!(
+        f,
+        ", of which {} reads and {} writes reached the host",
+        counts.reads, counts.writes
+      )?;
+    }
+    Ok(())
   }
 }
+
+const SIZE_BYTES: usize = std::mem::size_of::<Size>();
+
+/* On-disk Size values are little-endian whatever the host's byte order */
+pub fn read_size_le(block: &[u8], byte_offset: usize) -> Size {
+  let mut bytes = [0; SIZE_BYTES];
+  bytes.copy_from_slice(&block[byte_offset..byte_offset + SIZE_BYTES]);
+  Size::from_le_bytes(bytes)
+}
+
+pub fn write_size_le(block: &mut [u8], byte_offset: usize, value: Size) {
+  block[byte_offset..byte_offset + SIZE_BYTES].copy_from_slice(&value.to_le_bytes());
+}
+
+/* Formats a block as offset-labelled lines of hex and printable ASCII */
+pub fn hexdump(block: &Block) -> String {
+  let mut dump = String::new();
+
+  for (line, bytes) in block.chunks(DUMP_WIDTH).enumerate() {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let ascii: String = bytes
+      .iter()
+      .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+      .collect();
+
+    dump.push_str(&format!("{:04x}  {}  |{}|\n", line * DUMP_WIDTH, hex.join(" "), ascii));
+  }
+
+  dump
+}
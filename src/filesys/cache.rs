@@ -0,0 +1,457 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::block::{self, Block, BlockOperations, IoCounts};
+use crate::Size;
+
+/* Which buffer cache, if any, sits between the disk's BlockDevice and its host file */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BufferCacheStrategy {
+  #[default]
+  None,
+  Lfu { capacity: usize },
+  Fifo { capacity: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CacheStats {
+  pub hits: usize,
+  pub misses: usize,
+  pub evictions: usize,
+}
+
+/* Access counts are halved once one reaches this, so blocks hot long ago can still be evicted */
+const AGING_LIMIT: usize = 64;
+
+const RESIDENT_ERR: &str = "internal error: cached block not resident";
+
+/* Called with the block number and whether it was dirty each time a block is evicted */
+pub type EvictHook = Box<dyn FnMut(Size, bool)>;
+
+struct LfuEntry {
+  data: Block,
+  dirty: bool,
+  uses: usize,
+  /* Loaded by a prefetch and not accessed since */
+  prefetched: bool,
+}
+
+struct FifoEntry {
+  data: Block,
+  dirty: bool,
+}
+
+/*
+  Write-back cache evicting the least frequently used block, the lowest numbered on a tie.
+  Prefetched blocks not yet read are only evicted once nothing else is left
+*/
+pub struct LfuCacheDisk<B: BlockOperations> {
+  inner: B,
+  capacity: usize,
+  entries: HashMap<Size, LfuEntry>,
+  stats: CacheStats,
+  on_evict: Option<EvictHook>,
+}
+
+impl<B: BlockOperations> LfuCacheDisk<B> {
+  pub fn new(inner: B, capacity: usize) -> Self {
+    assert!(capacity > 0);
+
+    LfuCacheDisk {
+      inner,
+      capacity,
+      entries: HashMap::with_capacity(capacity),
+      stats: CacheStats::default(),
+      on_evict: None,
+    }
+  }
+
+  /* The entry for `pos`, loading it from `inner` if it is not resident */
+  fn entry(&mut self, pos: Size, load: bool) -> &mut LfuEntry {
+    if self.entries.contains_key(&pos) {
+      self.stats.hits += 1;
+    } else {
+      self.stats.misses += 1;
+      if self.entries.len() == self.capacity {
+        self.evict();
+      }
+
+      let mut data = block::EMPTY_BLOCK;
+      if load {
+        self.inner.read(&mut data, pos);
+      }
+      self.entries.insert(
+        pos,
+        LfuEntry {
+          data,
+          dirty: false,
+          uses: 0,
+          prefetched: false,
+        },
+      );
+    }
+
+    let entry = self.entries.get_mut(&pos).expect(RESIDENT_ERR);
+    entry.uses += 1;
+    entry.prefetched = false;
+
+    if entry.uses >= AGING_LIMIT {
+      self.entries.values_mut().for_each(|e| e.uses /= 2);
+    }
+    self.entries.get_mut(&pos).expect(RESIDENT_ERR)
+  }
+
+  fn evict(&mut self) {
+    let Some(&victim) = self
+      .entries
+      .iter()
+      .min_by_key(|&(&pos, entry)| (entry.prefetched, entry.uses, pos))
+      .map(|(pos, _)| pos)
+    else {
+      return;
+    };
+
+    let entry = self.entries.remove(&victim).expect(RESIDENT_ERR);
+    if entry.dirty {
+      self.inner.write(&entry.data, victim);
+    }
+    self.stats.evictions += 1;
+    if let Some(hook) = self.on_evict.as_mut() {
+      hook(victim, entry.dirty);
+    }
+  }
+
+  fn write_back(&mut self) {
+    for (&pos, entry) in self.entries.iter_mut().filter(|(_, e)| e.dirty) {
+      self.inner.write(&entry.data, pos);
+      entry.dirty = false;
+    }
+  }
+}
+
+impl<B: BlockOperations> BlockOperations for LfuCacheDisk<B> {
+  fn read(&mut self, buf: &mut [u8; block::BLOCK_USIZE], pos: Size) {
+    buf.copy_from_slice(&self.entry(pos, true).data);
+  }
+
+  /* Whole blocks are written, so a miss does not need the old contents */
+  fn write(&mut self, buf: &[u8; block::BLOCK_USIZE], pos: Size) {
+    let entry = self.entry(pos, false);
+    entry.data.copy_from_slice(buf);
+    entry.dirty = true;
+  }
+
+  fn flush(&mut self) {
+    self.write_back();
+    self.inner.flush();
+  }
+
+  /* Loaded unused, and kept until it is read unless the cache holds nothing else. Not a hit or miss */
+  fn prefetch(&mut self, pos: Size) {
+    if self.entries.contains_key(&pos) {
+      return;
+    }
+    if self.entries.len() == self.capacity {
+      self.evict();
+    }
+
+    let mut data = block::EMPTY_BLOCK;
+    self.inner.read(&mut data, pos);
+    self.entries.insert(
+      pos,
+      LfuEntry {
+        data,
+        dirty: false,
+        uses: 0,
+        prefetched: true,
+      },
+    );
+  }
+
+  /* Blocks past the new end are dropped, after being written back */
+  fn resize(&mut self, blocks: Size) -> bool {
+    self.write_back();
+    self.entries.retain(|&pos, _| pos < blocks);
+    self.inner.resize(blocks)
+  }
+
+  fn cache_stats(&self) -> Option<CacheStats> {
+    Some(self.stats)
+  }
+
+  fn set_evict_hook(&mut self, hook: EvictHook) -> bool {
+    self.on_evict = Some(hook);
+    true
+  }
+
+  fn host_counts(&self) -> Option<IoCounts> {
+    self.inner.host_counts()
+  }
+
+  fn reset_counters(&mut self) {
+    self.stats = CacheStats::default();
+    self.inner.reset_counters();
+  }
+}
+
+/* Dirty blocks still reach the host if the disk is dropped without a sync */
+impl<B: BlockOperations> Drop for LfuCacheDisk<B> {
+  fn drop(&mut self) {
+    self.write_back();
+  }
+}
+
+/* Write-back cache evicting blocks in the order they were loaded, however recently they were used */
+pub struct FifoCacheDisk<B: BlockOperations> {
+  inner: B,
+  capacity: usize,
+  entries: HashMap<Size, FifoEntry>,
+  /* Resident blocks, oldest first */
+  order: VecDeque<Size>,
+  stats: CacheStats,
+  on_evict: Option<EvictHook>,
+}
+
+impl<B: BlockOperations> FifoCacheDisk<B> {
+  pub fn new(inner: B, capacity: usize) -> Self {
+    assert!(capacity > 0);
+
+    FifoCacheDisk {
+      inner,
+      capacity,
+      entries: HashMap::with_capacity(capacity),
+      order: VecDeque::with_capacity(capacity),
+      stats: CacheStats::default(),
+      on_evict: None,
+    }
+  }
+
+  /* The entry for `pos`, loading it from `inner` if it is not resident */
+  fn entry(&mut self, pos: Size, load: bool) -> &mut FifoEntry {
+    if self.entries.contains_key(&pos) {
+      self.stats.hits += 1;
+    } else {
+      self.stats.misses += 1;
+      if self.entries.len() == self.capacity {
+        self.evict();
+      }
+
+      let mut data = block::EMPTY_BLOCK;
+      if load {
+        self.inner.read(&mut data, pos);
+      }
+      self.entries.insert(pos, FifoEntry { data, dirty: false });
+      self.order.push_back(pos);
+    }
+
+    self.entries.get_mut(&pos).expect(RESIDENT_ERR)
+  }
+
+  fn evict(&mut self) {
+    let Some(victim) = self.order.pop_front() else {
+      return;
+    };
+
+    let entry = self.entries.remove(&victim).expect(RESIDENT_ERR);
+    if entry.dirty {
+      self.inner.write(&entry.data, victim);
+    }
+    self.stats.evictions += 1;
+    if let Some(hook) = self.on_evict.as_mut() {
+      hook(victim, entry.dirty);
+    }
+  }
+
+  fn write_back(&mut self) {
+    for (&pos, entry) in self.entries.iter_mut().filter(|(_, e)| e.dirty) {
+      self.inner.write(&entry.data, pos);
+      entry.dirty = false;
+    }
+  }
+}
+
+impl<B: BlockOperations> BlockOperations for FifoCacheDisk<B> {
+  fn read(&mut self, buf: &mut [u8; block::BLOCK_USIZE], pos: Size) {
+    buf.copy_from_slice(&self.entry(pos, true).data);
+  }
+
+  fn write(&mut self, buf: &[u8; block::BLOCK_USIZE], pos: Size) {
+    let entry = self.entry(pos, false);
+    entry.data.copy_from_slice(buf);
+    entry.dirty = true;
+  }
+
+  fn flush(&mut self) {
+    self.write_back();
+    self.inner.flush();
+  }
+
+  /* Queued like any other load. Not a hit or miss */
+  fn prefetch(&mut self, pos: Size) {
+    if self.entries.contains_key(&pos) {
+      return;
+    }
+    if self.entries.len() == self.capacity {
+      self.evict();
+    }
+
+    let mut data = block::EMPTY_BLOCK;
+    self.inner.read(&mut data, pos);
+    self.entries.insert(pos, FifoEntry { data, dirty: false });
+    self.order.push_back(pos);
+  }
+
+  fn resize(&mut self, blocks: Size) -> bool {
+    self.write_back();
+    self.entries.retain(|&pos, _| pos < blocks);
+    self.order.retain(|&pos| pos < blocks);
+    self.inner.resize(blocks)
+  }
+
+  fn cache_stats(&self) -> Option<CacheStats> {
+    Some(self.stats)
+  }
+
+  fn set_evict_hook(&mut self, hook: EvictHook) -> bool {
+    self.on_evict = Some(hook);
+    true
+  }
+
+  fn host_counts(&self) -> Option<IoCounts> {
+    self.inner.host_counts()
+  }
+
+  fn reset_counters(&mut self) {
+    self.stats = CacheStats::default();
+    self.inner.reset_counters();
+  }
+}
+
+impl<B: BlockOperations> Drop for FifoCacheDisk<B> {
+  fn drop(&mut self) {
+    self.write_back();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{cell::RefCell, rc::Rc};
+
+  /* Blocks held in memory, so the caches can be driven without a host file */
+  struct MemDisk {
+    blocks: Vec<Block>,
+  }
+
+  impl BlockOperations for MemDisk {
+    fn read(&mut self, buf: &mut [u8; block::BLOCK_USIZE], pos: Size) {
+      buf.copy_from_slice(&self.blocks[pos as usize]);
+    }
+
+    fn write(&mut self, buf: &[u8; block::BLOCK_USIZE], pos: Size) {
+      self.blocks[pos as usize].copy_from_slice(buf);
+    }
+  }
+
+  fn mem_disk() -> MemDisk {
+    MemDisk {
+      blocks: vec![block::EMPTY_BLOCK; 8],
+    }
+  }
+
+  type EvictLog = Rc<RefCell<Vec<(Size, bool)>>>;
+
+  fn recorder() -> (EvictLog, EvictHook) {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&log);
+    (log, Box::new(move |pos, dirty| seen.borrow_mut().push((pos, dirty))))
+  }
+
+  #[test]
+  fn fifo_evictions_reach_the_hook_in_load_order() {
+    let (log, hook) = recorder();
+    let mut cache = FifoCacheDisk::new(mem_disk(), 2);
+    assert!(cache.set_evict_hook(hook));
+
+    let mut buf = block::EMPTY_BLOCK;
+    cache.write(&[1; block::BLOCK_USIZE], 0);
+    cache.read(&mut buf, 1);
+    cache.read(&mut buf, 0);
+    cache.read(&mut buf, 2);
+    cache.read(&mut buf, 3);
+    assert_eq!(*log.borrow(), vec![(0, true), (1, false)]);
+
+    /* Written back, but still resident */
+    cache.write(&[2; block::BLOCK_USIZE], 3);
+    cache.flush();
+    assert_eq!(log.borrow().len(), 2);
+    assert_eq!(cache.cache_stats().unwrap().evictions, 2);
+  }
+
+  #[test]
+  fn lfu_evictions_reach_the_hook_with_the_dirty_flag() {
+    let (log, hook) = recorder();
+    let mut cache = LfuCacheDisk::new(mem_disk(), 2);
+    assert!(cache.set_evict_hook(hook));
+
+    let mut buf = block::EMPTY_BLOCK;
+    cache.read(&mut buf, 0);
+    cache.read(&mut buf, 0);
+    cache.write(&[1; block::BLOCK_USIZE], 1);
+    cache.read(&mut buf, 2);
+    assert_eq!(*log.borrow(), vec![(1, true)]);
+
+    cache.flush();
+    cache.read(&mut buf, 3);
+    assert_eq!(*log.borrow(), vec![(1, true), (2, false)]);
+  }
+
+  #[test]
+  fn operations_without_a_cache_refuse_the_hook() {
+    let (_, hook) = recorder();
+    assert!(!mem_disk().set_evict_hook(hook));
+  }
+
+  #[test]
+  fn lfu_keeps_a_hot_block_past_capacity() {
+    let mut cache = LfuCacheDisk::new(mem_disk(), 4);
+    let mut buf = block::EMPTY_BLOCK;
+    (0..10).for_each(|_| cache.read(&mut buf, 0));
+    (1..8).for_each(|pos| cache.read(&mut buf, pos));
+    assert!(cache.cache_stats().unwrap().evictions > 0);
+
+    let misses = cache.cache_stats().unwrap().misses;
+    cache.read(&mut buf, 0);
+    assert_eq!(cache.cache_stats().unwrap().misses, misses);
+  }
+
+  /* Block 0 is used between every other load, which only frequency counting notices */
+  fn hot_and_cold(cache: &mut impl BlockOperations) -> CacheStats {
+    let mut buf = block::EMPTY_BLOCK;
+    for pos in 1..8 {
+      cache.read(&mut buf, 0);
+      cache.read(&mut buf, pos);
+    }
+    cache.cache_stats().unwrap()
+  }
+
+  #[test]
+  fn fifo_evicts_in_load_order_whatever_the_use() {
+    let mut fifo = FifoCacheDisk::new(mem_disk(), 2);
+    let mut lfu = LfuCacheDisk::new(mem_disk(), 2);
+    let (fifo, lfu) = (hot_and_cold(&mut fifo), hot_and_cold(&mut lfu));
+    assert!(fifo.hits < lfu.hits, "{:?} {:?}", fifo, lfu);
+    assert_eq!(lfu.hits, 6);
+  }
+
+  #[test]
+  fn fifo_writes_a_dirty_block_back_when_it_is_evicted() {
+    let mut cache = FifoCacheDisk::new(mem_disk(), 2);
+    let mut buf = block::EMPTY_BLOCK;
+    cache.write(&[9; block::BLOCK_USIZE], 0);
+    cache.read(&mut buf, 1);
+    assert_eq!(cache.inner.blocks[0], block::EMPTY_BLOCK);
+
+    cache.read(&mut buf, 2);
+    assert_eq!(cache.inner.blocks[0], [9; block::BLOCK_USIZE]);
+  }
+}
@@ -1,24 +1,80 @@
-use std::{
-  borrow::{Borrow, BorrowMut},
-  cell::RefCell,
-};
+use std::{cell::RefCell, collections::HashMap};
 
 use crate::{Ofs, Size};
 
 use super::{
-  block::BlockDevice,
+  block::{self, BlockDevice},
   free_map::FreeMap,
-  inode::{Inode, InodeManager},
+  inode::{self, Inode, InodeManager},
   ROOT_INODE,
 };
 
 pub const NAME_MAX: usize = 15;
 type FileName = [u8; NAME_MAX + 1]; /* Null-terminated */
 
-const NON_ASCII_ERR: &str = "encountered non-ascii character";
+/* Why a name cannot be stored in a directory entry */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+  Empty,
+  /* Over NAME_MAX bytes of UTF-8, however many characters that is */
+  TooLong { len: usize },
+}
+
+/* Why an entry could not be added to a directory, or a file created to go in it */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirError {
+  Name(NameError),
+  /* An entry of that name is already in use */
+  Exists,
+  /* No blocks were left for the inode, its data or the directory to grow into */
+  OutOfSpace,
+  /* The length asked for is past `MAX_FILE_SIZE` */
+  TooLarge,
+  /* A transaction is open, and names only change when it commits */
+  InTransaction,
+}
+
+/* Byte offsets of the fields of an encoded DirEntry, matching its C layout */
+const BLOCK_OFFSET: usize = std::mem::offset_of!(DirEntry, block);
+const IN_USE_OFFSET: usize = std::mem::offset_of!(DirEntry, in_use);
+
+/*
+  Function the directory index hashes names with. The index is rebuilt in memory each time a
+  directory is opened, so nothing on disk depends on the choice
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DirHash {
+  #[default]
+  Fnv1a,
+  /* DJB2 with xor in place of addition */
+  Djb2,
+}
+
+/*
+  In-memory directory state, kept on the inode for as long as it stays open. It is never written
+  to disk: `Dir::find` rebuilds it with one scan of the entries after the inode is opened again
+*/
+#[derive(Default)]
+pub struct DirIndex {
+  hash: DirHash,
+  /* Hash of a name to the offsets of every entry sharing that hash */
+  offsets: HashMap<u64, Vec<Ofs>>,
+  /* Every slot before this offset is in use */
+  free_hint: Ofs,
+}
+
+/* Entries must tile a block exactly, so none straddles two */
+const _: () = {
+  assert!(block::BLOCK_USIZE.is_multiple_of(std::mem::size_of::<DirEntry>()));
+};
+
+const DJB2_OFFSET: u64 = 5381;
+
+const NO_INDEX_ERR: &str = "internal error: directory index not built";
 
 pub struct Dir<'a> {
   inode: RefCell<&'a mut Inode>,
+  hash: DirHash,
 }
 
 #[repr(C)]
@@ -40,12 +96,14 @@ impl<'a> Dir<'a> {
     todo!()
   }
 
-  fn init(inode: RefCell<&'a mut Inode>) -> Self {
-    Dir { inode }
+  fn init(inode: RefCell<&'a mut Inode>, hash: DirHash) -> Self {
+    Dir { inode, hash }
   }
 
+  /* The root is pinned while the disk is mounted, so using it leaves no handle to close */
   pub fn open_root(inodes: &'a mut InodeManager, disk: &mut BlockDevice) -> Self {
-    Dir::init(inodes.open_inode(ROOT_INODE, disk))
+    let hash = inodes.dir_hash();
+    Dir::init(inodes.open_pinned(ROOT_INODE, disk), hash)
   }
 
   pub fn open_path(
@@ -62,41 +120,87 @@ impl<'a> Dir<'a> {
   }
 
   fn lookup(&self, path: &str, inode_dst: &mut Size, store: bool, disk: &mut BlockDevice) -> bool {
-    if path.is_empty() || path.len() > NAME_MAX {
+    if check_name(path).is_err() {
       panic!("should not call this without valid name");
     }
 
+    match self.find(&file_name(path), disk) {
+      Some((_, entry)) => {
+        if store {
+          *inode_dst = entry.block
+        }
+        true
+      }
+      None => false,
+    }
+  }
+
+  /* Offset and contents of the entry in use under `name` */
+  fn find(&self, name: &FileName, disk: &mut BlockDevice) -> Option<(Ofs, DirEntry)> {
+    self.build_index(disk);
+
     let inode = self.inode.borrow();
+    let index = inode.dir_index().expect(NO_INDEX_ERR);
 
-    let mut name = [b'\0'; NAME_MAX + 1];
-    for (i, c) in path.chars().enumerate() {
-      name[i] = c.try_into().expect(NON_ASCII_ERR);
+    /* Only entries sharing the name's hash are read, and told apart by name */
+    for &start in index.offsets.get(&index.hash.of(name)).into_iter().flatten() {
+      let entry = read_entry(&inode, start, disk);
+      if entry.in_use && entry.name == *name {
+        return Some((start, entry));
+      }
+    }
+
+    None
+  }
+
+  /* Scan every entry once, after which lookups go through the index */
+  fn build_index(&self, disk: &mut BlockDevice) {
+    let mut inode = self.inode.borrow_mut();
+    if inode.dir_index().is_some() {
+      return;
     }
 
+    let mut index = DirIndex::new(self.hash);
+    let mut first_free: Option<Ofs> = None;
     let mut start: Ofs = 0;
 
     while start as usize + std::mem::size_of::<DirEntry>() <= inode.length() as usize {
-      let mut raw = [0; std::mem::size_of::<DirEntry>()];
-      inode.borrow().read_at(&mut raw, start, disk);
+      let entry = read_entry(&inode, start, disk);
+      if entry.in_use {
+        index.insert(&entry.name, start);
+      } else if first_free.is_none() {
+        first_free = Some(start);
+      }
 
-      let entry: DirEntry = unsafe { std::mem::transmute(raw) };
-      if entry.in_use && entry.name == name {
-        if store {
-          *inode_dst = entry.block
-        }
-        return true;
+      start += std::mem::size_of::<DirEntry>() as Ofs;
+    }
+
+    index.free_hint = first_free.unwrap_or(start);
+
+    *inode.dir_index_mut() = Some(index);
+  }
+
+  /* Scans forward from the first slot that may be free */
+  fn free_slot(&self, disk: &mut BlockDevice) -> Option<Ofs> {
+    let inode = self.inode.borrow();
+    let mut start: Ofs = inode.dir_index().expect(NO_INDEX_ERR).free_hint;
+
+    while start as usize + std::mem::size_of::<DirEntry>() <= inode.length() as usize {
+      if !read_entry(&inode, start, disk).in_use {
+        return Some(start);
       }
 
       start += std::mem::size_of::<DirEntry>() as Ofs;
     }
 
-    false
+    None
   }
 
+  /* None as well if the entry is corrupt and does not point at an inode */
   pub fn open_file(&self, path: &str, disk: &mut BlockDevice) -> Option<Size> {
     let mut inode = 0;
 
-    if self.lookup(path, &mut inode, true, disk) {
+    if self.lookup(path, &mut inode, true, disk) && inode::holds_inode(inode, disk) {
       Some(inode)
     } else {
       None
@@ -109,83 +213,165 @@ impl<'a> Dir<'a> {
     block: Size,
     free_map: &mut FreeMap,
     disk: &mut BlockDevice,
-  ) -> bool {
-    let mut name = [b'\0'; NAME_MAX + 1];
-    for (i, c) in path.chars().enumerate() {
-      name[i] = c.try_into().expect(NON_ASCII_ERR);
-    }
-    
-    {
-      let inode = self.inode.borrow();
+  ) -> Result<(), DirError> {
+    check_name(path).map_err(DirError::Name)?;
 
-      if path.is_empty() || path.len() > NAME_MAX {
-        return false;
-      }
+    if self.lookup(path, &mut 0, false, disk) {
+      return Err(DirError::Exists);
+    }
 
-      if self.lookup(path, &mut 0, false, disk) {
-        return false;
-      }
+    let entry = DirEntry {
+      name: file_name(path),
+      block,
+      in_use: true,
+    };
 
-      let mut start: Ofs = 0;
-      while start as usize + std::mem::size_of::<DirEntry>() < inode.length() as usize {
-        let mut raw = [0; std::mem::size_of::<DirEntry>()];
-        inode.borrow().read_at(&mut raw, start, disk);
-
-        let mut entry: DirEntry = unsafe { std::mem::transmute(raw) };
-
-        if !entry.in_use {
-          entry.name = name;
-          entry.block = block;
-          entry.in_use = true;
-          let ptr = (&entry) as *const DirEntry as *const u8;
-          let buffer: &[u8] =
-            unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of::<DirEntry>()) };
-          inode.borrow().write_at(buffer, start, disk);
-          return true;
+    let start = match self.free_slot(disk) {
+      Some(start) => start,
+      None => {
+        /* Directory full, extend it to the end of a new block so bulk creation rarely grows it */
+        let mut inode = self.inode.borrow_mut();
+        let old_len = inode.length();
+        let new_len =
+          (old_len + std::mem::size_of::<DirEntry>() as Size).next_multiple_of(block::BLOCK_SIZE);
+        if !inode.set_len(new_len, free_map, disk) {
+          return Err(DirError::OutOfSpace);
         }
 
-        start += std::mem::size_of::<DirEntry>() as Ofs;
+        /* Reused blocks may hold stale bytes, so the new slots are explicitly marked unused */
+        inode.write_at(&vec![0; (new_len - old_len) as usize], old_len as Ofs, disk);
+        old_len as Ofs
       }
-    }
-    // File full, extend file
+    };
 
     let mut inode = self.inode.borrow_mut();
-    let old_len = inode.length();
+    write_entry(&inode, &entry, start, disk);
 
-    inode.borrow_mut().set_len(
-      old_len + std::mem::size_of::<DirEntry>() as Size,
-      free_map,
-      disk,
-    );
+    let index = inode.dir_index_mut().as_mut().expect(NO_INDEX_ERR);
+    index.insert(&entry.name, start);
+    index.free_hint = start + std::mem::size_of::<DirEntry>() as Ofs;
 
-    let entry = DirEntry {
-      name,
-      block,
-      in_use: true,
+    Ok(())
+  }
+
+  /* Remove the entry for `path`, returning the inode it referred to */
+  pub fn remove(&mut self, path: &str, disk: &mut BlockDevice) -> Option<Size> {
+    if check_name(path).is_err() {
+      return None;
+    }
+
+    let (start, entry) = self.find(&file_name(path), disk)?;
+    let block = entry.block;
+
+    clear_entry(&mut self.inode.borrow_mut(), entry, start, disk);
+    Some(block)
+  }
+
+  /* Point the entry for `path` at `block` instead, returning the inode it referred to */
+  pub fn repoint(&mut self, path: &str, block: Size, disk: &mut BlockDevice) -> Option<Size> {
+    if check_name(path).is_err() {
+      return None;
+    }
+
+    let (start, entry) = self.find(&file_name(path), disk)?;
+    let replaced = entry.block;
+
+    /* A single entry never straddles two blocks, so the switch is one block write */
+    write_entry(&self.inode.borrow(), &DirEntry { block, ..entry }, start, disk);
+    Some(replaced)
+  }
+
+  /*
+    Point `to` at the inode behind `from`. An existing `to` entry is repointed before `from` is
+    removed, so at least one of the names exists throughout
+  */
+  pub fn rename(&mut self, from: &str, to: &str, disk: &mut BlockDevice) -> bool {
+    if check_name(from).is_err() || check_name(to).is_err() {
+      return false;
+    }
+
+    let Some((src_start, src)) = self.find(&file_name(from), disk) else {
+      return false;
     };
 
-    let ptr = (&entry) as *const DirEntry as *const u8;
-    let buffer: &[u8] = unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of::<DirEntry>()) };
-    inode.borrow().write_at(
-      buffer,
-      old_len.div_ceil(std::mem::size_of::<DirEntry>() as Size) as Ofs,
-      disk,
-    );
+    if from == to {
+      return true;
+    }
+
+    let dst = self.find(&file_name(to), disk);
+
+    let mut inode = self.inode.borrow_mut();
+
+    match dst {
+      Some((dst_start, dst)) => {
+        let repointed = DirEntry {
+          block: src.block,
+          ..dst
+        };
+        write_entry(&inode, &repointed, dst_start, disk);
+
+        clear_entry(&mut inode, src, src_start, disk);
+      }
+      None => {
+        let renamed = DirEntry {
+          name: file_name(to),
+          ..src
+        };
+        write_entry(&inode, &renamed, src_start, disk);
+
+        let index = inode.dir_index_mut().as_mut().expect(NO_INDEX_ERR);
+        index.remove(&src.name, src_start);
+        index.insert(&renamed.name, src_start);
+      }
+    }
 
     true
   }
 
+  /*
+    Rewrite the entries in use contiguously, dropping the unused slots between them, and shrink
+    the directory to fit, releasing its trailing blocks
+  */
+  pub fn compact(&mut self, free_map: &mut FreeMap, disk: &mut BlockDevice) -> bool {
+    let mut inode = self.inode.borrow_mut();
+
+    let mut entries: Vec<DirEntry> = Vec::new();
+    let mut start: Ofs = 0;
+    while start as usize + std::mem::size_of::<DirEntry>() <= inode.length() as usize {
+      let entry = read_entry(&inode, start, disk);
+      if entry.in_use {
+        entries.push(entry);
+      }
+
+      start += std::mem::size_of::<DirEntry>() as Ofs;
+    }
+
+    let mut index = DirIndex::new(self.hash);
+    let mut start: Ofs = 0;
+    for entry in &entries {
+      write_entry(&inode, entry, start, disk);
+      index.insert(&entry.name, start);
+      start += std::mem::size_of::<DirEntry>() as Ofs;
+    }
+    index.free_hint = start;
+
+    *inode.dir_index_mut() = Some(index);
+    inode.set_len(start as Size, free_map, disk)
+  }
+
   pub fn list(&self, disk: &mut BlockDevice) -> Vec<String> {
-    let mut files: Vec<String> = Vec::new();
+    self.entries(disk).into_iter().map(|(name, _)| name).collect()
+  }
+
+  /* Name and inode number of every entry in use */
+  pub fn entries(&self, disk: &mut BlockDevice) -> Vec<(String, Size)> {
+    let mut files: Vec<(String, Size)> = Vec::new();
 
     let mut start: Ofs = 0;
     let inode = self.inode.borrow();
 
     while start as usize + std::mem::size_of::<DirEntry>() <= inode.length() as usize {
-      let mut raw = [0; std::mem::size_of::<DirEntry>()];
-      inode.borrow().read_at(&mut raw, start, disk);
-
-      let entry: DirEntry = unsafe { std::mem::transmute(raw) };
+      let entry = read_entry(&inode, start, disk);
 
       if entry.in_use {
         let terminator = entry
@@ -193,8 +379,9 @@ impl<'a> Dir<'a> {
           .iter()
           .position(|&x| x == b'\0')
           .expect("not null-terminated");
-        let filename = String::from_utf8(entry.name[..terminator].to_vec()).expect(NON_ASCII_ERR);
-        files.push(filename);
+        /* Names are stored as UTF-8. Bytes damaged on disk are replaced rather than refused */
+        let filename = String::from_utf8_lossy(&entry.name[..terminator]).into_owned();
+        files.push((filename, entry.block));
       }
 
       start += std::mem::size_of::<DirEntry>() as Ofs;
@@ -203,3 +390,222 @@ impl<'a> Dir<'a> {
     files
   }
 }
+
+impl DirIndex {
+  fn new(hash: DirHash) -> Self {
+    DirIndex {
+      hash,
+      ..DirIndex::default()
+    }
+  }
+
+  fn insert(&mut self, name: &FileName, start: Ofs) {
+    self.offsets.entry(self.hash.of(name)).or_default().push(start);
+  }
+
+  fn remove(&mut self, name: &FileName, start: Ofs) {
+    if let Some(offsets) = self.offsets.get_mut(&self.hash.of(name)) {
+      offsets.retain(|&o| o != start);
+    }
+  }
+}
+
+pub fn check_name(path: &str) -> Result<(), NameError> {
+  match path.len() {
+    0 => Err(NameError::Empty),
+    len if len > NAME_MAX => Err(NameError::TooLong { len }),
+    _ => Ok(()),
+  }
+}
+
+/* The UTF-8 bytes of `path`, which `check_name` has already limited to NAME_MAX */
+fn file_name(path: &str) -> FileName {
+  let mut name = [b'\0'; NAME_MAX + 1];
+  name[..path.len()].copy_from_slice(path.as_bytes());
+  name
+}
+
+impl DirHash {
+  fn of(self, name: &FileName) -> u64 {
+    match self {
+      DirHash::Fnv1a => super::fnv1a(super::FNV_OFFSET, name),
+      DirHash::Djb2 => name
+        .iter()
+        .fold(DJB2_OFFSET, |hash, &b| hash.wrapping_mul(33) ^ b as u64),
+    }
+  }
+}
+
+/* Mark the entry at `start` unused and drop it from the index */
+fn clear_entry(inode: &mut Inode, entry: DirEntry, start: Ofs, disk: &mut BlockDevice) {
+  let removed = DirEntry {
+    in_use: false,
+    ..entry
+  };
+  write_entry(inode, &removed, start, disk);
+
+  let index = inode.dir_index_mut().as_mut().expect(NO_INDEX_ERR);
+  index.remove(&removed.name, start);
+  index.free_hint = std::cmp::min(index.free_hint, start);
+}
+
+fn read_entry(inode: &Inode, start: Ofs, disk: &mut BlockDevice) -> DirEntry {
+  let mut raw = [0; std::mem::size_of::<DirEntry>()];
+  inode.read_at(&mut raw, start, disk);
+
+  let mut name = [b'\0'; NAME_MAX + 1];
+  name.copy_from_slice(&raw[..BLOCK_OFFSET]);
+
+  DirEntry {
+    name,
+    block: block::read_size_le(&raw, BLOCK_OFFSET),
+    in_use: raw[IN_USE_OFFSET] != 0,
+  }
+}
+
+fn write_entry(inode: &Inode, entry: &DirEntry, start: Ofs, disk: &mut BlockDevice) {
+  let mut raw = [0; std::mem::size_of::<DirEntry>()];
+  raw[..BLOCK_OFFSET].copy_from_slice(&entry.name);
+  block::write_size_le(&mut raw, BLOCK_OFFSET, entry.block);
+  raw[IN_USE_OFFSET] = entry.in_use as u8;
+
+  inode.write_at(&raw, start, disk);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesys::{block::DeviceType, inode::FileType, tests::TempImage, Filesys};
+
+  fn add_to_root(filesys: &mut Filesys, name: &str, inumber: Size) -> Result<(), DirError> {
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    let free_map = filesys.free_map.as_mut().unwrap();
+    Dir::open_root(&mut filesys.inodes, disk).add(name, inumber, free_map, disk)
+  }
+
+  fn root_len(filesys: &mut Filesys) -> Size {
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    let root = Dir::open_root(&mut filesys.inodes, disk);
+    let len = root.inode.borrow().length();
+    len
+  }
+
+  /*
+    Fill the root's first block with entries and then all but `spare` free blocks with unnamed
+    inodes, so the next entry needs a block that may not be there. Returns one entry's inode
+  */
+  fn fill_root_and_disk(filesys: &mut Filesys, spare: Size) -> Size {
+    let slots = block::BLOCK_USIZE / std::mem::size_of::<DirEntry>();
+    for i in filesys.list_sorted("", false).unwrap().len()..slots {
+      assert!(filesys.create_file(&format!("f{}", i), 0).is_ok());
+    }
+    assert_eq!(root_len(filesys), block::BLOCK_SIZE);
+
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    let free_map = filesys.free_map.as_mut().unwrap();
+    while free_map.available() > spare {
+      assert!(filesys
+        .inodes
+        .create_inode(0, FileType::Regular, disk, free_map)
+        .is_some());
+    }
+    filesys.stat("f0").unwrap().inumber
+  }
+
+  /* The first two names of lowercase letters and digits sharing a hash under `hash` */
+  fn colliding_pair(hash: DirHash) -> Option<(String, String)> {
+    let chars: Vec<char> = ('a'..='z').chain('0'..='9').collect();
+    let mut seen = HashMap::new();
+    for &first in &chars {
+      for &second in &chars {
+        let name = format!("{}{}", first, second);
+        if let Some(other) = seen.insert(hash.of(&file_name(&name)), name.clone()) {
+          return Some((other, name));
+        }
+      }
+    }
+    None
+  }
+
+  #[test]
+  fn names_sharing_a_hash_are_still_told_apart() {
+    let (a, b) = colliding_pair(DirHash::Djb2).unwrap();
+    assert_ne!(
+      DirHash::Fnv1a.of(&file_name(&a)),
+      DirHash::Fnv1a.of(&file_name(&b))
+    );
+
+    for hash in [DirHash::Fnv1a, DirHash::Djb2] {
+      let image = TempImage::new("dir_hash");
+      let mut filesys = image.build(64);
+      filesys.set_dir_hash(hash);
+      assert!(filesys.create_file_with_data(&a, b"first").is_ok());
+      assert!(filesys.create_file_with_data(&b, b"second!").is_ok());
+
+      assert_eq!(filesys.stat(&a).unwrap().len, 5, "{:?}", hash);
+      assert_eq!(filesys.stat(&b).unwrap().len, 7, "{:?}", hash);
+      assert!(filesys.remove_file(&a));
+      assert!(filesys.stat(&a).is_none());
+      assert_eq!(filesys.stat(&b).unwrap().len, 7, "{:?}", hash);
+    }
+  }
+
+  #[test]
+  fn add_names_each_cause_of_failure() {
+    let image = TempImage::new("dir_add_errors");
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file("taken", 0).is_ok());
+    let inumber = filesys.stat("taken").unwrap().inumber;
+
+    assert_eq!(
+      add_to_root(&mut filesys, "", inumber),
+      Err(DirError::Name(NameError::Empty))
+    );
+    let long = "x".repeat(NAME_MAX + 1);
+    assert_eq!(
+      add_to_root(&mut filesys, &long, inumber),
+      Err(DirError::Name(NameError::TooLong { len: NAME_MAX + 1 }))
+    );
+    assert_eq!(add_to_root(&mut filesys, "taken", inumber), Err(DirError::Exists));
+    assert_eq!(add_to_root(&mut filesys, "alias", inumber), Ok(()));
+
+    let image = TempImage::new("dir_add_full");
+    let mut filesys = image.build(64);
+    let inumber = fill_root_and_disk(&mut filesys, 0);
+    assert_eq!(add_to_root(&mut filesys, "extra", inumber), Err(DirError::OutOfSpace));
+  }
+
+  #[test]
+  fn a_directory_that_cannot_grow_is_left_unchanged() {
+    let image = TempImage::new("dir_extend_full");
+    let mut filesys = image.build(64);
+    let inumber = fill_root_and_disk(&mut filesys, 0);
+    let names = filesys.list_sorted("", false).unwrap();
+    let drift = filesys.verify_free_map();
+
+    assert_eq!(add_to_root(&mut filesys, "extra", inumber), Err(DirError::OutOfSpace));
+    assert_eq!(root_len(&mut filesys), block::BLOCK_SIZE);
+    assert_eq!(filesys.list_sorted("", false).unwrap(), names);
+    assert_eq!(filesys.free_blocks(), 0);
+    assert_eq!(filesys.verify_free_map(), drift);
+
+    /* The entry still fits once a slot is freed */
+    assert!(filesys.remove_file("f1"));
+    assert_eq!(add_to_root(&mut filesys, "extra", inumber), Ok(()));
+    assert_eq!(root_len(&mut filesys), block::BLOCK_SIZE);
+  }
+
+  #[test]
+  fn create_file_gives_back_its_inode_when_the_directory_cannot_grow() {
+    let image = TempImage::new("dir_extend_create");
+    let mut filesys = image.build(64);
+    fill_root_and_disk(&mut filesys, 1);
+    let drift = filesys.verify_free_map();
+
+    assert_eq!(filesys.create_file("extra", 0), Err(DirError::OutOfSpace));
+    assert_eq!(filesys.free_blocks(), 1);
+    assert!(filesys.stat("extra").is_none());
+    assert_eq!(root_len(&mut filesys), block::BLOCK_SIZE);
+    assert_eq!(filesys.verify_free_map(), drift);
+  }
+}
@@ -0,0 +1,79 @@
+use super::block::{self, BlockOperations, IoCounts};
+use crate::Size;
+
+/* Which access a `FaultyDisk` fails. Counts start at 1 and include formatting */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+  NthRead(usize),
+  NthWrite(usize),
+  WriteTo(Size),
+}
+
+/*
+  Passes accesses through to `inner` until the configured fault, which panics. Writes after it are
+  dropped, as by a disk that crashed, so a cache writing back as it is dropped cannot undo the fault
+*/
+pub struct FaultyDisk<B: BlockOperations> {
+  inner: B,
+  fault: Fault,
+  reads: usize,
+  writes: usize,
+  crashed: bool,
+}
+
+impl<B: BlockOperations> FaultyDisk<B> {
+  pub fn new(inner: B, fault: Fault) -> Self {
+    FaultyDisk {
+      inner,
+      fault,
+      reads: 0,
+      writes: 0,
+      crashed: false,
+    }
+  }
+}
+
+impl<B: BlockOperations> BlockOperations for FaultyDisk<B> {
+  fn read(&mut self, buf: &mut [u8; block::BLOCK_USIZE], pos: Size) {
+    self.reads += 1;
+    if self.fault == Fault::NthRead(self.reads) {
+      panic!("injected fault: read {} of block {}", self.reads, pos);
+    }
+
+    self.inner.read(buf, pos);
+  }
+
+  fn write(&mut self, buf: &[u8; block::BLOCK_USIZE], pos: Size) {
+    self.writes += 1;
+    if self.crashed {
+      return;
+    }
+    if self.fault == Fault::NthWrite(self.writes) || self.fault == Fault::WriteTo(pos) {
+      self.crashed = true;
+      panic!("injected fault: write {} of block {}", self.writes, pos);
+    }
+
+    self.inner.write(buf, pos);
+  }
+
+  fn flush(&mut self) {
+    self.inner.flush();
+  }
+
+  fn prefetch(&mut self, pos: Size) {
+    self.inner.prefetch(pos);
+  }
+
+  fn resize(&mut self, blocks: Size) -> bool {
+    self.inner.resize(blocks)
+  }
+
+  fn host_counts(&self) -> Option<IoCounts> {
+    self.inner.host_counts()
+  }
+
+  /* The access counts that trigger the fault are not statistics, and keep running */
+  fn reset_counters(&mut self) {
+    self.inner.reset_counters();
+  }
+}
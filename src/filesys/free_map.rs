@@ -1,51 +1,428 @@
-use super::vfile::VFile;
+use std::collections::HashSet;
+
+use super::{
+  block::{self, BlockDevice},
+  inode::Inode,
+};
 use crate::bitmap::Bitmap;
 
 use crate::Size;
 
-pub struct FreeMap<'a> {
-  _file: VFile<'a>,
+/* A request for more blocks than are free */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfSpace {
+  pub requested: Size,
+  pub available: Size,
+}
+
+/* Where `allocate` looks for free blocks */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocPolicy {
+  /* Lowest free blocks first */
+  #[default]
+  FirstFit,
+  /* Continue from just after the previous allocation, wrapping around */
+  NextFit,
+  /* Smallest run of free blocks the request fits in, or first-fit if none does */
+  BestFitContiguous,
+}
+
+/* The bitmap is persisted in the file stored at `inode` */
+pub struct FreeMap {
+  inode: Size,
   bitmap: Bitmap,
+  policy: AllocPolicy,
+  /* Next block NextFit considers */
+  cursor: Size,
+  /*
+    Blocks that are never handed out or released. Only the bits are persisted, so after a reload
+    a reserved block stays allocated but is no longer protected from `release`
+  */
+  reserved: HashSet<Size>,
+  /* Whether blocks are zeroed on the device as they are handed out, so stale data never leaks */
+  zero_new: bool,
+  /* Blocks below this form the inode table, holding inodes only. Zero when there is no table */
+  inode_limit: Size,
 }
 
-impl<'a> FreeMap<'a> {
-  pub fn init(_file: VFile<'a>, bits: Size) -> Self {
+impl FreeMap {
+  pub fn init(inode: Size, bits: Size) -> Self {
     let mut bitmap = Bitmap::new(bits);
     bitmap.mark(super::ROOT_INODE);
     bitmap.mark(super::FREE_MAP_INODE);
-    FreeMap { _file, bitmap }
+    FreeMap {
+      inode,
+      bitmap,
+      policy: AllocPolicy::default(),
+      cursor: 0,
+      reserved: HashSet::from([super::ROOT_INODE, super::FREE_MAP_INODE]),
+      zero_new: false,
+      inode_limit: 0,
+    }
+  }
+
+  pub fn inode(&self) -> Size {
+    self.inode
+  }
+
+  /* Write the bitmap to its file, growing the file on first use */
+  pub fn flush(&mut self, inode: &mut Inode, disk: &mut BlockDevice) -> bool {
+    assert_eq!(inode.inumber(), self.inode);
+
+    let len = self.bitmap.to_bytes().len() as Size;
+    if !inode.set_len(len, self, disk) {
+      return false;
+    }
+
+    /* Serialised after growing, so the file's own blocks are marked */
+    inode.write_at(&self.bitmap.to_bytes(), 0, disk);
+    true
+  }
+
+  /* Read a bitmap of `bits` blocks back from its file */
+  pub fn open(inode: &Inode, bits: Size, disk: &mut BlockDevice) -> Self {
+    let mut bytes = vec![0; inode.length() as usize];
+    inode.read_at(&mut bytes, 0, disk);
+
+    let mut bitmap = Bitmap::from_bytes(bits, &bytes);
+    bitmap.mark(super::ROOT_INODE);
+    bitmap.mark(super::FREE_MAP_INODE);
+    FreeMap {
+      inode: inode.inumber(),
+      bitmap,
+      policy: AllocPolicy::default(),
+      cursor: 0,
+      reserved: HashSet::from([super::ROOT_INODE, super::FREE_MAP_INODE]),
+      zero_new: false,
+      inode_limit: 0,
+    }
+  }
+
+  pub fn set_policy(&mut self, policy: AllocPolicy) {
+    self.policy = policy;
   }
 
-  fn _open() -> Self {
-    // Read bitmap from file
-    // let _file = VFile::open(Inode::open(FREE_MAP_BLOCK));
-    todo!()
+  pub fn set_zero_new(&mut self, zero_new: bool) {
+    self.zero_new = zero_new;
+  }
+
+  /*
+    Keep blocks below `limit` for inodes and everything else above it. False if a block in the
+    table is already allocated, other than the reserved root and free-map inodes
+  */
+  pub fn set_inode_limit(&mut self, limit: Size) -> bool {
+    let limit = std::cmp::min(limit, self.bitmap.count());
+    if (0..limit).any(|b| self.bitmap.test(b) && !self.reserved.contains(&b)) {
+      return false;
+    }
+
+    self.inode_limit = limit;
+    self.cursor = std::cmp::max(self.cursor, limit);
+    true
+  }
+
+  /* A block for a new inode, from the inode table if there is one */
+  pub fn allocate_inode(&mut self) -> Option<Size> {
+    if self.inode_limit == 0 {
+      let mut dst = Vec::new();
+      return self.allocate(1, &mut dst).then(|| dst[0]);
+    }
+
+    let block = (0..self.inode_limit).find(|&b| !self.bitmap.test(b))?;
+    self.bitmap.mark(block);
+    Some(block)
+  }
+
+  /* Zero freshly allocated blocks if enabled. Callers pass the blocks `allocate` just handed out */
+  pub fn scrub(&self, blocks: &[Size], disk: &mut BlockDevice) {
+    if self.zero_new {
+      blocks.iter().for_each(|&b| disk.write(&block::EMPTY_BLOCK, b));
+    }
+  }
+
+  pub fn is_allocated(&self, block: Size) -> bool {
+    self.bitmap.test(block)
+  }
+
+  pub fn is_reserved(&self, block: Size) -> bool {
+    self.reserved.contains(&block)
+  }
+
+  /* Zero when there is no inode table */
+  pub fn inode_limit(&self) -> Size {
+    self.inode_limit
+  }
+
+  pub fn allocated_blocks(&self) -> impl Iterator<Item = Size> + '_ {
+    self.bitmap.iter_set()
+  }
+
+  /* Start and length of each run of unallocated blocks, the inode table included */
+  pub fn free_extents(&self) -> impl Iterator<Item = (Size, Size)> + '_ {
+    self.bitmap.iter_clear_runs()
+  }
+
+  /* Track `bits` blocks instead. False if an allocated block would be dropped */
+  pub fn resize(&mut self, bits: Size) -> bool {
+    if !self.bitmap.resize(bits) {
+      return false;
+    }
+
+    self.cursor %= bits;
+    true
+  }
+
+  /* Number of blocks not yet allocated outside any inode table, which are all data can use */
+  pub fn available(&self) -> Size {
+    self.free_between(self.inode_limit, self.bitmap.count())
+  }
+
+  /* Unused inode table slots. Without a table an inode may take any free block */
+  pub fn free_inode_slots(&self) -> Size {
+    match self.inode_limit {
+      0 => self.available(),
+      limit => self.free_between(0, limit),
+    }
+  }
+
+  fn free_between(&self, start: Size, end: Size) -> Size {
+    (start..end).filter(|&b| !self.bitmap.test(b)).count() as Size
   }
 
   pub fn allocate(&mut self, blocks: usize, dst: &mut Vec<Size>) -> bool {
-    let mut allocations: Vec<Size> = Vec::with_capacity(blocks);
+    self.try_allocate(blocks, dst).is_ok()
+  }
+
+  /*
+    Allocate `blocks` blocks under the current policy, outside any inode table. Nothing is marked if
+    the request cannot be met
+  */
+  pub fn try_allocate(&mut self, blocks: usize, dst: &mut Vec<Size>) -> Result<(), OutOfSpace> {
+    let available = self.available();
+    if blocks as Size > available {
+      return Err(OutOfSpace {
+        requested: blocks as Size,
+        available,
+      });
+    }
+
+    let start = match self.policy {
+      AllocPolicy::FirstFit => self.inode_limit,
+      AllocPolicy::NextFit => self.cursor,
+      AllocPolicy::BestFitContiguous => self.best_run(blocks as Size).unwrap_or(self.inode_limit),
+    };
 
-    let mut idx = 0;
+    let bits = self.bitmap.count();
     let mut count = 0;
+    let mut idx = start;
 
-    while count < blocks && idx < self.bitmap.count() {
-      if self.bitmap.compare_and_flip(idx) {
+    while count < blocks {
+      if idx >= self.inode_limit && self.bitmap.compare_and_flip(idx) {
         count += 1;
-        allocations.push(idx);
+        dst.push(idx);
+        self.cursor = (idx + 1) % bits;
       }
-      idx += 1;
+      idx = (idx + 1) % bits;
+    }
+
+    Ok(())
+  }
+
+  /* Allocate `blocks` adjacent blocks from the smallest run that fits. False if none does */
+  pub fn allocate_contiguous(&mut self, blocks: usize, dst: &mut Vec<Size>) -> bool {
+    let Some(start) = self.best_run(blocks as Size) else {
+      return false;
+    };
+
+    for block in start..start + blocks as Size {
+      self.bitmap.mark(block);
+      dst.push(block);
     }
+    true
+  }
+
+  /* Allocate `blocks` adjacent blocks starting at a multiple of `alignment`, a power of two */
+  pub fn allocate_aligned(&mut self, blocks: usize, alignment: Size) -> Option<Size> {
+    assert!(alignment.is_power_of_two());
+
+    let len = blocks as Size;
+    let start = (self.inode_limit.next_multiple_of(alignment)..self.bitmap.count())
+      .step_by(alignment as usize)
+      .take_while(|&start| start + len <= self.bitmap.count())
+      .find(|&start| (start..start + len).all(|block| !self.bitmap.test(block)))?;
+
+    self.bitmap.set_range(start, len);
+    Some(start)
+  }
+
+  /* Start of the smallest run of free blocks at least `len` long */
+  fn best_run(&self, len: Size) -> Option<Size> {
+    let mut best: Option<(Size, Size)> = None;
+    let mut run_start = self.inode_limit;
+
+    for idx in self.inode_limit..=self.bitmap.count() {
+      if idx < self.bitmap.count() && !self.bitmap.test(idx) {
+        continue;
+      }
 
-    if count == blocks {
-      allocations.into_iter().for_each(|a| dst.push(a));
-      true
-    } else {
-      false
+      let run_len = idx - run_start;
+      if run_len >= len && best.is_none_or(|(_, best_len)| run_len < best_len) {
+        best = Some((run_start, run_len));
+      }
+      run_start = idx + 1;
     }
+
+    best.map(|(start, _)| start)
   }
 
-  fn _release(&mut self, block: Size) {
-    assert!(!self.bitmap.test(block));
+  /* Mark a block in use without handing it out, when rebuilding the map from the inode tree */
+  pub fn mark_allocated(&mut self, block: Size) {
+    self.bitmap.mark(block);
+  }
+
+  /* Reserved blocks are left allocated */
+  pub fn release(&mut self, block: Size) {
+    if self.reserved.contains(&block) {
+      return;
+    }
+
+    assert!(self.bitmap.test(block));
     self.bitmap.reset(block);
   }
+
+  /* Keep a block allocated permanently. False if something else already holds it */
+  pub fn reserve(&mut self, block: Size) -> bool {
+    if self.reserved.contains(&block) {
+      return true;
+    }
+
+    if !self.bitmap.compare_and_flip(block) {
+      return false;
+    }
+    self.reserved.insert(block);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn a_failed_allocation_reports_what_is_available() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(10, &mut dst));
+    let available = free_map.available();
+    assert_eq!(available, 64 - 2 - 10);
+
+    let mut failed = Vec::new();
+    assert_eq!(
+      free_map.try_allocate(100, &mut failed),
+      Err(OutOfSpace {
+        requested: 100,
+        available
+      })
+    );
+    assert!(failed.is_empty());
+    assert_eq!(free_map.available(), available);
+  }
+
+  #[test]
+  fn next_fit_hands_out_increasing_blocks_across_calls() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    free_map.set_policy(AllocPolicy::NextFit);
+
+    let mut first = Vec::new();
+    assert!(free_map.allocate(3, &mut first));
+    assert_eq!(first, vec![2, 3, 4]);
+    first.iter().for_each(|&block| free_map.release(block));
+
+    let mut second = Vec::new();
+    assert!(free_map.allocate(2, &mut second));
+    assert_eq!(second, vec![5, 6]);
+  }
+
+  #[test]
+  fn best_fit_prefers_the_tightest_run() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    let mut all = Vec::new();
+    assert!(free_map.allocate(20, &mut all));
+
+    /* Runs of 10 and 3 free blocks, before the large one from 22 */
+    (4..14).chain(16..19).for_each(|block| free_map.release(block));
+    free_map.set_policy(AllocPolicy::BestFitContiguous);
+
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(3, &mut dst));
+    assert_eq!(dst, vec![16, 17, 18]);
+  }
+
+  #[test]
+  fn a_reserved_block_is_never_allocated_or_released() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    assert!(free_map.reserve(5));
+    assert!(free_map.reserve(5));
+
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(61, &mut dst));
+    assert!(!dst.contains(&5));
+    assert_eq!(free_map.available(), 0);
+
+    free_map.release(5);
+    assert!(free_map.is_allocated(5));
+    dst.iter().for_each(|&block| free_map.release(block));
+    assert_eq!(free_map.available(), 61);
+  }
+
+  #[test]
+  fn a_block_in_use_cannot_be_reserved() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(1, &mut dst));
+
+    assert!(!free_map.reserve(dst[0]));
+    assert!(!free_map.is_reserved(dst[0]));
+  }
+
+  #[test]
+  fn aligned_runs_start_on_a_multiple_of_the_alignment() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(3, &mut dst));
+    assert_eq!(dst, vec![2, 3, 4]);
+
+    let start = free_map.allocate_aligned(4, 4).unwrap();
+    assert_eq!(start % 4, 0);
+    assert_eq!(start, 8);
+    assert!((start..start + 4).all(|block| free_map.is_allocated(block)));
+    assert_eq!(free_map.available(), 64 - 2 - 3 - 4);
+
+    assert_eq!(free_map.allocate_aligned(4, 64), None);
+  }
+
+  #[test]
+  fn an_inode_table_is_counted_apart_from_data_blocks() {
+    let mut free_map = FreeMap::init(super::super::FREE_MAP_INODE, 64);
+    assert_eq!(free_map.free_inode_slots(), free_map.available());
+
+    assert!(free_map.set_inode_limit(8));
+    assert_eq!(free_map.available(), 64 - 8);
+    assert_eq!(free_map.free_inode_slots(), 8 - 2);
+
+    assert_eq!(free_map.allocate_inode(), Some(2));
+    let mut dst = Vec::new();
+    assert!(free_map.allocate(3, &mut dst));
+    assert_eq!(free_map.available(), 64 - 8 - 3);
+    assert_eq!(free_map.free_inode_slots(), 8 - 3);
+
+    /* The table's free slots are not offered to data */
+    assert_eq!(
+      free_map.try_allocate(64, &mut dst),
+      Err(OutOfSpace {
+        requested: 64,
+        available: 64 - 8 - 3
+      })
+    );
+  }
 }
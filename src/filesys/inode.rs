@@ -1,26 +1,65 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::HashMap};
 
 use super::{
   block::{self, BlockDevice, BLOCK_USIZE},
+  directory::{DirHash, DirIndex},
   free_map::FreeMap,
 };
 use crate::{Ofs, Size};
 
 /* Arbitrarily chosen value for inodes on disk. Used to detect corruption */
-const INODE_MAGIC: Size = 0x8BCEFADC;
+const INODE_MAGIC: Size = 0x8BCEFADD;
+/*
+  Magic of inodes written before they carried a checksum, whose checksum field is not checked.
+  Such an inode takes `INODE_MAGIC` the next time it is written
+*/
+const UNCHECKSUMMED_MAGIC: Size = 0x8BCEFADC;
+
+/* On-disk encoding of each file type. Zero is left unused to catch unformatted inodes */
+const REGULAR_KIND: Size = 1;
+const DIRECTORY_KIND: Size = 2;
 
-/* Reassignable constants, subject to they fit inside an inode */
+/*
+  Reassignable constants, subject to they fit inside an inode. The `small-files` feature selects
+  enough direct pointers that files of up to 16 blocks never need a pointer block. Images are only
+  readable with the shape that wrote them
+*/
+#[cfg(not(feature = "small-files"))]
 const N_DIRECT: usize = 4;
+#[cfg(feature = "small-files")]
+const N_DIRECT: usize = 16;
 const N_INDIRECT: usize = 1;
 const N_DOUBLY_INDIRECT: usize = 1;
 
+const SIZE_BYTES: usize = std::mem::size_of::<Size>();
+
 /*  */
-const PTRS_PER_BLOCK: usize = block::BLOCK_USIZE / std::mem::size_of::<Size>();
+const PTRS_PER_BLOCK: usize = block::BLOCK_USIZE / SIZE_BYTES;
 type PtrBlock = [Size; PTRS_PER_BLOCK];
 
-/* Stores all inodes currently open */
+/* Data blocks the direct, indirect and doubly indirect pointers can address between them */
+const MAX_FILE_BLOCKS: usize =
+  N_DIRECT + N_INDIRECT * PTRS_PER_BLOCK + N_DOUBLY_INDIRECT * PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+pub const MAX_FILE_SIZE: Size = (MAX_FILE_BLOCKS * BLOCK_USIZE) as Size;
+
+/*
+  Stores all inodes currently open. Each is boxed so the reference a handle holds stays valid while
+  other inodes are opened and closed around it
+*/
 pub struct InodeManager {
-  open_list: Vec<Inode>,
+  #[allow(clippy::vec_box)]
+  open_list: Vec<Box<Inode>>,
+  /* Unlinked inodes whose last handle has closed, waiting for `reap` to release their blocks */
+  orphans: Vec<Size>,
+  /* Hash given to directory indexes as they are built */
+  dir_hash: DirHash,
+}
+
+/* Type of data an inode holds */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileType {
+  Regular,
+  Directory,
 }
 
 /* In-memory Inode */
@@ -28,6 +67,17 @@ pub struct Inode {
   open_count: usize,
   block: Size,
   data: InodeDisk,
+  dir_index: Option<DirIndex>,
+  /* Pointer blocks read while the inode is open, dropped whenever its blocks change */
+  ptr_cache: RefCell<PtrCache>,
+  /* No directory refers to the inode any more, so it is freed once closed */
+  pending_delete: bool,
+}
+
+/* Decoded pointer blocks by block number */
+#[derive(Default)]
+struct PtrCache {
+  blocks: HashMap<Size, PtrBlock>,
 }
 
 /* On-disk Inode. Must be exactly BLOCK_SIZE bytes long */
@@ -39,67 +89,155 @@ struct InodeDisk {
   doubly_indirect: [Size; N_DOUBLY_INDIRECT],
   magic: Size,
   len: Size,
+  kind: Size,
+  /* Bumped each time the block is given to a new inode, so handles on the old one can tell */
+  generation: Size,
+  /* FNV-1a of the encoded block with this field zeroed. Unset under `UNCHECKSUMMED_MAGIC` */
+  checksum: Size,
   unused: [u8;
-    BLOCK_USIZE - std::mem::size_of::<Size>() * (2 + N_DIRECT + N_INDIRECT + N_DOUBLY_INDIRECT)],
+    BLOCK_USIZE - std::mem::size_of::<Size>() * (5 + N_DIRECT + N_INDIRECT + N_DOUBLY_INDIRECT)],
 }
 
+const CHECKSUM_OFFSET: usize = std::mem::offset_of!(InodeDisk, checksum);
+
+/* Checked at compile time, so miscalculated `unused` padding fails the build */
+/// A layout a byte too large is rejected by the same check:
+///
+/// ```compile_fail,E0080
+/// #[repr(C)]
+/// struct Oversized {
+///   fields: [u64; 4],
+///   unused: [u8; 1024 - 8 * 4 + 1],
+/// }
+///
+/// const _: () = {
+///   assert!(std::mem::size_of::<Oversized>() == 1024);
+/// };
+/// ```
 const _: () = {
   assert!(std::mem::size_of::<InodeDisk>() == block::BLOCK_USIZE);
 };
 
 impl InodeManager {
   pub const fn init() -> Self {
-    Self { open_list: Vec::new() }
+    Self {
+      open_list: Vec::new(),
+      orphans: Vec::new(),
+      dir_hash: DirHash::Fnv1a,
+    }
+  }
+
+  pub fn dir_hash(&self) -> DirHash {
+    self.dir_hash
+  }
+
+  /* Open directories drop their index, to be rebuilt under `hash` on their next lookup */
+  pub fn set_dir_hash(&mut self, hash: DirHash) {
+    self.dir_hash = hash;
+    self.open_list.iter_mut().for_each(|inode| inode.dir_index = None);
   }
 
   /*
-    Create a new inode on disk with allocated blocks for `length` bytes
+    Create a new inode on disk with allocated blocks for `length` bytes. None if the blocks could
+    not be allocated, in which case none are
   */
   pub fn create_inode(
     &mut self,
     length: Size,
+    kind: FileType,
     disk: &mut BlockDevice,
     free_map: &mut FreeMap,
-  ) -> RefCell<&mut Inode> {
-    assert_eq!(std::mem::size_of::<InodeDisk>(), block::BLOCK_USIZE);
-
+  ) -> Option<Size> {
+    if length > MAX_FILE_SIZE {
+      return None;
+    }
     let block_count = bytes_to_blocks(length);
 
-    let mut allocations: Vec<Size> = Vec::new();
-    free_map.allocate(1 + block_count, &mut allocations);
-    let mut blocks = allocations.into_iter();
+    let inode_block = free_map.allocate_inode()?;
+    let mut allocations = vec![inode_block];
+    if !free_map.allocate(block_count + pointer_blocks(block_count), &mut allocations) {
+      free_map.release(inode_block);
+      return None;
+    }
+
+    /* Read before scrubbing, which would erase the previous generation */
+    let previous = self.disk_inode(inode_block, disk);
+    let generation = match previous.has_magic() {
+      true => previous.generation.wrapping_add(1),
+      false => 0,
+    };
 
-    let inode_block = blocks.next().expect("block not found");
+    free_map.scrub(&allocations, disk);
+    let mut blocks = allocations.into_iter().skip(1);
 
     /* Allocate disk blocks */
     let mut skip = 0;
     let mut data = InodeDisk::default();
     fill_direct(&mut skip, &mut data.direct, &mut blocks);
     fill_indirect(&mut skip, &mut data.indirect, &mut blocks, disk);
-    fill_doubly_indirect(&mut skip, &mut data.indirect, &mut blocks, disk);
+    fill_doubly_indirect(&mut skip, &mut data.doubly_indirect, &mut blocks, disk);
     data.len = length;
+    data.kind = kind.into();
+    data.generation = generation;
+
+    /* `unlink` keeps an open inode's block until its last handle closes, so none is open here */
+    debug_assert!(!self.is_open(inode_block));
 
     /* Write inode to disk */
-    disk.write(&data.clone().into(), inode_block);
+    disk.write(&data.into(), inode_block);
+
+    Some(inode_block)
+  }
+
+  /* A new empty inode that no directory refers to, opened once and freed when that handle closes */
+  pub fn open_anonymous(
+    &mut self,
+    kind: FileType,
+    disk: &mut BlockDevice,
+    free_map: &mut FreeMap,
+  ) -> Option<RefCell<&mut Inode>> {
+    let block_num = self.create_inode(0, kind, disk, free_map)?;
+
+    let inode = self.open_inode(block_num, disk);
+    inode.borrow_mut().pending_delete = true;
+    Some(inode)
+  }
+
+  /* Write an empty inode of the given type to a fixed block, such as the root directory */
+  pub fn format_inode(&mut self, block_num: Size, kind: FileType, disk: &mut BlockDevice) {
+    assert!(self.open_list.iter().all(|i| i.block != block_num));
 
-    /* Initialise in-memory representation */
-    let inode = Inode {
-      open_count: 1,
-      block: inode_block,
-      data,
+    let data = InodeDisk {
+      kind: kind.into(),
+      ..InodeDisk::default()
     };
+    disk.write(&data.into(), block_num);
+  }
 
-    /* Push to global list */
-    self.open_list.push(inode);
+  /* Query the type of an inode without opening it */
+  pub fn file_type(&self, block_num: Size, disk: &mut BlockDevice) -> Option<FileType> {
+    FileType::try_from(self.disk_inode(block_num, disk).kind).ok()
+  }
 
-    /* Find a reference now that the InodeManager owns it */
-    let inode = self
-      .open_list
-      .iter_mut()
-      .find(|i| i.block == inode_block)
-      .expect("internal error: InodeManager does not contain newly added inode");
-    
-    RefCell::new(inode)
+  /* Blocks owned by an inode without opening it, or None if its magic is wrong */
+  pub fn inode_blocks(&self, block_num: Size, disk: &mut BlockDevice) -> Option<Vec<Size>> {
+    let data = self.disk_inode(block_num, disk);
+    if !data.has_magic() {
+      return None;
+    }
+
+    Some(data.blocks(block_num, disk))
+  }
+
+  /* The open copy of an inode if there is one, otherwise the copy on disk */
+  fn disk_inode(&self, block_num: Size, disk: &mut BlockDevice) -> InodeDisk {
+    if let Some(inode) = self.open_list.iter().find(|i| i.block == block_num) {
+      return inode.data.clone();
+    }
+
+    let mut block = block::EMPTY_BLOCK;
+    disk.read(&mut block, block_num);
+    InodeDisk::from(block)
   }
 
   /* Returns a reference to an inode, opening a new one if not already open */
@@ -111,15 +249,18 @@ impl InodeManager {
         let mut block = block::EMPTY_BLOCK;
         disk.read(&mut block, block_num);
 
-        let data: InodeDisk = unsafe { std::mem::transmute(block) };
+        let data = InodeDisk::from(block);
 
         let inode = Inode {
             open_count: 0,
             data,
             block: block_num,
+            dir_index: None,
+            ptr_cache: RefCell::default(),
+            pending_delete: false,
         };
         let new_index = self.open_list.len();
-        self.open_list.push(inode);
+        self.open_list.push(Box::new(inode));
         new_index
       });
 
@@ -128,17 +269,174 @@ impl InodeManager {
     RefCell::new(inode)
   }
 
+  /*
+    As `open_inode`, or None without opening anything if the block does not hold an intact inode.
+    An inode already open was checked when it was first read
+  */
+  pub fn open_verified(
+    &mut self,
+    block_num: Size,
+    disk: &mut BlockDevice,
+  ) -> Option<RefCell<&mut Inode>> {
+    if !self.is_open(block_num) && !holds_inode(block_num, disk) {
+      return None;
+    }
+
+    Some(self.open_inode(block_num, disk))
+  }
+
+  /*
+    As `open_inode`, for an inode held open for as long as the disk is mounted. Only the first call
+    counts a handle, so later ones need no matching close
+  */
+  pub fn open_pinned(&mut self, block_num: Size, disk: &mut BlockDevice) -> RefCell<&mut Inode> {
+    if !self.is_open(block_num) {
+      return self.open_inode(block_num, disk);
+    }
+
+    let inode = self
+      .open_list
+      .iter_mut()
+      .find(|i| i.block == block_num)
+      .expect("internal error: inode not found");
+    RefCell::new(inode)
+  }
+
+  /* Distinct regular files currently open. Directories, such as the pinned root, are left out */
+  pub fn open_regular_files(&self) -> usize {
+    self
+      .open_list
+      .iter()
+      .filter(|i| i.file_type() == Some(FileType::Regular))
+      .count()
+  }
+
+  /* Every open inode with its handle count */
+  pub fn open_handles(&self) -> Vec<(Size, usize)> {
+    self.open_list.iter().map(|i| (i.block, i.open_count)).collect()
+  }
+
+  /* Handles currently open on an inode, without opening it */
+  pub fn open_count(&self, block_num: Size) -> usize {
+    self
+      .open_list
+      .iter()
+      .find(|i| i.block == block_num)
+      .map_or(0, |i| i.open_count)
+  }
+
+  pub fn is_open(&self, block_num: Size) -> bool {
+    self.open_list.iter().any(|i| i.block == block_num)
+  }
+
+  /* Release every block of a closed inode back to the free map */
+  pub fn free_inode(&mut self, block_num: Size, free_map: &mut FreeMap, disk: &mut BlockDevice) {
+    assert!(!self.is_open(block_num));
+
+    let mut block = block::EMPTY_BLOCK;
+    disk.read(&mut block, block_num);
+
+    InodeDisk::from(block)
+      .blocks(block_num, disk)
+      .into_iter()
+      .for_each(|b| free_map.release(b));
+  }
+
+  /* Write every open inode back to disk, e.g. before shutdown */
+  pub fn flush_all(&mut self, disk: &mut BlockDevice) {
+    self.open_list.iter().for_each(|inode| inode.flush(disk));
+  }
+
+  /*
+    Move the blocks of a closed inode into one contiguous run, keeping the inode where it is. Data
+    and pointer blocks are written to the new run before the inode is switched over, and the old
+    blocks are only released afterwards. False if the inode is open or no run is large enough
+  */
+  pub fn defragment(&mut self, block_num: Size, free_map: &mut FreeMap, disk: &mut BlockDevice) -> bool {
+    if self.is_open(block_num) {
+      return false;
+    }
+
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, block_num);
+    let old = InodeDisk::from(raw);
+
+    let old_blocks = old.blocks(block_num, disk);
+    if old_blocks[1..].windows(2).all(|w| w[1] == w[0] + 1) {
+      return true;
+    }
+
+    let block_count = bytes_to_blocks(old.len);
+    let mut allocations: Vec<Size> = Vec::new();
+    if !free_map.allocate_contiguous(block_count + pointer_blocks(block_count), &mut allocations) {
+      return false;
+    }
+    let mut blocks = allocations.into_iter();
+
+    let mut skip = 0;
+    let mut data = InodeDisk {
+      direct: [0; N_DIRECT],
+      indirect: [0; N_INDIRECT],
+      doubly_indirect: [0; N_DOUBLY_INDIRECT],
+      ..old.clone()
+    };
+    fill_direct(&mut skip, &mut data.direct, &mut blocks);
+    fill_indirect(&mut skip, &mut data.indirect, &mut blocks, disk);
+    fill_doubly_indirect(&mut skip, &mut data.doubly_indirect, &mut blocks, disk);
+
+    let old_data = old.block_range(old.len, 0, &mut PtrCache::default(), disk);
+    let new_data = data.block_range(data.len, 0, &mut PtrCache::default(), disk);
+    for (&src, &dst) in old_data.iter().zip(&new_data) {
+      let mut bounce = block::EMPTY_BLOCK;
+      disk.read(&mut bounce, src);
+      disk.write(&bounce, dst);
+    }
+
+    disk.write(&data.into(), block_num);
+
+    old_blocks[1..].iter().for_each(|&b| free_map.release(b));
+    true
+  }
+
+  /*
+    Free an inode that no directory refers to any more. An open inode keeps its blocks until its
+    last handle closes and `reap` runs, so existing handles can still use it
+  */
+  pub fn unlink(&mut self, block_num: Size, free_map: &mut FreeMap, disk: &mut BlockDevice) {
+    match self.open_list.iter_mut().find(|i| i.block == block_num) {
+      Some(inode) => inode.pending_delete = true,
+      None => self.free_inode(block_num, free_map, disk),
+    }
+  }
+
+  /* Release the blocks of unlinked inodes that have since been closed */
+  pub fn reap(&mut self, free_map: &mut FreeMap, disk: &mut BlockDevice) {
+    for block_num in std::mem::take(&mut self.orphans) {
+      self.free_inode(block_num, free_map, disk);
+    }
+  }
+
   /* Decrement the open count and remove if we're the last reference */
   pub fn close(&mut self, inode_ref: RefCell<&mut Inode>) {
-    let mut inode = inode_ref.borrow_mut();
+    let block_num = inode_ref.borrow().block;
+    self.close_inumber(block_num);
+  }
+
+  /* As `close`, for callers whose reference was borrowed from this manager */
+  pub fn close_inumber(&mut self, block_num: Size) {
+    let idx: usize = self
+      .open_list
+      .iter()
+      .position(|i| i.block == block_num)
+      .expect("internal error: inode not found");
+
+    let inode = &mut self.open_list[idx];
     inode.decr_open();
 
     if inode.no_refs() {
-      let idx: usize = self
-        .open_list
-        .iter()
-        .position(|i| i.block == inode.block)
-        .expect("internal error: inode not found");
+      if inode.pending_delete {
+        self.orphans.push(block_num);
+      }
       self.open_list.swap_remove(idx);
     };
   }
@@ -156,6 +454,40 @@ impl Inode {
     self.block
   }
 
+  pub fn generation(&self) -> Size {
+    self.data.generation
+  }
+
+  /* Every block the inode occupies: itself, its data, and any pointer blocks */
+  pub fn allocated_blocks(&self, disk: &mut BlockDevice) -> Size {
+    self.data.blocks(self.block, disk).len() as Size
+  }
+
+  /* Data blocks holding `len` bytes at `offset`, stopping at the end of the file */
+  pub fn data_blocks(&self, offset: Ofs, len: Size, disk: &mut BlockDevice) -> Vec<Size> {
+    if offset < 0 {
+      return Vec::new();
+    }
+
+    self
+      .data
+      .block_range(len, offset, &mut self.ptr_cache.borrow_mut(), disk)
+  }
+
+  /* None if the inode was never formatted or is corrupt */
+  pub fn file_type(&self) -> Option<FileType> {
+    FileType::try_from(self.data.kind).ok()
+  }
+
+  /* Only built for directories, and kept for as long as the inode stays open */
+  pub fn dir_index(&self) -> Option<&DirIndex> {
+    self.dir_index.as_ref()
+  }
+
+  pub fn dir_index_mut(&mut self) -> &mut Option<DirIndex> {
+    &mut self.dir_index
+  }
+
   fn incr_open(&mut self) {
     self.open_count += 1
   }
@@ -168,30 +500,43 @@ impl Inode {
     self.open_count == 0
   }
 
-  /* Read */
+  /* Read. A negative offset reads nothing */
   pub fn read_at(&self, buffer: &mut [u8], offset: Ofs, disk: &mut BlockDevice) -> Ofs {
+    if offset < 0 {
+      return 0;
+    }
+
     let mut size = buffer.len() as Ofs;
     let mut ofs = offset;
     let mut bytes_written: Ofs = 0;
 
     #[cfg(feature = "debug")]
-    println!(
-      "Inode {} reading {} bytes at {}",
-      self.inumber(),
-      buffer.len(),
-      offset
-    );
+    if super::debug_io() {
+      println!(
+        "Inode {} reading {} bytes at {}",
+        self.inumber(),
+        buffer.len(),
+        offset
+      );
+    }
 
     let mut blocks = self
       .data
-      .block_range(buffer.len().try_into().unwrap(), offset, disk)
+      .block_range(
+        buffer.len().try_into().unwrap(),
+        offset,
+        &mut self.ptr_cache.borrow_mut(),
+        disk,
+      )
       .into_iter();
 
     let mut buf: *mut u8 = buffer.as_mut_ptr();
 
+    /* Bounce buffer, reused for every block of the read */
+    let mut bounce = block::EMPTY_BLOCK;
+
     while size > 0 {
       let block_ofs = ofs % block::BLOCK_SIZE as Ofs;
-      let block_idx = blocks.next().expect("block not found");
 
       let inode_left = self.length() as Ofs - ofs;
       let block_left = block::BLOCK_SIZE as Ofs - block_ofs;
@@ -203,8 +548,8 @@ impl Inode {
         return bytes_written;
       }
 
-      /* Bounce buffer */
-      let mut bounce = block::EMPTY_BLOCK;
+      let block_idx = blocks.next().expect("block not found");
+
       disk.read(&mut bounce, block_idx);
       unsafe {
         bounce
@@ -223,30 +568,43 @@ impl Inode {
     bytes_written
   }
 
-  /* Write */
+  /* Write. A negative offset writes nothing */
   pub fn write_at(&self, buffer: &[u8], offset: Ofs, disk: &mut BlockDevice) -> Ofs {
+    if offset < 0 {
+      return 0;
+    }
+
     let mut size = buffer.len() as Ofs;
     let mut ofs = offset;
     let mut bytes_written: Ofs = 0;
 
     #[cfg(feature = "debug")]
-    println!(
-      "Inode {} writing {} bytes at {}",
-      self.inumber(),
-      buffer.len(),
-      offset
-    );
+    if super::debug_io() {
+      println!(
+        "Inode {} writing {} bytes at {}",
+        self.inumber(),
+        buffer.len(),
+        offset
+      );
+    }
 
     let mut blocks = self
       .data
-      .block_range(buffer.len().try_into().unwrap(), offset, disk)
+      .block_range(
+        buffer.len().try_into().unwrap(),
+        offset,
+        &mut self.ptr_cache.borrow_mut(),
+        disk,
+      )
       .into_iter();
 
     let mut buf: *const u8 = buffer.as_ptr();
 
+    /* Bounce buffer, reused for every block of the write */
+    let mut bounce = block::EMPTY_BLOCK;
+
     while size > 0 {
       let block_ofs = ofs % block::BLOCK_SIZE as Ofs;
-      let block_idx = blocks.next().expect("block not found");
 
       let inode_left = self.length() as Ofs - ofs;
       let block_left = block::BLOCK_SIZE as Ofs - block_ofs;
@@ -258,9 +616,8 @@ impl Inode {
         return bytes_written;
       }
 
-      /* Bounce buffer */
+      let block_idx = blocks.next().expect("block not found");
 
-      let mut bounce = block::EMPTY_BLOCK;
       disk.read(&mut bounce, block_idx);
       unsafe {
         buf.copy_to(bounce.as_mut_ptr().add(block_ofs as usize), chunk_size as _);
@@ -278,28 +635,65 @@ impl Inode {
     bytes_written
   }
 
-  /* Set the length of a file, allocating new blocks if needed */
-  pub fn set_len(&mut self, len: Size, free_map: &mut FreeMap, disk: &mut BlockDevice) {
+  /*
+    Zero the bytes from `from` up to `to`, which must be within the file. Used after growing it, as
+    the tail of the old last block and any reused block may still hold earlier bytes
+  */
+  pub fn zero_range(&self, from: Size, to: Size, disk: &mut BlockDevice) {
+    let mut ofs = from;
+    while ofs < to {
+      let chunk = std::cmp::min(to - ofs, block::BLOCK_SIZE - ofs % block::BLOCK_SIZE);
+      self.write_at(&block::EMPTY_BLOCK[..chunk as usize], ofs as Ofs, disk);
+      ofs += chunk;
+    }
+  }
+
+  /*
+    Set the length of a file, allocating new blocks if needed or releasing those past the new end.
+    False if new blocks could not be allocated, or `len` is beyond what the inode can address
+  */
+  pub fn set_len(&mut self, len: Size, free_map: &mut FreeMap, disk: &mut BlockDevice) -> bool {
+    if len > MAX_FILE_SIZE {
+      return false;
+    }
+
     let cur_block_count = bytes_to_blocks(self.length());
     let req_block_count = bytes_to_blocks(len);
 
-    if cur_block_count <= req_block_count {
-      self.data.len = len;
-      return;
+    if req_block_count != cur_block_count {
+      self.ptr_cache.get_mut().blocks.clear();
     }
 
-    let mut allocations: Vec<Size> = Vec::new();
-    free_map.allocate(req_block_count - cur_block_count, &mut allocations);
-    let mut blocks = allocations.into_iter();
+    if req_block_count > cur_block_count {
+      let new_blocks = req_block_count - cur_block_count;
+      let new_ptr_blocks = pointer_blocks(req_block_count) - pointer_blocks(cur_block_count);
+
+      let mut allocations: Vec<Size> = Vec::new();
+      if !free_map.allocate(new_blocks + new_ptr_blocks, &mut allocations) {
+        return false;
+      }
+      free_map.scrub(&allocations, disk);
+      let mut blocks = allocations.into_iter();
+
+      let mut skip = cur_block_count;
+      fill_direct(&mut skip, &mut self.data.direct, &mut blocks);
+      fill_indirect(&mut skip, &mut self.data.indirect, &mut blocks, disk);
+      fill_doubly_indirect(&mut skip, &mut self.data.doubly_indirect, &mut blocks, disk);
+    } else if req_block_count < cur_block_count {
+      let mut skip = req_block_count;
+      clear_direct(&mut skip, &mut self.data.direct, free_map);
+      clear_indirect(&mut skip, &mut self.data.indirect, free_map, disk);
+      clear_doubly_indirect(&mut skip, &mut self.data.doubly_indirect, free_map, disk);
+    }
 
-    let mut skip = cur_block_count;
-    fill_direct(&mut skip, &mut self.data.direct, &mut blocks);
-    fill_indirect(&mut skip, &mut self.data.direct, &mut blocks, disk);
-    fill_doubly_indirect(&mut skip, &mut self.data.direct, &mut blocks, disk);
     self.data.len = len;
+    self.flush(disk);
+    true
+  }
 
-    let buffer: block::Block = unsafe { std::mem::transmute(self.data.clone()) };
-    disk.write(&buffer, self.inumber());
+  /* Write the in-memory inode back to its block */
+  pub fn flush(&self, disk: &mut BlockDevice) {
+    disk.write(&self.data.clone().into(), self.inumber());
   }
 }
 
@@ -313,25 +707,105 @@ impl Default for InodeDisk {
       doubly_indirect: [0; N_DOUBLY_INDIRECT],
       magic: INODE_MAGIC,
       len: 0,
+      kind: 0,
+      generation: 0,
+      checksum: 0,
       unused: [0; BLOCK_USIZE
-        - std::mem::size_of::<Size>() * (2 + N_DIRECT + N_INDIRECT + N_DOUBLY_INDIRECT)],
+        - std::mem::size_of::<Size>() * (5 + N_DIRECT + N_INDIRECT + N_DOUBLY_INDIRECT)],
+    }
+  }
+}
+
+impl From<FileType> for Size {
+  fn from(kind: FileType) -> Self {
+    match kind {
+      FileType::Regular => REGULAR_KIND,
+      FileType::Directory => DIRECTORY_KIND,
+    }
+  }
+}
+
+impl TryFrom<Size> for FileType {
+  type Error = Size;
+
+  fn try_from(kind: Size) -> Result<Self, Self::Error> {
+    match kind {
+      REGULAR_KIND => Ok(FileType::Regular),
+      DIRECTORY_KIND => Ok(FileType::Directory),
+      _ => Err(kind),
     }
   }
 }
 
+/* Fields are laid out in declaration order, each Size little-endian */
 impl From<block::Block> for InodeDisk {
   fn from(block: block::Block) -> Self {
-    unsafe { std::mem::transmute(block) }
+    let mut fields = (0..).map(|i| block::read_size_le(&block, i * SIZE_BYTES));
+    let mut data = InodeDisk::default();
+
+    data.direct.iter_mut().for_each(|ptr| *ptr = fields.next().unwrap());
+    data.indirect.iter_mut().for_each(|ptr| *ptr = fields.next().unwrap());
+    data.doubly_indirect.iter_mut().for_each(|ptr| *ptr = fields.next().unwrap());
+    data.magic = fields.next().unwrap();
+    data.len = fields.next().unwrap();
+    data.kind = fields.next().unwrap();
+    data.generation = fields.next().unwrap();
+    data.checksum = fields.next().unwrap();
+
+    let unused_start = block::BLOCK_USIZE - data.unused.len();
+    data.unused.copy_from_slice(&block[unused_start..]);
+    data
   }
 }
 
 impl From<InodeDisk> for block::Block {
   fn from(data: InodeDisk) -> Self {
-    unsafe { std::mem::transmute(data) }
+    let mut block = block::EMPTY_BLOCK;
+
+    /* An inode from before checksums gains one here, so it takes the magic that says so */
+    let magic = match data.has_magic() {
+      true => INODE_MAGIC,
+      false => data.magic,
+    };
+
+    let fields = data
+      .direct
+      .iter()
+      .chain(&data.indirect)
+      .chain(&data.doubly_indirect)
+      .chain([&magic, &data.len, &data.kind, &data.generation, &0]);
+    for (i, &field) in fields.enumerate() {
+      block::write_size_le(&mut block, i * SIZE_BYTES, field);
+    }
+
+    let unused_start = block::BLOCK_USIZE - data.unused.len();
+    block[unused_start..].copy_from_slice(&data.unused);
+
+    /* Computed while the checksum field is still zero */
+    let checksum = super::fnv1a(super::FNV_OFFSET, &block);
+    block::write_size_le(&mut block, CHECKSUM_OFFSET, checksum);
+    block
+  }
+}
+
+fn decode_ptrs(raw: &block::Block) -> PtrBlock {
+  std::array::from_fn(|i| block::read_size_le(raw, i * SIZE_BYTES))
+}
+
+fn encode_ptrs(ptrs: &PtrBlock) -> block::Block {
+  let mut raw = block::EMPTY_BLOCK;
+  for (i, &ptr) in ptrs.iter().enumerate() {
+    block::write_size_le(&mut raw, i * SIZE_BYTES, ptr);
   }
+  raw
 }
 
 impl InodeDisk {
+  /* Whether the magic is one an inode is written with, with a checksum or from before them */
+  fn has_magic(&self) -> bool {
+    matches!(self.magic, INODE_MAGIC | UNCHECKSUMMED_MAGIC)
+  }
+
   fn direct_range(
     mut skip: usize,
     mut count: Size,
@@ -359,14 +833,19 @@ impl InodeDisk {
     mut count: Size,
     indirect: &[Size],
     blocks: &mut Vec<Size>,
+    cache: &mut PtrCache,
     disk: &mut BlockDevice,
   ) -> (usize, Size) {
     let mut indirect_count = 0;
     while count > 0 && indirect_count < indirect.len() {
-      let mut indirect_block_raw = block::EMPTY_BLOCK;
-      disk.read(&mut indirect_block_raw, indirect[indirect_count]);
+      /* A pointer block wholly before the range is not read */
+      if skip >= PTRS_PER_BLOCK {
+        skip -= PTRS_PER_BLOCK;
+        indirect_count += 1;
+        continue;
+      }
 
-      let indirect_block: PtrBlock = unsafe { std::mem::transmute(indirect_block_raw) };
+      let indirect_block = cache.get(indirect[indirect_count], disk);
 
       (skip, count) = InodeDisk::direct_range(skip, count, &indirect_block, blocks);
 
@@ -381,20 +860,21 @@ impl InodeDisk {
     mut count: Size,
     doubly_indirect: &[Size],
     blocks: &mut Vec<Size>,
+    cache: &mut PtrCache,
     disk: &mut BlockDevice,
   ) -> (usize, Size) {
     let mut doubly_indirect_count = 0;
     while count > 0 && doubly_indirect_count < doubly_indirect.len() {
-      let mut doubly_indirect_block_raw = block::EMPTY_BLOCK;
-      disk.read(
-        &mut doubly_indirect_block_raw,
-        doubly_indirect[doubly_indirect_count],
-      );
+      if skip >= PTRS_PER_BLOCK * PTRS_PER_BLOCK {
+        skip -= PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+        doubly_indirect_count += 1;
+        continue;
+      }
 
-      let doubly_indirect_block: PtrBlock =
-        unsafe { std::mem::transmute(doubly_indirect_block_raw) };
+      let doubly_indirect_block = cache.get(doubly_indirect[doubly_indirect_count], disk);
 
-      (skip, count) = InodeDisk::indirect_range(skip, count, &doubly_indirect_block, blocks, disk);
+      (skip, count) =
+        InodeDisk::indirect_range(skip, count, &doubly_indirect_block, blocks, cache, disk);
 
       doubly_indirect_count += 1;
     }
@@ -402,15 +882,45 @@ impl InodeDisk {
     (skip, count)
   }
 
-  fn block_range(&self, buf_len: Size, offset: Ofs, disk: &mut BlockDevice) -> Vec<Size> {
+  /* Every block owned by the inode stored at `inumber`, including that block */
+  fn blocks(&self, inumber: Size, disk: &mut BlockDevice) -> Vec<Size> {
+    let mut blocks = vec![inumber];
+    collect_direct(&self.direct, &mut blocks);
+    collect_indirect(&self.indirect, &mut blocks, disk);
+    collect_doubly_indirect(&self.doubly_indirect, &mut blocks, disk);
+    blocks
+  }
+
+  fn block_range(
+    &self,
+    buf_len: Size,
+    offset: Ofs,
+    cache: &mut PtrCache,
+    disk: &mut BlockDevice,
+  ) -> Vec<Size> {
+    /*
+      Every block the byte range touches, including a partial first block. The range stops at the
+      end of the file, past which pointers are unallocated and would be followed into garbage.
+      Pointer blocks are all resolved before any data block is touched, and those in `cache` are
+      not read again
+    */
+    let end = std::cmp::min(offset as Size + buf_len, self.len);
     let mut skip = offset as usize / block::BLOCK_USIZE;
-    let mut count = buf_len.div_ceil(block::BLOCK_SIZE);
+    let mut count = end.div_ceil(block::BLOCK_SIZE).saturating_sub(skip as Size);
 
     let mut blocks: Vec<Size> = Vec::new();
 
     (skip, count) = InodeDisk::direct_range(skip, count, &self.direct, &mut blocks);
-    (skip, count) = InodeDisk::indirect_range(skip, count, &self.indirect, &mut blocks, disk);
-    _ = InodeDisk::doubly_indirect_range(skip, count, &self.doubly_indirect, &mut blocks, disk);
+    (skip, count) =
+      InodeDisk::indirect_range(skip, count, &self.indirect, &mut blocks, cache, disk);
+    _ = InodeDisk::doubly_indirect_range(
+      skip,
+      count,
+      &self.doubly_indirect,
+      &mut blocks,
+      cache,
+      disk,
+    );
 
     blocks
   }
@@ -435,16 +945,18 @@ fn fill_indirect(
   disk: &mut BlockDevice,
 ) {
   for ptr in dst {
-    if let Some(block) = blocks.next() {
-      *ptr = block;
-      let mut direct_block: PtrBlock = [0; PTRS_PER_BLOCK];
-      fill_direct(skip, &mut direct_block, blocks);
+    /* Pointer blocks wholly before the fill point are left untouched */
+    if *skip >= PTRS_PER_BLOCK {
+      *skip -= PTRS_PER_BLOCK;
+      continue;
+    }
 
-      let raw: block::Block = unsafe { std::mem::transmute_copy(&direct_block) };
-      disk.write(&raw, block);
-    } else {
+    let Some(mut direct_block) = ptr_block(ptr, blocks, disk) else {
       return;
-    }
+    };
+    fill_direct(skip, &mut direct_block, blocks);
+
+    disk.write(&encode_ptrs(&direct_block), *ptr);
   }
 }
 
@@ -455,20 +967,404 @@ fn fill_doubly_indirect(
   disk: &mut BlockDevice,
 ) {
   for ptr in dst {
-    if let Some(block) = blocks.next() {
-      *ptr = block;
-      let mut indirect_block: PtrBlock = [0; PTRS_PER_BLOCK];
-      fill_indirect(skip, &mut indirect_block, blocks, disk);
+    if *skip >= PTRS_PER_BLOCK * PTRS_PER_BLOCK {
+      *skip -= PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+      continue;
+    }
 
-      let raw: block::Block = unsafe { std::mem::transmute_copy(&indirect_block) };
-      disk.write(&raw, block);
-    } else {
+    let Some(mut indirect_block) = ptr_block(ptr, blocks, disk) else {
       return;
+    };
+    fill_indirect(skip, &mut indirect_block, blocks, disk);
+
+    disk.write(&encode_ptrs(&indirect_block), *ptr);
+  }
+}
+
+/* Release every block from data block `skip` onwards, unsetting the pointers to them */
+fn clear_direct(skip: &mut usize, dst: &mut [Size], free_map: &mut FreeMap) {
+  for elem in dst {
+    if *skip > 0 {
+      *skip -= 1;
+    } else if *elem != 0 {
+      free_map.release(*elem);
+      *elem = 0;
+    }
+  }
+}
+
+fn clear_indirect(
+  skip: &mut usize,
+  dst: &mut [Size],
+  free_map: &mut FreeMap,
+  disk: &mut BlockDevice,
+) {
+  for ptr in dst.iter_mut().filter(|ptr| **ptr != 0) {
+    if *skip >= PTRS_PER_BLOCK {
+      *skip -= PTRS_PER_BLOCK;
+      continue;
+    }
+
+    /* A pointer block left with no data blocks is released too */
+    let emptied = *skip == 0;
+
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, *ptr);
+    let mut direct_block = decode_ptrs(&raw);
+    clear_direct(skip, &mut direct_block, free_map);
+
+    if emptied {
+      free_map.release(*ptr);
+      *ptr = 0;
+    } else {
+      disk.write(&encode_ptrs(&direct_block), *ptr);
     }
   }
 }
 
+fn clear_doubly_indirect(
+  skip: &mut usize,
+  dst: &mut [Size],
+  free_map: &mut FreeMap,
+  disk: &mut BlockDevice,
+) {
+  for ptr in dst.iter_mut().filter(|ptr| **ptr != 0) {
+    if *skip >= PTRS_PER_BLOCK * PTRS_PER_BLOCK {
+      *skip -= PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+      continue;
+    }
+
+    let emptied = *skip == 0;
+
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, *ptr);
+    let mut indirect_block = decode_ptrs(&raw);
+    clear_indirect(skip, &mut indirect_block, free_map, disk);
+
+    if emptied {
+      free_map.release(*ptr);
+      *ptr = 0;
+    } else {
+      disk.write(&encode_ptrs(&indirect_block), *ptr);
+    }
+  }
+}
+
+impl PtrCache {
+  fn get(&mut self, ptr: Size, disk: &mut BlockDevice) -> PtrBlock {
+    *self.blocks.entry(ptr).or_insert_with(|| {
+      let mut raw = block::EMPTY_BLOCK;
+      disk.read(&mut raw, ptr);
+      decode_ptrs(&raw)
+    })
+  }
+}
+
+/* Reads the pointer block at `ptr`, or assigns it a fresh one if unset */
+fn ptr_block(
+  ptr: &mut Size,
+  blocks: &mut impl Iterator<Item = Size>,
+  disk: &mut BlockDevice,
+) -> Option<PtrBlock> {
+  if *ptr != 0 {
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, *ptr);
+    return Some(decode_ptrs(&raw));
+  }
+
+  *ptr = blocks.next()?;
+  Some([0; PTRS_PER_BLOCK])
+}
+
+/* Pointers are never 0 once assigned, as block 0 is the root inode */
+fn collect_direct(ptrs: &[Size], dst: &mut Vec<Size>) {
+  dst.extend(ptrs.iter().filter(|&&ptr| ptr != 0));
+}
+
+fn collect_indirect(ptrs: &[Size], dst: &mut Vec<Size>, disk: &mut BlockDevice) {
+  for &ptr in ptrs.iter().filter(|&&ptr| ptr != 0) {
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, ptr);
+
+    let direct_block = decode_ptrs(&raw);
+    dst.push(ptr);
+    collect_direct(&direct_block, dst);
+  }
+}
+
+fn collect_doubly_indirect(ptrs: &[Size], dst: &mut Vec<Size>, disk: &mut BlockDevice) {
+  for &ptr in ptrs.iter().filter(|&&ptr| ptr != 0) {
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, ptr);
+
+    let indirect_block = decode_ptrs(&raw);
+    dst.push(ptr);
+    collect_indirect(&indirect_block, dst, disk);
+  }
+}
+
+/* Whether `block_num` lies on the device and holds an intact inode, by its magic and checksum */
+pub fn holds_inode(block_num: Size, disk: &mut BlockDevice) -> bool {
+  if block_num >= disk.max_size() {
+    return false;
+  }
+
+  let mut block = block::EMPTY_BLOCK;
+  disk.read(&mut block, block_num);
+  match InodeDisk::from(block).magic {
+    INODE_MAGIC => checksum_matches(block),
+    /* Written before inodes carried a checksum, so taken on trust */
+    UNCHECKSUMMED_MAGIC => true,
+    _ => false,
+  }
+}
+
+fn checksum_matches(mut block: block::Block) -> bool {
+  let stored = block::read_size_le(&block, CHECKSUM_OFFSET);
+  block::write_size_le(&mut block, CHECKSUM_OFFSET, 0);
+  stored == super::fnv1a(super::FNV_OFFSET, &block)
+}
+
+/* Number of indirect and doubly indirect blocks needed to address `data_blocks` */
+fn pointer_blocks(data_blocks: usize) -> usize {
+  let mut left = data_blocks.saturating_sub(N_DIRECT);
+  let mut count = 0;
+
+  for _ in 0..N_INDIRECT {
+    if left == 0 {
+      return count;
+    }
+    count += 1;
+    left = left.saturating_sub(PTRS_PER_BLOCK);
+  }
+
+  for _ in 0..N_DOUBLY_INDIRECT {
+    if left == 0 {
+      return count;
+    }
+    let covered = std::cmp::min(left, PTRS_PER_BLOCK * PTRS_PER_BLOCK);
+    count += 1 + covered.div_ceil(PTRS_PER_BLOCK);
+    left -= covered;
+  }
+
+  count
+}
+
 /* Number of blocks needed to store `bytes` */
 fn bytes_to_blocks(bytes: Size) -> usize {
   (bytes as usize).div_ceil(block::BLOCK_USIZE)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filesys::{
+    block::DeviceType,
+    tests::{Shared, TempImage},
+    BufferCacheStrategy, Filesys,
+  };
+
+  #[test]
+  fn flush_all_writes_every_open_inode() {
+    let image = TempImage::new("flush_all");
+    let mut filesys = image.build(64);
+    let names = ["a", "b", "c"];
+    names.iter().for_each(|name| assert!(filesys.create_file(name, 0).is_ok()));
+
+    let inumbers: Vec<Size> = names
+      .iter()
+      .map(|name| filesys.stat(name).unwrap().inumber)
+      .collect();
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+
+    /* Changed in memory only, and left open */
+    for (i, &inumber) in inumbers.iter().enumerate() {
+      filesys.inodes.open_inode(inumber, disk).borrow_mut().data.len = 100 * (i as Size + 1);
+    }
+    filesys.inodes.flush_all(disk);
+
+    for (i, &inumber) in inumbers.iter().enumerate() {
+      let mut raw = block::EMPTY_BLOCK;
+      disk.read(&mut raw, inumber);
+      assert_eq!(InodeDisk::from(raw).len, 100 * (i as Size + 1));
+    }
+  }
+
+  #[test]
+  fn reusing_an_inode_block_bumps_its_generation() {
+    let image = TempImage::new("generation");
+    let mut filesys = image.build(64);
+    let generation_of = |filesys: &mut Filesys, name: &str| {
+      let inumber = filesys.stat(name).unwrap().inumber;
+      let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+      (inumber, filesys.inodes.disk_inode(inumber, disk).generation)
+    };
+
+    assert!(filesys.create_file("a", 0).is_ok());
+    let (first, generation) = generation_of(&mut filesys, "a");
+    assert!(filesys.remove_file("a"));
+    assert!(filesys.create_file("b", 0).is_ok());
+    assert_eq!(generation_of(&mut filesys, "b"), (first, generation + 1));
+  }
+
+  #[test]
+  fn a_corrupted_inode_fails_its_checksum() {
+    let image = TempImage::new("inode_checksum");
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file("a", 2 * block::BLOCK_SIZE).is_ok());
+    assert!(filesys.create_file("b", block::BLOCK_SIZE).is_ok());
+    let (a, b) = (filesys.stat("a").unwrap().inumber, filesys.stat("b").unwrap().inumber);
+    assert!(filesys.sync());
+    drop(filesys);
+
+    /* Reloaded, so neither path is cached */
+    let mut filesys = Filesys::init();
+    filesys.load_disk(&image.path, BufferCacheStrategy::None);
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    let mut raw = block::EMPTY_BLOCK;
+
+    /* A flipped bit in the first direct pointer */
+    disk.read(&mut raw, a);
+    raw[0] ^= 1;
+    disk.write(&raw, a);
+    assert!(!holds_inode(a, disk));
+
+    /* A zeroed checksum is no longer mistaken for an image from before the field */
+    disk.read(&mut raw, b);
+    block::write_size_le(&mut raw, CHECKSUM_OFFSET, 0);
+    disk.write(&raw, b);
+    assert!(!holds_inode(b, disk));
+
+    assert!(filesys.stat("a").is_none());
+    assert!(filesys.stat("b").is_none());
+  }
+
+  #[test]
+  fn a_corrupted_checksum_fails_the_open() {
+    let image = TempImage::new("inode_checksum_open");
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file("a", block::BLOCK_SIZE).is_ok());
+    let a = filesys.stat("a").unwrap().inumber;
+    assert!(filesys.sync());
+    drop(filesys);
+
+    let mut reloaded = Filesys::init();
+    reloaded.load_disk(&image.path, BufferCacheStrategy::None);
+    let shared = Shared::new(reloaded);
+
+    /* Opened once, so the path is cached and the directory is not consulted again */
+    let file = shared.fs().open_file("a").unwrap();
+    shared.fs().close_file(file);
+
+    let filesys = shared.fs();
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, a);
+    let stored = block::read_size_le(&raw, CHECKSUM_OFFSET);
+    block::write_size_le(&mut raw, CHECKSUM_OFFSET, stored ^ 1);
+    disk.write(&raw, a);
+
+    assert!(shared.fs().open_file("a").is_none());
+    assert!(shared.fs().stat("a").is_none());
+  }
+
+  #[test]
+  fn an_inode_from_before_checksums_is_trusted_until_rewritten() {
+    let image = TempImage::new("inode_checksum_legacy");
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file("a", block::BLOCK_SIZE).is_ok());
+    let a = filesys.stat("a").unwrap().inumber;
+    assert!(filesys.sync());
+    drop(filesys);
+
+    let mut filesys = Filesys::init();
+    filesys.load_disk(&image.path, BufferCacheStrategy::None);
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+
+    /* As an older image left it, with the old magic and no checksum */
+    let mut raw = block::EMPTY_BLOCK;
+    disk.read(&mut raw, a);
+    block::write_size_le(&mut raw, std::mem::offset_of!(InodeDisk, magic), UNCHECKSUMMED_MAGIC);
+    block::write_size_le(&mut raw, CHECKSUM_OFFSET, 0);
+    disk.write(&raw, a);
+    assert!(holds_inode(a, disk));
+    assert_eq!(filesys.stat("a").unwrap().len, block::BLOCK_SIZE);
+
+    /* Growing it writes the inode back, now under the checksummed magic */
+    assert!(filesys.write_path("a", block::BLOCK_SIZE as Ofs, &[7]));
+    let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+    disk.read(&mut raw, a);
+    assert_eq!(InodeDisk::from(raw).magic, INODE_MAGIC);
+    assert!(checksum_matches(raw));
+  }
+
+  /* Write across the first block addressed through `boundary`, then check every byte */
+  fn write_across(name: &str, boundary: usize) {
+    let image = TempImage::new(name);
+    let mut filesys = image.build(512);
+    let len = (boundary + 2) * block::BLOCK_USIZE;
+    assert!(filesys.create_file("a", len as Size).is_ok());
+
+    let mut model = vec![0; len];
+    let start = boundary * block::BLOCK_USIZE - 100;
+    let data: Vec<u8> = (0..block::BLOCK_USIZE + 300).map(|i| (i % 251) as u8 + 1).collect();
+    assert!(filesys.write_path("a", start as Ofs, &data));
+    model[start..start + data.len()].copy_from_slice(&data);
+
+    assert_eq!(filesys.read_range("a", 0, len as Size).unwrap(), model);
+
+    /* Growing into the new region as the write goes */
+    assert!(filesys.create_file("b", start as Size).is_ok());
+    assert!(filesys.write_path("b", start as Ofs, &data));
+    let grown = start + data.len();
+    assert_eq!(filesys.read_range("b", 0, grown as Size).unwrap(), model[..grown]);
+    assert!(filesys.sync());
+    drop(filesys);
+
+    let mut filesys = Filesys::init();
+    filesys.load_disk(&image.path, BufferCacheStrategy::None);
+    assert_eq!(filesys.read_range("a", 0, len as Size).unwrap(), model);
+    assert_eq!(filesys.read_range("b", 0, grown as Size).unwrap(), model[..grown]);
+    assert!(filesys.verify_free_map().is_empty());
+  }
+
+  #[test]
+  fn a_write_from_the_direct_into_the_indirect_blocks_round_trips() {
+    write_across("direct_to_indirect", N_DIRECT);
+  }
+
+  #[test]
+  fn a_write_from_the_indirect_into_the_doubly_indirect_blocks_round_trips() {
+    write_across("indirect_to_doubly", N_DIRECT + N_INDIRECT * PTRS_PER_BLOCK);
+  }
+
+  #[test]
+  fn files_within_the_direct_pointers_need_no_pointer_block() {
+    let image = TempImage::new("direct_only");
+    let mut filesys = image.build(128);
+    let direct = N_DIRECT as Size;
+
+    assert!(filesys.create_file("direct", direct * block::BLOCK_SIZE).is_ok());
+    assert_eq!(filesys.stat("direct").unwrap().blocks, direct + 1);
+
+    assert!(filesys.create_file("indirect", (direct + 1) * block::BLOCK_SIZE).is_ok());
+    assert_eq!(filesys.stat("indirect").unwrap().blocks, direct + 3);
+
+    /* The preset for small files keeps every file up to 16 blocks direct */
+    if cfg!(feature = "small-files") {
+      assert_eq!(direct, 16);
+    }
+  }
+
+  #[test]
+  fn the_largest_file_is_what_the_pointers_address() {
+    /* N_DIRECT blocks, 128 through the indirect block and 128 * 128 through the doubly indirect */
+    let expected = if cfg!(feature = "small-files") {
+      16_924_672
+    } else {
+      16_912_384
+    };
+    assert_eq!(Filesys::max_file_size(), expected);
+    assert_eq!(MAX_FILE_SIZE, expected);
+  }
+}
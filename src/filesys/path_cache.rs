@@ -0,0 +1,43 @@
+use std::collections::VecDeque;
+
+use crate::Size;
+
+/* Most recently resolved paths and their inodes, the least recently used dropped first */
+pub struct PathCache {
+  capacity: usize,
+  /* Least recently used first */
+  entries: VecDeque<(String, Size)>,
+}
+
+impl PathCache {
+  pub const fn init(capacity: usize) -> Self {
+    PathCache {
+      capacity,
+      entries: VecDeque::new(),
+    }
+  }
+
+  pub fn get(&mut self, path: &str) -> Option<Size> {
+    let idx = self.entries.iter().position(|(p, _)| p == path)?;
+    let entry = self.entries.remove(idx)?;
+    let inumber = entry.1;
+    self.entries.push_back(entry);
+    Some(inumber)
+  }
+
+  pub fn insert(&mut self, path: &str, inumber: Size) {
+    self.invalidate(path);
+    if self.entries.len() == self.capacity {
+      self.entries.pop_front();
+    }
+    self.entries.push_back((path.to_string(), inumber));
+  }
+
+  pub fn invalidate(&mut self, path: &str) {
+    self.entries.retain(|(p, _)| p != path);
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.clear();
+  }
+}
@@ -0,0 +1,235 @@
+use super::{
+  block::{self, BlockDevice},
+  directory::{self, Dir, DirError},
+  free_map::FreeMap,
+  inode::{FileType, InodeManager, MAX_FILE_SIZE},
+  path_cache::PathCache,
+};
+use crate::{Ofs, Size};
+
+/*
+  The inode manager, free map and disk of a `Filesys`, borrowed together by `with_disk` so a
+  closure can run several path operations without re-borrowing the filesystem for each. Its path
+  cache is borrowed too, for the operations that point a path at a new inode
+*/
+pub struct DiskSession<'s, 'a> {
+  inodes: &'s mut InodeManager,
+  free_map: &'s mut FreeMap,
+  disk: &'s mut BlockDevice<'a>,
+  path_cache: &'s mut PathCache,
+}
+
+impl<'s, 'a> DiskSession<'s, 'a> {
+  pub fn new(
+    inodes: &'s mut InodeManager,
+    free_map: &'s mut FreeMap,
+    disk: &'s mut BlockDevice<'a>,
+    path_cache: &'s mut PathCache,
+  ) -> Self {
+    DiskSession {
+      inodes,
+      free_map,
+      disk,
+      path_cache,
+    }
+  }
+
+  /* Checked before any block is allocated, so only running out of space can be found late */
+  pub fn create_file(&mut self, path: &str, length: Size) -> Result<(), DirError> {
+    directory::check_name(path).map_err(DirError::Name)?;
+    if length > MAX_FILE_SIZE {
+      return Err(DirError::TooLarge);
+    }
+    let exists = Dir::open_path(self.inodes, self.disk, path)
+      .and_then(|dir| dir.open_file(path, self.disk))
+      .is_some();
+    if exists {
+      return Err(DirError::Exists);
+    }
+
+    let Some(inumber) = self
+      .inodes
+      .create_inode(length, FileType::Regular, self.disk, self.free_map)
+    else {
+      return Err(DirError::OutOfSpace);
+    };
+
+    /*
+      Barrier: the inode reaches the host before any entry naming it can. A crash in between
+      leaves an unreferenced inode, which is reclaimable, rather than an entry to garbage
+    */
+    self.disk.flush();
+
+    let added = match Dir::open_path(self.inodes, self.disk, path) {
+      Some(mut dir) => dir.add(path, inumber, self.free_map, self.disk),
+      None => Err(DirError::OutOfSpace),
+    };
+
+    /* Nothing refers to the inode unless it was added */
+    if added.is_err() {
+      self.inodes.free_inode(inumber, self.free_map, self.disk);
+    }
+    added
+  }
+
+  /* Write `data` at `offset`, growing the file if needed */
+  pub fn write_at(&mut self, path: &str, offset: Ofs, data: &[u8]) -> bool {
+    let Some(inumber) = self.regular_file(path) else {
+      return false;
+    };
+    if offset < 0 {
+      return false;
+    }
+
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    let old_len = inode.borrow().length();
+    let end = offset as Size + data.len() as Size;
+    let grown = end <= old_len || inode.borrow_mut().set_len(end, self.free_map, self.disk);
+    if grown {
+      /* A write starting past the old end leaves a gap it does not cover */
+      let gap_end = std::cmp::max(offset as Size, old_len);
+      inode.borrow().zero_range(old_len, gap_end, self.disk);
+      inode.borrow().write_at(data, offset, self.disk);
+    }
+
+    self.inodes.close_inumber(inumber);
+    grown
+  }
+
+  /* Copy `len` bytes from `src_offset` to `dst_offset`, which may overlap, growing the file */
+  pub fn copy_range(&mut self, path: &str, src_offset: Ofs, dst_offset: Ofs, len: Size) -> Ofs {
+    let Some(inumber) = self.regular_file(path) else {
+      return 0;
+    };
+    if src_offset < 0 || dst_offset < 0 {
+      return 0;
+    }
+
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    let old_len = inode.borrow().length();
+    let len = len.min(old_len.saturating_sub(src_offset as Size));
+    let grown = match (dst_offset as Size).checked_add(len) {
+      Some(end) => end <= old_len || inode.borrow_mut().set_len(end, self.free_map, self.disk),
+      None => false,
+    };
+    if !grown {
+      self.inodes.close_inumber(inumber);
+      return 0;
+    }
+    /* A destination past the old end leaves a gap the copy does not cover */
+    let gap_end = std::cmp::max(dst_offset as Size, old_len);
+    inode.borrow().zero_range(old_len, gap_end, self.disk);
+
+    /* When the destination is later, copy from the end so overlapping bytes are read first */
+    let mut starts: Vec<Size> = (0..len).step_by(block::BLOCK_USIZE).collect();
+    if dst_offset > src_offset {
+      starts.reverse();
+    }
+
+    let mut bounce = block::EMPTY_BLOCK;
+    for start in starts {
+      let n = (len - start).min(block::BLOCK_SIZE) as usize;
+      let inode = inode.borrow();
+      inode.read_at(&mut bounce[..n], src_offset + start as Ofs, self.disk);
+      inode.write_at(&bounce[..n], dst_offset + start as Ofs, self.disk);
+    }
+
+    self.inodes.close_inumber(inumber);
+    len as Ofs
+  }
+
+  /*
+    Replace the contents of an existing file with `data`. The new contents are written to an inode
+    of their own and reach the host before the entry is switched to it, so a crash part way leaves
+    either the old file or the new one. Handles still open on the old inode keep its contents
+  */
+  pub fn rewrite(&mut self, path: &str, data: &[u8]) -> bool {
+    let Some(old) = self.regular_file(path) else {
+      return false;
+    };
+    let Some(inumber) = self.inodes.create_inode(
+      data.len() as Size,
+      FileType::Regular,
+      self.disk,
+      self.free_map,
+    ) else {
+      return false;
+    };
+
+    /* Every block was allocated up front, so the write cannot run short */
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    inode.borrow().write_at(data, 0, self.disk);
+    self.inodes.close_inumber(inumber);
+
+    /* Barrier: the new inode and its data are on the host before the entry names it */
+    self.disk.flush();
+
+    let repointed = match Dir::open_path(self.inodes, self.disk, path) {
+      Some(mut dir) => dir.repoint(path, inumber, self.disk) == Some(old),
+      None => false,
+    };
+
+    if repointed {
+      self.path_cache.invalidate(path);
+      self.inodes.unlink(old, self.free_map, self.disk);
+    } else {
+      self.inodes.free_inode(inumber, self.free_map, self.disk);
+    }
+    repointed
+  }
+
+  /* Grow or shrink to `len` bytes. Bytes past the old end read as zero */
+  pub fn set_len(&mut self, path: &str, len: Size) -> bool {
+    let Some(inumber) = self.regular_file(path) else {
+      return false;
+    };
+
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    let old_len = inode.borrow().length();
+    let resized = inode.borrow_mut().set_len(len, self.free_map, self.disk);
+
+    if resized {
+      inode.borrow().zero_range(old_len, len, self.disk);
+    }
+
+    self.inodes.close_inumber(inumber);
+    resized
+  }
+
+  /* Read up to `len` bytes at `offset` */
+  pub fn read_range(&mut self, path: &str, offset: Ofs, len: Size) -> Option<Vec<u8>> {
+    let inumber = self.regular_file(path)?;
+
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    let available = (inode.borrow().length() as Ofs - offset).max(0) as Size;
+
+    let mut buffer = vec![0; std::cmp::min(len, available) as usize];
+    let bytes_read = inode.borrow().read_at(&mut buffer, offset, self.disk);
+    buffer.truncate(bytes_read as usize);
+
+    self.inodes.close_inumber(inumber);
+    Some(buffer)
+  }
+
+  /* Load the blocks holding `len` bytes at `offset` into the disk's cache, if it has one */
+  pub fn prefetch(&mut self, path: &str, offset: Ofs, len: Size) -> bool {
+    let Some(inumber) = self.regular_file(path) else {
+      return false;
+    };
+
+    let inode = self.inodes.open_inode(inumber, self.disk);
+    let blocks = inode.borrow().data_blocks(offset, len, self.disk);
+    blocks.into_iter().for_each(|block| self.disk.prefetch(block));
+
+    self.inodes.close_inumber(inumber);
+    true
+  }
+
+  /* Inode of `path`, if it names a regular file */
+  fn regular_file(&mut self, path: &str) -> Option<Size> {
+    let dir = Dir::open_path(self.inodes, self.disk, path)?;
+    let inumber = dir.open_file(path, self.disk)?;
+
+    (self.inodes.file_type(inumber, self.disk) == Some(FileType::Regular)).then_some(inumber)
+  }
+}
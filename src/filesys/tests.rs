@@ -0,0 +1,2891 @@
+use std::{
+  cell::RefCell,
+  panic,
+  rc::Rc,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+  block::{self, CountedDisk, DeviceType},
+  cache::LfuCacheDisk,
+  directory::Dir,
+  faulty_disk::FaultyDisk,
+  vdisk::VDisk,
+  AllocPolicy, BufferCacheStrategy, CacheStats, DirError, Fault, FileType, Filesys, FilesysBuilder,
+  IoCounts, NameError, OpenHandleInfo, RenameError, SyncPolicy, BLOCK_SIZE, NAME_MAX,
+};
+use crate::{Ofs, Size};
+
+static NEXT_IMAGE: AtomicUsize = AtomicUsize::new(0);
+
+/* A host file in the temporary directory, unique to the test that made it and removed on drop */
+pub(crate) struct TempImage {
+  pub(crate) path: String,
+}
+
+impl TempImage {
+  pub(crate) fn new(name: &str) -> Self {
+    let file = format!(
+      "vfs-{}-{}-{}.img",
+      std::process::id(),
+      NEXT_IMAGE.fetch_add(1, Ordering::Relaxed),
+      name
+    );
+    let path = std::env::temp_dir()
+      .join(file)
+      .to_str()
+      .expect("temporary directory is not valid UTF-8")
+      .to_string();
+
+    TempImage { path }
+  }
+
+  pub(crate) fn build(&self, block_count: Size) -> Filesys<'static> {
+    FilesysBuilder::new(&self.path, block_count).build()
+  }
+}
+
+impl Drop for TempImage {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+/*
+  `open_file` borrows the filesystem for as long as the handle lives, so tests that keep handles
+  open reach it through a pointer, as the example does through a static. Declare it after the
+  image it uses, so it is dropped first
+*/
+pub(crate) struct Shared(*mut Filesys<'static>);
+
+impl Shared {
+  pub(crate) fn new(filesys: Filesys<'static>) -> Self {
+    Shared(Box::into_raw(Box::new(filesys)))
+  }
+
+  #[allow(clippy::mut_from_ref)]
+  pub(crate) fn fs(&self) -> &'static mut Filesys<'static> {
+    unsafe { &mut *self.0 }
+  }
+}
+
+impl Drop for Shared {
+  fn drop(&mut self) {
+    drop(unsafe { Box::from_raw(self.0) });
+  }
+}
+
+/*
+  Offsets
+*/
+
+#[test]
+fn negative_offsets_read_and_write_nothing() {
+  let image = TempImage::new("negative_offsets");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"hello").is_ok());
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  let mut buffer = [0xAA; 5];
+
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, -1), 0);
+  assert_eq!(buffer, [0xAA; 5]);
+  assert_eq!(shared.fs().file_write(&mut file, b"xy", -3), 0);
+  assert_eq!(file.tell(), 0);
+
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 5);
+  assert_eq!(&buffer, b"hello");
+  shared.fs().close_file(file);
+}
+
+#[test]
+fn negative_offsets_are_relative_to_the_seek_head() {
+  let image = TempImage::new("negative_relative");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"hello").is_ok());
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  file.seek(3);
+
+  let mut buffer = [0; 2];
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, -2), 2);
+  assert_eq!(&buffer, b"el");
+  assert_eq!(shared.fs().read_range("a", -1, 5), Some(Vec::new()));
+  shared.fs().close_file(file);
+}
+
+/*
+  Opening files
+*/
+
+/* Add an empty directory inode to the root under `name`, as there is no mkdir yet */
+fn add_directory(filesys: &mut Filesys, name: &str) -> Size {
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  let free_map = filesys.free_map.as_mut().unwrap();
+
+  let inumber = filesys
+    .inodes
+    .create_inode(0, FileType::Directory, disk, free_map)
+    .unwrap();
+  Dir::open_root(&mut filesys.inodes, disk)
+    .add(name, inumber, free_map, disk)
+    .unwrap();
+  inumber
+}
+
+#[test]
+fn cat_returns_text_and_refuses_everything_else() {
+  let image = TempImage::new("cat");
+  let mut filesys = image.build(64);
+  let text = "line one\nzwei, drei — 四\n";
+  assert!(filesys
+    .create_file_with_data("text", text.as_bytes())
+    .is_ok());
+  assert!(filesys
+    .create_file_with_data("binary", &[b'o', b'k', 0xff, 0xfe])
+    .is_ok());
+  assert!(filesys.create_file("empty", 0).is_ok());
+  add_directory(&mut filesys, "dir");
+
+  assert_eq!(filesys.cat("text").as_deref(), Some(text));
+  assert_eq!(filesys.cat("empty").as_deref(), Some(""));
+  assert_eq!(filesys.cat("binary"), None);
+  assert_eq!(
+    filesys.cat_lossy("binary").as_deref(),
+    Some("ok\u{fffd}\u{fffd}")
+  );
+  assert_eq!(filesys.cat("missing"), None);
+  assert_eq!(filesys.cat("dir"), None);
+  assert_eq!(filesys.cat_lossy("dir"), None);
+}
+
+#[test]
+fn is_file_and_is_dir_check_the_kind() {
+  let image = TempImage::new("is_file_is_dir");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("file", b"data").is_ok());
+  add_directory(&mut filesys, "dir");
+
+  assert!(filesys.is_file("file"));
+  assert!(!filesys.is_dir("file"));
+  assert!(filesys.is_dir("dir"));
+  assert!(!filesys.is_file("dir"));
+  assert!(!filesys.is_file("missing"));
+  assert!(!filesys.is_dir("missing"));
+}
+
+#[test]
+fn open_file_refuses_directories() {
+  let image = TempImage::new("open_directory");
+  let shared = Shared::new(image.build(64));
+  add_directory(shared.fs(), "sub");
+  assert!(shared.fs().create_file("file", 0).is_ok());
+
+  assert!(shared.fs().open_file("sub").is_none());
+  assert!(shared.fs().open_file_mode("sub", false).is_none());
+
+  let file = shared.fs().open_file("file").unwrap();
+  shared.fs().close_file(file);
+}
+
+#[test]
+fn builder_formats_and_initialises_the_free_map() {
+  let image = TempImage::new("builder");
+  let mut filesys = FilesysBuilder::new(&image.path, 64).build();
+
+  assert!(filesys.create_file_with_data("a", b"ready").is_ok());
+  assert_eq!(filesys.cat("a").as_deref(), Some("ready"));
+  assert_eq!(filesys.disk_info().total_blocks, 64);
+  assert!(filesys.cache_stats().is_none());
+}
+
+#[test]
+fn builder_puts_the_chosen_cache_in_front_of_the_disk() {
+  let image = TempImage::new("builder_cache");
+  let mut filesys = FilesysBuilder::new(&image.path, 64)
+    .cache(BufferCacheStrategy::Lfu { capacity: 8 })
+    .build();
+
+  assert!(filesys.create_file_with_data("a", b"cached").is_ok());
+  assert!(filesys.cache_stats().is_some());
+}
+
+/*
+  Buffer cache
+*/
+
+fn cached(image: &TempImage, block_count: Size, strategy: BufferCacheStrategy) -> Filesys<'static> {
+  FilesysBuilder::new(&image.path, block_count)
+    .cache(strategy)
+    .build()
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+  (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn prefetched_blocks_are_read_without_touching_the_host() {
+  for strategy in [
+    BufferCacheStrategy::Lfu { capacity: 16 },
+    BufferCacheStrategy::Fifo { capacity: 16 },
+  ] {
+    let image = TempImage::new("prefetch");
+    let shared = Shared::new(cached(&image, 128, strategy));
+    let data = pattern(40 * BLOCK_SIZE as usize);
+    assert!(shared.fs().create_file_with_data("a", &data).is_ok());
+    assert!(shared.fs().sync());
+
+    /* Open across the prefetch, so the pointer blocks it resolved are not looked up again */
+    let mut file = shared.fs().open_file("a").unwrap();
+
+    /* The file is larger than the cache, so its first blocks were evicted while it was written */
+    let mut buffer = vec![0; 4 * BLOCK_SIZE as usize];
+    shared.fs().reset_counters();
+    shared.fs().file_read(&mut file, &mut buffer, 0);
+    assert!(
+      shared.fs().host_counts().unwrap().reads > 0,
+      "{:?}",
+      strategy
+    );
+
+    let (offset, len) = (8 * BLOCK_SIZE as usize, 6 * BLOCK_SIZE as usize);
+    assert!(shared.fs().prefetch("a", offset as Ofs, len as Size));
+    shared.fs().reset_counters();
+
+    let mut buffer = vec![0; len];
+    let from_head = offset as Ofs - file.tell();
+    assert_eq!(
+      shared.fs().file_read(&mut file, &mut buffer, from_head),
+      len as Ofs
+    );
+    assert_eq!(buffer, &data[offset..offset + len]);
+    assert_eq!(
+      shared.fs().host_counts().unwrap().reads,
+      0,
+      "{:?}",
+      strategy
+    );
+    assert_eq!(
+      shared.fs().cache_stats().unwrap().misses,
+      0,
+      "{:?}",
+      strategy
+    );
+    shared.fs().close_file(file);
+  }
+}
+
+#[test]
+fn prefetch_without_a_cache_changes_nothing() {
+  let image = TempImage::new("prefetch_uncached");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(3000)).is_ok());
+
+  assert!(filesys.prefetch("a", 0, 3000));
+  assert!(!filesys.prefetch("missing", 0, 1));
+  assert_eq!(filesys.read_range("a", 0, 3000), Some(pattern(3000)));
+}
+
+/*
+  Scratch device
+*/
+
+#[test]
+fn scratch_blocks_round_trip_without_touching_the_disk() {
+  let image = TempImage::new("scratch_disk");
+  let scratch_image = TempImage::new("scratch");
+  let mut filesys = image.build(64);
+  filesys.new_scratch(&scratch_image.path, 16);
+
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  let disk_before = disk.to_string();
+
+  let mut written = block::EMPTY_BLOCK;
+  written
+    .iter_mut()
+    .enumerate()
+    .for_each(|(i, b)| *b = i as u8);
+  filesys.scratch_write(&written, 15);
+
+  let mut read = block::EMPTY_BLOCK;
+  filesys.scratch_read(&mut read, 15);
+  assert_eq!(read, written);
+
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  assert_eq!(disk.to_string(), disk_before);
+  let scratch = filesys.block_devs.get_by_role(DeviceType::Scratch).unwrap();
+  assert!(scratch
+    .to_string()
+    .ends_with("has performed 1 read and 1 write operations"));
+}
+
+/*
+  Raw blocks
+*/
+
+fn filled(byte: u8) -> block::Block {
+  [byte; BLOCK_SIZE as usize]
+}
+
+#[test]
+fn raw_blocks_round_trip_verbatim() {
+  let image = TempImage::new("raw");
+  let mut filesys = image.build(64);
+
+  let mut written = block::EMPTY_BLOCK;
+  written
+    .iter_mut()
+    .enumerate()
+    .for_each(|(i, b)| *b = (i * 7) as u8);
+  assert!(filesys.write_block(40, &written));
+
+  let mut read = block::EMPTY_BLOCK;
+  assert!(filesys.read_block(40, &mut read));
+  assert_eq!(read, written);
+
+  assert!(!filesys.read_block(64, &mut read));
+  assert!(!filesys.write_block(64, &written));
+}
+
+#[test]
+fn an_inode_table_keeps_inodes_and_data_apart() {
+  let image = TempImage::new("inode_table");
+  let mut filesys = image.build(128);
+  let table = 8;
+  assert!(filesys.set_inode_limit(table));
+
+  /* The root and free map take two slots, and neither holds any data yet */
+  assert_eq!(filesys.free_inodes(), table - 2);
+  assert_eq!(filesys.free_blocks(), 128 - table);
+
+  let mut created = 0;
+  while filesys
+    .create_file_with_data(&format!("f{}", created), &pattern(2000))
+    .is_ok()
+  {
+    let name = format!("f{}", created);
+    assert!(filesys.stat(&name).unwrap().inumber < table);
+    assert!(file_blocks(&mut filesys, &name).iter().all(|&b| b >= table));
+    created += 1;
+  }
+
+  /* Stopped by the full table, with data blocks to spare: two per file and one for the root */
+  assert_eq!(created, table as usize - 2);
+  assert_eq!(filesys.free_inodes(), 0);
+  assert_eq!(filesys.free_blocks(), 128 - table - 1 - 2 * created as Size);
+  let info = filesys.disk_info();
+  assert_eq!((info.free_blocks, info.free_inodes), (filesys.free_blocks(), 0));
+  assert_eq!(info.used_blocks, 128 - info.free_blocks);
+
+  assert!(filesys.remove_file("f0"));
+  assert_eq!(filesys.free_inodes(), 1);
+  assert!(filesys.create_file("again", 0).is_ok());
+  assert!(filesys.stat("again").unwrap().inumber < table);
+  assert_eq!(filesys.free_inodes(), 0);
+  assert_eq!(filesys.free_blocks(), 128 - table - 1 - 2 * (created as Size - 1));
+
+  /* A table can only be laid over blocks nothing uses yet */
+  let image = TempImage::new("inode_table_late");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(3000)).is_ok());
+  assert!(!filesys.set_inode_limit(table));
+}
+
+#[test]
+fn raw_access_refuses_reserved_blocks_and_the_inode_table() {
+  let image = TempImage::new("raw_reserved");
+  let mut filesys = image.build(64);
+  assert!(filesys.set_inode_limit(8));
+  assert!(filesys.reserve_block(50));
+
+  let mut buffer = block::EMPTY_BLOCK;
+  for pos in [super::ROOT_INODE, super::FREE_MAP_INODE, 5, 50] {
+    assert!(!filesys.read_block(pos, &mut buffer), "{}", pos);
+    assert!(!filesys.write_block(pos, &filled(0xEE)), "{}", pos);
+  }
+  assert!(filesys.read_block(8, &mut buffer));
+
+  filesys.set_unsafe_raw(true);
+  assert!(filesys.write_block(50, &filled(0xEE)));
+  assert!(filesys.read_block(50, &mut buffer));
+  assert_eq!(buffer, filled(0xEE));
+  assert!(filesys.read_block(super::ROOT_INODE, &mut buffer));
+}
+
+#[test]
+fn dump_block_labels_offsets_and_shows_sixteen_bytes_a_line() {
+  let image = TempImage::new("dump");
+  let mut filesys = image.build(64);
+
+  let mut written = block::EMPTY_BLOCK;
+  written[16..21].copy_from_slice(b"hello");
+  written[32] = 0xff;
+  assert!(filesys.write_block(40, &written));
+
+  let dump = filesys.dump_block(40).unwrap();
+  let lines: Vec<&str> = dump.lines().collect();
+  assert_eq!(lines.len(), BLOCK_SIZE as usize / 16);
+  assert_eq!(
+    lines[1],
+    "0010  68 65 6c 6c 6f 00 00 00 00 00 00 00 00 00 00 00  |hello...........|"
+  );
+  assert!(lines[2].starts_with("0020  ff 00"));
+  assert!(lines
+    .last()
+    .unwrap()
+    .starts_with(&format!("{:04x}  ", BLOCK_SIZE - 16)));
+
+  /* Reserved blocks are shown, but nothing past the end of the disk */
+  assert!(filesys.dump_block(0).is_some());
+  assert!(filesys.dump_block(63).is_some());
+  assert_eq!(filesys.dump_block(64), None);
+  assert_eq!(filesys.dump_block(Size::MAX), None);
+}
+
+/*
+  Space accounting
+*/
+
+#[test]
+fn disk_info_counts_only_open_regular_files() {
+  let image = TempImage::new("disk_info_open");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("a", 0).is_ok());
+  assert!(shared.fs().create_file("b", 0).is_ok());
+  add_directory(shared.fs(), "dir");
+  assert_eq!(shared.fs().list_sorted("/", false).unwrap().len(), 3);
+  assert_eq!(shared.fs().disk_info().open_files, 0);
+
+  let first = shared.fs().open_file("a").unwrap();
+  assert_eq!(shared.fs().disk_info().open_files, 1);
+  let second = shared.fs().open_file("a").unwrap();
+  let other = shared.fs().open_file("b").unwrap();
+  assert_eq!(shared.fs().disk_info().open_files, 2);
+
+  shared.fs().close_file(first);
+  shared.fs().close_file(other);
+  assert_eq!(shared.fs().disk_info().open_files, 1);
+  shared.fs().close_file(second);
+  assert_eq!(shared.fs().disk_info().open_files, 0);
+}
+
+#[test]
+fn allocated_blocks_count_the_inode_and_pointer_blocks() {
+  let image = TempImage::new("allocated_blocks");
+  let mut filesys = image.build(128);
+
+  assert!(filesys.create_file("small", BLOCK_SIZE).is_ok());
+  assert_eq!(filesys.stat("small").unwrap().blocks, 2);
+
+  /* More blocks than any number of direct pointers, so one indirect block is needed */
+  let free_before = filesys.disk_info().free_blocks;
+  assert!(filesys.create_file("large", 20 * BLOCK_SIZE).is_ok());
+  let blocks = filesys.stat("large").unwrap().blocks;
+
+  assert_eq!(blocks, 1 + 20 + 1);
+  assert_eq!(free_before - filesys.disk_info().free_blocks, blocks);
+}
+
+/*
+  Directory index
+*/
+
+fn reads_during(filesys: &mut Filesys, op: impl FnOnce(&mut Filesys)) -> usize {
+  filesys.set_trace(100_000);
+  op(filesys);
+  let reads = filesys
+    .trace_log()
+    .iter()
+    .filter(|event| event.op == block::TraceOp::Read)
+    .count();
+  filesys.set_trace(0);
+  reads
+}
+
+#[test]
+fn lookups_read_a_few_entries_in_a_large_directory() {
+  let image = TempImage::new("dir_index");
+  let mut filesys = image.build(4096);
+  (0..2000).for_each(|i| assert!(filesys.create_file(&format!("f{}", i), 0).is_ok()));
+
+  let root_blocks = filesys.stat_inumber(super::ROOT_INODE).unwrap().len / BLOCK_SIZE;
+  assert!(root_blocks > 50);
+
+  let reads = reads_during(&mut filesys, |filesys| {
+    assert!(filesys.stat("f1999").is_some())
+  });
+  assert!(reads <= 8, "{} reads", reads);
+  let reads = reads_during(&mut filesys, |filesys| {
+    assert!(filesys.stat("missing").is_none())
+  });
+  assert!(reads <= 8, "{} reads", reads);
+}
+
+#[test]
+fn the_index_is_rebuilt_after_a_reload() {
+  let image = TempImage::new("dir_index_reload");
+  let mut filesys = image.build(1024);
+  (0..300).for_each(|i| assert!(filesys.create_file(&format!("f{}", i), 0).is_ok()));
+  assert!(filesys.sync());
+  drop(filesys);
+
+  let mut filesys = Filesys::init();
+  filesys.load_disk(&image.path, BufferCacheStrategy::None);
+
+  /* The first lookup scans every entry to rebuild the index, which later lookups then use */
+  let first = reads_during(&mut filesys, |filesys| {
+    assert!(filesys.stat("f0").is_some())
+  });
+  let second = reads_during(&mut filesys, |filesys| {
+    assert!(filesys.stat("f299").is_some())
+  });
+  let root_blocks = filesys.stat_inumber(super::ROOT_INODE).unwrap().len / BLOCK_SIZE;
+  assert!(first as Size > root_blocks, "{} reads", first);
+  assert!(second <= 8, "{} reads", second);
+}
+
+/* Reads of the root directory's own blocks while `op` runs */
+fn root_dir_reads(filesys: &mut Filesys, op: impl FnOnce(&mut Filesys)) -> usize {
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  let mut dir_blocks = filesys
+    .inodes
+    .inode_blocks(super::ROOT_INODE, disk)
+    .unwrap();
+  dir_blocks.retain(|&block| block != super::ROOT_INODE);
+
+  filesys.set_trace(100_000);
+  op(filesys);
+  let reads = filesys
+    .trace_log()
+    .iter()
+    .filter(|event| event.op == block::TraceOp::Read && dir_blocks.contains(&event.block))
+    .count();
+  filesys.set_trace(0);
+  reads
+}
+
+#[test]
+fn a_repeated_lookup_skips_the_directory() {
+  let image = TempImage::new("path_cache");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"abc").is_ok());
+
+  assert!(root_dir_reads(&mut filesys, |filesys| assert!(filesys.stat("a").is_some())) > 0);
+  assert_eq!(
+    root_dir_reads(&mut filesys, |filesys| assert!(filesys.stat("a").is_some())),
+    0
+  );
+}
+
+#[test]
+fn the_path_cache_follows_renames_removals_and_rewrites() {
+  let image = TempImage::new("path_cache_stale");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"abc").is_ok());
+  assert!(filesys.create_file_with_data("b", b"defg").is_ok());
+  let (a, b) = (
+    filesys.stat("a").unwrap().inumber,
+    filesys.stat("b").unwrap().inumber,
+  );
+
+  /* Renamed over `b`, whose inode is cached */
+  assert!(filesys.rename("a", "b", true).is_ok());
+  assert!(filesys.stat("a").is_none());
+  assert_eq!(filesys.stat("b").unwrap().inumber, a);
+
+  assert!(filesys.remove_file("b"));
+  assert!(filesys.stat("b").is_none());
+  assert!(filesys.create_file_with_data("b", b"hi").is_ok());
+  assert_ne!(filesys.stat("b").unwrap().inumber, b);
+
+  /* A rewrite made inside a session moves the path to a new inode too */
+  let before = filesys.stat("b").unwrap().inumber;
+  assert_eq!(
+    filesys.with_disk(|session| session.rewrite("b", b"bye!")),
+    Some(true)
+  );
+  let after = filesys.stat("b").unwrap();
+  assert_ne!(after.inumber, before);
+  assert_eq!(after.len, 4);
+}
+
+fn create_reads(filesys: &mut Filesys, names: std::ops::Range<usize>) -> usize {
+  reads_during(filesys, |filesys| {
+    names.for_each(|i| assert!(filesys.create_file(&format!("f{}", i), 0).is_ok()))
+  })
+}
+
+#[test]
+fn bulk_creation_reads_grow_linearly() {
+  let image = TempImage::new("free_hint");
+  let mut filesys = image.build(2048);
+
+  /* Each batch adds as many entries as the directory held before it */
+  let first = create_reads(&mut filesys, 0..400);
+  let second = create_reads(&mut filesys, 400..800);
+  assert!(second < first * 3 / 2, "{} then {} reads", first, second);
+}
+
+/* Entry names in slot order. `Filesys::list` borrows the filesystem for good */
+fn root_names(filesys: &mut Filesys) -> Vec<String> {
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  Dir::open_root(&mut filesys.inodes, disk).list(disk)
+}
+
+#[test]
+fn removed_slots_are_reused_first() {
+  let image = TempImage::new("free_hint_reuse");
+  let mut filesys = image.build(256);
+  (0..40).for_each(|i| assert!(filesys.create_file(&format!("f{}", i), 0).is_ok()));
+
+  assert!(filesys.remove_file("f3"));
+  assert!(filesys.create_file("new", 0).is_ok());
+  assert_eq!(root_names(&mut filesys)[3], "new");
+  assert!(filesys.create_file("last", 0).is_ok());
+  assert_eq!(root_names(&mut filesys).last().unwrap(), "last");
+}
+
+/*
+  Rename
+*/
+
+#[test]
+fn rename_moves_an_entry_to_a_new_name() {
+  let image = TempImage::new("rename");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"contents").is_ok());
+
+  assert_eq!(filesys.rename("a", "b", false), Ok(None));
+  assert!(filesys.stat("a").is_none());
+  assert_eq!(filesys.cat("b").as_deref(), Some("contents"));
+}
+
+#[test]
+fn rename_refuses_to_replace_without_overwrite() {
+  let image = TempImage::new("rename_refuse");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"source").is_ok());
+  assert!(filesys.create_file_with_data("b", b"destination").is_ok());
+
+  assert_eq!(filesys.rename("a", "b", false), Err(RenameError::Exists));
+  assert_eq!(filesys.cat("a").as_deref(), Some("source"));
+  assert_eq!(filesys.cat("b").as_deref(), Some("destination"));
+}
+
+#[test]
+fn rename_reports_a_missing_source_before_an_existing_destination() {
+  let image = TempImage::new("rename_missing");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("b", b"destination").is_ok());
+
+  assert_eq!(
+    filesys.rename("missing", "b", false),
+    Err(RenameError::NotFound)
+  );
+  assert_eq!(
+    filesys.rename("missing", "b", true),
+    Err(RenameError::NotFound)
+  );
+  assert_eq!(filesys.cat("b").as_deref(), Some("destination"));
+}
+
+#[test]
+fn rename_overwrite_frees_the_old_destination() {
+  let image = TempImage::new("rename_overwrite");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"source").is_ok());
+  assert!(filesys.create_file("b", 3 * BLOCK_SIZE).is_ok());
+  let old = filesys.stat("b").unwrap();
+  let free_before = filesys.disk_info().free_blocks;
+
+  assert_eq!(filesys.rename("a", "b", true), Ok(Some(old.inumber)));
+  assert!(filesys.stat("a").is_none());
+  assert_eq!(filesys.cat("b").as_deref(), Some("source"));
+  assert_eq!(filesys.disk_info().free_blocks, free_before + old.blocks);
+}
+
+#[test]
+fn rename_returns_an_open_destination_and_frees_it_on_close() {
+  let image = TempImage::new("rename_replaced_open");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"source").is_ok());
+  assert!(shared.fs().create_file("b", 3 * BLOCK_SIZE).is_ok());
+  add_directory(shared.fs(), "dir");
+  let old = shared.fs().stat("b").unwrap();
+  let free_before = shared.fs().free_blocks();
+
+  assert_eq!(shared.fs().rename("a", "a", true), Ok(None));
+  assert_eq!(
+    shared.fs().rename("a", "dir", true),
+    Err(RenameError::NotRegular)
+  );
+  assert_eq!(
+    shared.fs().rename("a", "", true),
+    Err(RenameError::Name(NameError::Empty))
+  );
+
+  let file = shared.fs().open_file("b").unwrap();
+  assert_eq!(shared.fs().rename("a", "b", true), Ok(Some(old.inumber)));
+  assert_eq!(shared.fs().free_blocks(), free_before);
+  shared.fs().close_file(file);
+  assert_eq!(shared.fs().free_blocks(), free_before + old.blocks);
+  assert_eq!(shared.fs().cat("b").as_deref(), Some("source"));
+}
+
+#[test]
+fn an_open_handle_survives_a_rename() {
+  let image = TempImage::new("rename_open");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"before").is_ok());
+  let mut file = shared.fs().open_file("a").unwrap();
+
+  assert!(shared.fs().rename_file("a", "b"));
+  assert!(shared.fs().path_cache.get("a").is_none());
+
+  let mut buffer = [0; 6];
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 6);
+  assert_eq!(&buffer, b"before");
+  assert_eq!(shared.fs().file_write(&mut file, b"+after", 0), 6);
+  shared.fs().close_file(file);
+
+  assert_eq!(shared.fs().cat("b").as_deref(), Some("before+after"));
+  assert_eq!(shared.fs().open_count("b"), Some(0));
+  assert!(shared.fs().stat("a").is_none());
+}
+
+/*
+  Empty files
+*/
+
+#[test]
+fn touch_creates_an_empty_file_with_only_an_inode() {
+  let image = TempImage::new("touch_create");
+  let mut filesys = image.build(64);
+  /* The first entry grows the directory by a block */
+  assert!(filesys.touch("first").is_ok());
+  let free_before = filesys.disk_info().free_blocks;
+
+  assert!(filesys.touch("new").is_ok());
+  let stat = filesys.stat("new").unwrap();
+  assert_eq!(
+    (stat.file_type, stat.len, stat.blocks),
+    (FileType::Regular, 0, 1)
+  );
+  assert_eq!(filesys.disk_info().free_blocks, free_before - 1);
+}
+
+#[test]
+fn touch_leaves_an_existing_file_alone() {
+  let image = TempImage::new("touch_existing");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"kept").is_ok());
+  let free_before = filesys.disk_info().free_blocks;
+
+  assert!(filesys.touch("a").is_ok());
+  assert_eq!(filesys.cat("a").as_deref(), Some("kept"));
+  assert_eq!(filesys.disk_info().free_blocks, free_before);
+  assert_eq!(filesys.touch(""), Err(DirError::Name(NameError::Empty)));
+  assert_eq!(
+    filesys.touch("a-name-far-too-long"),
+    Err(DirError::Name(NameError::TooLong { len: 19 }))
+  );
+}
+
+#[test]
+fn an_empty_file_reads_nothing_and_grows_on_write() {
+  let image = TempImage::new("empty_file");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("empty", 0).is_ok());
+
+  let mut file = shared.fs().open_file("empty").unwrap();
+  assert_eq!(file.length(), 0);
+  let mut buffer = [0xAA; 4];
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 0);
+  assert_eq!(buffer, [0xAA; 4]);
+
+  assert_eq!(shared.fs().file_write(&mut file, b"grown", 0), 5);
+  assert_eq!(file.length(), 5);
+  shared.fs().close_file(file);
+  assert_eq!(shared.fs().cat("empty").as_deref(), Some("grown"));
+}
+
+/* Leave freed blocks on the disk that still hold `byte`, for the next file to reuse */
+fn leave_stale_blocks(filesys: &mut Filesys, blocks: usize, byte: u8) {
+  assert!(filesys
+    .create_file_with_data("stale", &vec![byte; blocks * BLOCK_SIZE as usize])
+    .is_ok());
+  assert!(filesys.remove_file("stale"));
+}
+
+#[test]
+fn zeroing_new_blocks_hides_a_removed_file() {
+  for zero in [false, true] {
+    let image = TempImage::new("zero_new_blocks");
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file("first", 0).is_ok());
+    filesys.set_alloc_policy(AllocPolicy::FirstFit);
+    filesys.set_zero_new_blocks(zero);
+    leave_stale_blocks(&mut filesys, 3, 0xAA);
+
+    assert!(filesys.create_file("new", 3 * BLOCK_SIZE).is_ok());
+    assert!(filesys.write_path("new", 0, b"head"));
+    let tail = filesys.read_range("new", 4, 3 * BLOCK_SIZE - 4).unwrap();
+    assert_eq!(tail.iter().all(|&b| b == 0), zero);
+    if !zero {
+      assert!(tail.contains(&0xAA));
+    }
+  }
+}
+
+#[test]
+fn writes_past_the_end_zero_the_gap() {
+  let image = TempImage::new("write_gap");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("first", 0).is_ok());
+  leave_stale_blocks(shared.fs(), 4, 0xAA);
+  assert!(shared.fs().create_file("sparse", 0).is_ok());
+
+  let mut file = shared.fs().open_file("sparse").unwrap();
+  assert_eq!(shared.fs().file_write(&mut file, b"abc", 0), 3);
+  /* Relative to the head, which the first write moved to 3 */
+  let gap_end = 3 * BLOCK_SIZE as Ofs + 7;
+  assert_eq!(shared.fs().file_write(&mut file, b"end", gap_end - 3), 3);
+  shared.fs().close_file(file);
+
+  let data = shared.fs().read_range("sparse", 0, BLOCK_SIZE * 4).unwrap();
+  assert_eq!(data.len(), gap_end as usize + 3);
+  assert_eq!(&data[..3], b"abc");
+  assert!(data[3..gap_end as usize].iter().all(|&b| b == 0));
+  assert_eq!(&data[gap_end as usize..], b"end");
+}
+
+#[test]
+fn a_write_that_cannot_extend_returns_a_short_count() {
+  let image = TempImage::new("write_short");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", &[1; 10]).is_ok());
+  /* The filler's inode and indirect block use up the last two */
+  let rest = shared.fs().free_blocks() - 2;
+  assert!(shared.fs().create_file("filler", rest * BLOCK_SIZE).is_ok());
+  assert_eq!(shared.fs().free_blocks(), 0);
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  let data = pattern(2 * BLOCK_SIZE as usize);
+  assert_eq!(shared.fs().file_write(&mut file, &data, 4), 6);
+  assert_eq!(file.length(), 10);
+  shared.fs().close_file(file);
+
+  let contents = shared.fs().read_range("a", 0, 10).unwrap();
+  assert_eq!(&contents[..4], &[1; 4]);
+  assert_eq!(&contents[4..], &data[..6]);
+}
+
+/*
+  Path operations
+*/
+
+#[test]
+fn read_range_returns_a_slice_across_blocks() {
+  let image = TempImage::new("read_range");
+  let mut filesys = image.build(64);
+  let data = pattern(5 * BLOCK_SIZE as usize);
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+
+  let (offset, len) = (BLOCK_SIZE as usize - 10, 2 * BLOCK_SIZE as usize + 20);
+  let slice = filesys.read_range("a", offset as Ofs, len as Size).unwrap();
+  assert_eq!(slice, &data[offset..offset + len]);
+
+  /* Clamped to the end of the file */
+  let tail = filesys.read_range("a", data.len() as Ofs - 4, 100).unwrap();
+  assert_eq!(tail, &data[data.len() - 4..]);
+  assert_eq!(
+    filesys.read_range("a", data.len() as Ofs + 1, 10),
+    Some(Vec::new())
+  );
+  assert_eq!(filesys.read_range("missing", 0, 10), None);
+  assert_eq!(filesys.open_count("a"), Some(0));
+}
+
+/*
+  Sync
+*/
+
+#[test]
+fn sync_persists_file_data_and_the_free_map() {
+  let image = TempImage::new("sync");
+  let mut filesys = image.build(64);
+  let data: Vec<u8> = b"persisted ".iter().cycle().take(3000).copied().collect();
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  assert!(filesys.sync());
+  let free = filesys.free_blocks();
+
+  let host = std::fs::read(&image.path).unwrap();
+  assert!(host.windows(data.len()).any(|window| window == data));
+
+  let mut reloaded = Filesys::init();
+  reloaded.load_disk(&image.path, BufferCacheStrategy::None);
+  assert_eq!(reloaded.free_blocks(), free);
+  assert_eq!(reloaded.cat("a").map(String::into_bytes), Some(data));
+}
+
+#[test]
+fn the_sync_policy_decides_when_a_write_reaches_the_host() {
+  for policy in [SyncPolicy::Never, SyncPolicy::Always] {
+    let image = TempImage::new("sync_policy");
+    let shared = Shared::new(cached(
+      &image,
+      64,
+      BufferCacheStrategy::Lfu { capacity: 16 },
+    ));
+    assert!(shared.fs().create_file("a", 16).is_ok());
+    assert!(shared.fs().sync());
+    shared.fs().set_sync_policy(policy);
+
+    let mut file = shared.fs().open_file("a").unwrap();
+    assert_eq!(shared.fs().file_write(&mut file, b"marker-bytes", 0), 12);
+    let on_host = || {
+      let host = std::fs::read(&image.path).unwrap();
+      host.windows(12).any(|window| window == b"marker-bytes")
+    };
+    assert_eq!(on_host(), policy == SyncPolicy::Always, "{:?}", policy);
+
+    assert!(shared.fs().sync());
+    assert!(on_host(), "{:?}", policy);
+    shared.fs().close_file(file);
+  }
+}
+
+#[test]
+fn a_failed_sync_is_reported_under_the_always_policy() {
+  let image = TempImage::new("sync_always");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"data").is_ok());
+
+  /* The free map's file is only allocated on the first sync, for which no block is left */
+  let rest = shared.fs().free_blocks() - 2;
+  assert!(shared.fs().create_file("filler", rest * BLOCK_SIZE).is_ok());
+  assert_eq!(shared.fs().free_blocks(), 0);
+  shared.fs().set_sync_policy(SyncPolicy::Always);
+
+  assert!(!shared.fs().set_file_len("a", 4));
+  let mut file = shared.fs().open_file("a").unwrap();
+  assert_eq!(shared.fs().file_write(&mut file, b"DATA", 0), 0);
+  shared.fs().close_file(file);
+}
+
+/*
+  Loading
+*/
+
+fn reload(image: &TempImage) -> Filesys<'static> {
+  let mut filesys = Filesys::init();
+  filesys.load_disk(&image.path, BufferCacheStrategy::None);
+  filesys
+}
+
+#[test]
+fn shrinking_drops_free_trailing_blocks_from_the_host_file() {
+  let image = TempImage::new("shrink");
+  let mut filesys = image.build(256);
+  let data = pattern(3 * BLOCK_SIZE as usize);
+  assert!(filesys.create_file_with_data("low", &data).is_ok());
+  assert!(filesys.create_file("high", 100 * BLOCK_SIZE).is_ok());
+  assert!(filesys.sync());
+  let host_len = || std::fs::metadata(&image.path).unwrap().len();
+  let before = host_len();
+
+  /* Still in use, so nothing up to its last block can go */
+  let highest = *file_blocks(&mut filesys, "high").last().unwrap();
+  assert!(filesys.shrink_disk());
+  assert!(host_len() < before);
+  assert!(host_len() > highest * BLOCK_SIZE);
+
+  assert!(filesys.remove_file("high"));
+  assert!(filesys.shrink_disk());
+  let after = host_len();
+  assert!(
+    after < highest * BLOCK_SIZE,
+    "{} of {} bytes left",
+    after,
+    before
+  );
+  assert!(!filesys.shrink_disk());
+  assert_eq!(
+    filesys.read_range("low", 0, data.len() as Size),
+    Some(data.clone())
+  );
+  let free = filesys.free_blocks();
+  drop(filesys);
+
+  let mut filesys = reload(&image);
+  assert_eq!(filesys.read_range("low", 0, data.len() as Size), Some(data));
+  assert_eq!(filesys.free_blocks(), free);
+  assert!(filesys.verify_free_map().is_empty());
+}
+
+#[test]
+fn repair_drops_only_the_dangling_entries() {
+  let image = TempImage::new("repair_dangling");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"kept").is_ok());
+  assert!(filesys.create_file("b", 0).is_ok());
+
+  /* Entries left by creations cut short: one at a block never written, one off the device */
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  let free_map = filesys.free_map.as_mut().unwrap();
+  let never_written = free_map.free_extents().last().unwrap().0;
+  let mut root = Dir::open_root(&mut filesys.inodes, disk);
+  root.add("ghost", never_written, free_map, disk).unwrap();
+  root.add("far", 10_000, free_map, disk).unwrap();
+
+  assert_eq!(filesys.repair_dangling_entries(), 2);
+  assert_eq!(filesys.repair_dangling_entries(), 0);
+  let mut names = root_names(&mut filesys);
+  names.sort();
+  assert_eq!(names, vec!["a", "b"]);
+  assert_eq!(filesys.read_range("a", 0, 4).as_deref(), Some(&b"kept"[..]));
+  assert!(filesys.create_file("ghost", 0).is_ok());
+}
+
+#[test]
+fn an_entry_pointing_at_a_data_block_is_refused() {
+  let image = TempImage::new("corrupt_entry");
+  let shared = Shared::new(image.build(64));
+  let data = pattern(2 * BLOCK_SIZE as usize);
+  assert!(shared.fs().create_file_with_data("a", &data).is_ok());
+  assert!(shared.fs().create_file("b", 0).is_ok());
+  let data_block = file_blocks(shared.fs(), "a")[0];
+
+  let filesys = shared.fs();
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  Dir::open_root(&mut filesys.inodes, disk)
+    .repoint("b", data_block, disk)
+    .unwrap();
+
+  assert!(shared.fs().open_file("b").is_none());
+  assert!(shared.fs().stat("b").is_none());
+  assert!(!shared.fs().remove_file("b"));
+  assert_eq!(shared.fs().read_range("b", 0, 1), None);
+
+  /* The block still belongs to `a`, untouched */
+  assert_eq!(
+    shared.fs().read_range("a", 0, data.len() as Size),
+    Some(data)
+  );
+  assert_eq!(shared.fs().repair_dangling_entries(), 1);
+  assert_eq!(root_names(shared.fs()), vec!["a"]);
+}
+
+#[test]
+fn a_clean_image_loads_with_a_clean_report() {
+  let image = TempImage::new("load_clean");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(3000)).is_ok());
+  assert!(filesys.create_file("b", 0).is_ok());
+  assert!(filesys.sync());
+  drop(filesys);
+
+  let mut filesys = Filesys::init();
+  let report = filesys.load_disk_verified(&image.path);
+  assert!(report.is_clean(), "{:?}", report);
+  assert_eq!(filesys.read_range("a", 0, 3000), Some(pattern(3000)));
+}
+
+#[test]
+fn a_corrupt_inode_is_reported_on_load() {
+  let image = TempImage::new("load_corrupt");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(3000)).is_ok());
+  assert!(filesys.sync());
+  let inumber = filesys.stat("a").unwrap().inumber;
+  assert!(filesys.write_block(inumber, &block::EMPTY_BLOCK));
+  drop(filesys);
+
+  let mut filesys = Filesys::init();
+  let report = filesys.load_disk_verified(&image.path);
+  assert!(!report.is_clean());
+  assert_eq!(report.bad_inodes, vec![inumber]);
+  /* The inode and its three data blocks are marked, but nothing valid refers to them */
+  assert_eq!(report.leaked_blocks.len(), 4);
+}
+
+#[test]
+fn an_image_never_synced_rebuilds_its_free_map() {
+  let image = TempImage::new("load_unsynced");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(3000)).is_ok());
+  let free = filesys.free_blocks();
+  drop(filesys);
+
+  let mut filesys = reload(&image);
+  assert_eq!(filesys.free_blocks(), free);
+  assert!(filesys.verify_free_map().is_empty());
+
+  /* New files must not be handed the blocks `a` still uses */
+  assert!(filesys.create_file_with_data("b", &[0xEE; 3000]).is_ok());
+  assert_eq!(filesys.read_range("a", 0, 3000), Some(pattern(3000)));
+}
+
+#[test]
+fn free_blocks_matches_the_free_map() {
+  let image = TempImage::new("free_blocks");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("a", 5 * BLOCK_SIZE).is_ok());
+
+  let free = filesys.free_blocks();
+  assert_eq!(filesys.disk_info().free_blocks, free);
+  assert_eq!(
+    filesys.create_file("too_large", (free + 1) * BLOCK_SIZE),
+    Err(DirError::OutOfSpace)
+  );
+  assert_eq!(filesys.free_blocks(), free);
+}
+
+/*
+  Handles
+*/
+
+#[test]
+fn a_dup_shares_data_but_keeps_its_own_position() {
+  let image = TempImage::new("dup");
+  let shared = Shared::new(image.build(64));
+  assert!(shared
+    .fs()
+    .create_file_with_data("a", b"0123456789")
+    .is_ok());
+
+  let mut first = shared.fs().open_file("a").unwrap();
+  first.seek(4);
+  let mut second = shared.fs().dup_file(&first);
+  assert_eq!(shared.fs().open_count("a"), Some(2));
+
+  assert_eq!(shared.fs().file_write(&mut first, b"ab", 0), 2);
+  assert_eq!((first.tell(), second.tell()), (6, 4));
+
+  let mut buffer = [0; 4];
+  assert_eq!(shared.fs().file_read(&mut second, &mut buffer, 0), 4);
+  assert_eq!(&buffer, b"ab67");
+  assert_eq!((first.tell(), second.tell()), (6, 8));
+
+  shared.fs().close_file(first);
+  shared.fs().close_file(second);
+  assert_eq!(shared.fs().open_count("a"), Some(0));
+}
+
+#[test]
+fn peek_reads_without_moving_the_head() {
+  let image = TempImage::new("peek");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"abcdefgh").is_ok());
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  file.seek(2);
+  let mut peeked = [0; 3];
+  assert_eq!(shared.fs().file_peek(&file, &mut peeked), 3);
+  assert_eq!(file.tell(), 2);
+
+  let mut read = [0; 3];
+  assert_eq!(shared.fs().file_read(&mut file, &mut read, 0), 3);
+  assert_eq!((&peeked, &read), (b"cde", b"cde"));
+  assert_eq!(file.tell(), 5);
+
+  assert_eq!(shared.fs().file_read_at(&file, 6, &mut peeked), 2);
+  assert_eq!(&peeked[..2], b"gh");
+  assert_eq!(file.tell(), 5);
+  shared.fs().close_file(file);
+}
+
+/*
+  Listing
+*/
+
+#[test]
+fn list_with_stat_matches_stat_and_skips_invalid_inodes() {
+  let image = TempImage::new("list_with_stat");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", &pattern(10)).is_ok());
+  assert!(filesys.create_file_with_data("b", &pattern(3000)).is_ok());
+  assert!(filesys.create_file("broken", 0).is_ok());
+  let broken = filesys.stat("broken").unwrap().inumber;
+  assert!(filesys.write_block(broken, &block::EMPTY_BLOCK));
+  let open_files = filesys.disk_info().open_files;
+
+  let listed = filesys.list_with_stat("/").unwrap();
+  let names: Vec<&str> = listed.iter().map(|(name, _)| name.as_str()).collect();
+  assert_eq!(names, ["a", "b"]);
+  for (name, stat) in &listed {
+    assert_eq!(Some(*stat), filesys.stat(name));
+  }
+  assert_eq!(filesys.disk_info().open_files, open_files);
+}
+
+/*
+  Defragmentation
+*/
+
+/* Data and pointer blocks of `path`, in disk order */
+fn file_blocks(filesys: &mut Filesys, path: &str) -> Vec<Size> {
+  let inumber = filesys.stat(path).unwrap().inumber;
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+
+  let mut blocks = filesys.inodes.inode_blocks(inumber, disk).unwrap();
+  blocks.retain(|&block| block != inumber);
+  blocks.sort_unstable();
+  blocks
+}
+
+fn contiguous(blocks: &[Size]) -> bool {
+  blocks.windows(2).all(|w| w[1] == w[0] + 1)
+}
+
+#[test]
+fn defragment_makes_a_file_contiguous_and_keeps_its_data() {
+  let image = TempImage::new("defragment");
+  let mut filesys = image.build(128);
+
+  /* Interleave the growth of `a` with other allocations */
+  assert!(filesys.create_file("a", 0).is_ok());
+  for i in 1..=6 {
+    assert!(filesys.set_file_len("a", i * BLOCK_SIZE));
+    assert!(filesys.create_file(&format!("x{}", i), BLOCK_SIZE).is_ok());
+  }
+  let data = pattern(6 * BLOCK_SIZE as usize);
+  assert!(filesys.write_path("a", 0, &data));
+  assert!(!contiguous(&file_blocks(&mut filesys, "a")));
+
+  assert!(filesys.defragment());
+  assert!(contiguous(&file_blocks(&mut filesys, "a")));
+  assert_eq!(filesys.read_range("a", 0, data.len() as Size), Some(data));
+  assert!(filesys.verify_free_map().is_empty());
+}
+
+#[test]
+fn defragment_dir_moves_only_what_it_can() {
+  let image = TempImage::new("defragment_dir");
+  let shared = Shared::new(image.build(128));
+  for name in ["a", "b"] {
+    assert!(shared.fs().create_file(name, 0).is_ok());
+  }
+  for i in 1..=4 {
+    for name in ["a", "b"] {
+      assert!(shared.fs().set_file_len(name, i * BLOCK_SIZE));
+    }
+  }
+
+  /* An open file is left where it is, and reported */
+  let file = shared.fs().open_file("b").unwrap();
+  let b_blocks = file_blocks(shared.fs(), "b");
+  assert!(!shared.fs().defragment_dir("/"));
+  assert!(contiguous(&file_blocks(shared.fs(), "a")));
+  assert_eq!(file_blocks(shared.fs(), "b"), b_blocks);
+  shared.fs().close_file(file);
+
+  assert!(shared.fs().defragment());
+  assert!(contiguous(&file_blocks(shared.fs(), "b")));
+}
+
+#[test]
+fn fragmentation_report_counts_runs_without_moving_blocks() {
+  let image = TempImage::new("frag_report");
+  let mut filesys = image.build(128);
+  assert!(filesys.create_file("a", 0).is_ok());
+  for i in 1..=3 {
+    assert!(filesys.set_file_len("a", i * BLOCK_SIZE));
+    assert!(filesys.create_file(&format!("x{}", i), BLOCK_SIZE).is_ok());
+  }
+  let blocks = file_blocks(&mut filesys, "a");
+
+  let report = filesys.fragmentation_report();
+  let runs = |report: &super::FragReport, name: &str| {
+    report
+      .files
+      .iter()
+      .find(|(file, _)| file == name)
+      .unwrap()
+      .1
+  };
+  assert!(runs(&report, "a") > 1);
+  assert_eq!(runs(&report, "x1"), 1);
+  assert!(report.score > 0.0);
+  assert_eq!(file_blocks(&mut filesys, "a"), blocks);
+
+  assert!(filesys.defragment());
+  let report = filesys.fragmentation_report();
+  assert_eq!(runs(&report, "a"), 1);
+}
+
+/*
+  Statistics
+*/
+
+#[test]
+fn disk_info_adds_up_and_tracks_creation() {
+  let image = TempImage::new("disk_info");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("first", 0).is_ok());
+  let before = filesys.disk_info();
+  assert_eq!(before.used_blocks + before.free_blocks, before.total_blocks);
+  assert_eq!((before.total_blocks, before.block_size), (64, BLOCK_SIZE));
+
+  assert!(filesys.create_file("a", 3 * BLOCK_SIZE).is_ok());
+  let after = filesys.disk_info();
+  assert_eq!(after.free_blocks, before.free_blocks - 4);
+  assert_eq!(after.used_blocks + after.free_blocks, after.total_blocks);
+}
+
+#[test]
+fn reset_counters_leaves_only_later_activity() {
+  let image = TempImage::new("reset_counters");
+  let mut filesys = cached(&image, 64, BufferCacheStrategy::Lfu { capacity: 8 });
+  assert!(filesys
+    .create_file_with_data("a", &pattern(4 * BLOCK_SIZE as usize))
+    .is_ok());
+  assert!(filesys.read_range("a", 0, 4 * BLOCK_SIZE).is_some());
+  assert!(filesys.sync());
+
+  filesys.reset_counters();
+  assert_eq!(filesys.cache_stats(), Some(CacheStats::default()));
+  assert_eq!(filesys.host_counts(), Some(IoCounts::default()));
+  assert!(filesys
+    .display_disk_stats()
+    .contains("performed 0 read and 0 write operations"));
+
+  /* Two reads of an unused block: a miss that reaches the host, then a hit that does not */
+  let mut buffer = block::EMPTY_BLOCK;
+  assert!(filesys.read_block(63, &mut buffer));
+  assert!(filesys.read_block(63, &mut buffer));
+
+  let stats = filesys.cache_stats().unwrap();
+  assert_eq!((stats.hits, stats.misses), (1, 1));
+  assert_eq!(
+    filesys.host_counts(),
+    Some(IoCounts {
+      reads: 1,
+      writes: 0
+    })
+  );
+  assert!(filesys
+    .display_disk_stats()
+    .contains("performed 2 read and 0 write operations"));
+}
+
+#[test]
+fn files_copy_between_mounted_volumes() {
+  let (first, second) = (
+    TempImage::new("mount_first"),
+    TempImage::new("mount_second"),
+  );
+  let mut other = first.build(64);
+  assert!(other.create_file_with_data("from_first", b"one").is_ok());
+  assert!(other.sync());
+  drop(other);
+  let mut other = second.build(64);
+  assert!(other.create_file("empty", 0).is_ok());
+  assert!(other.sync());
+  drop(other);
+
+  let image = TempImage::new("mount_default");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("local", b"default").is_ok());
+  assert!(filesys.mount("first", &first.path, BufferCacheStrategy::None));
+  assert!(filesys.mount(
+    "second",
+    &second.path,
+    BufferCacheStrategy::Lfu { capacity: 8 }
+  ));
+  assert!(!filesys.mount("first", &second.path, BufferCacheStrategy::None));
+
+  /* Each volume sees only its own files */
+  assert_eq!(
+    filesys.with_mount("first", |fs| root_names(fs)),
+    Some(vec!["from_first".into()])
+  );
+  assert_eq!(root_names(&mut filesys), vec!["local"]);
+  assert_eq!(filesys.with_mount("missing", |fs| fs.free_blocks()), None);
+
+  assert!(filesys.copy_between(Some("first"), "from_first", Some("second"), "copied"));
+  assert!(filesys.copy_between(None, "local", Some("second"), "local"));
+  assert!(filesys.copy_between(Some("second"), "copied", None, "back"));
+  assert!(!filesys.copy_between(Some("first"), "missing", None, "x"));
+  assert!(!filesys.copy_between(None, "local", Some("missing"), "x"));
+
+  assert_eq!(
+    filesys.read_range("back", 0, 3).as_deref(),
+    Some(&b"one"[..])
+  );
+  assert_eq!(
+    filesys.with_mount("second", |fs| fs.read_range("local", 0, 7)),
+    Some(Some(b"default".to_vec()))
+  );
+  assert!(filesys.verify_free_map().is_empty());
+  assert_eq!(
+    filesys.with_mount("second", |fs| fs.verify_free_map()),
+    Some(vec![])
+  );
+
+  /* Unmounting syncs, so the copies are on the image when loaded on its own */
+  assert!(filesys.unmount("second"));
+  assert!(!filesys.unmount("second"));
+  let mut second_alone = reload(&second);
+  let mut names = root_names(&mut second_alone);
+  names.sort();
+  assert_eq!(names, vec!["copied", "empty", "local"]);
+  assert_eq!(
+    second_alone.read_range("copied", 0, 3).as_deref(),
+    Some(&b"one"[..])
+  );
+}
+
+#[test]
+fn display_disk_stats_reports_the_read_and_write_counts() {
+  let image = TempImage::new("disk_stats");
+  let mut filesys = image.build(64);
+  filesys.reset_counters();
+
+  let mut buffer = block::EMPTY_BLOCK;
+  assert!(filesys.write_block(40, &buffer));
+  assert!(filesys.write_block(41, &buffer));
+  assert!(filesys.read_block(40, &mut buffer));
+
+  let stats = filesys.display_disk_stats();
+  assert!(stats.starts_with("Device 'DISK'"), "{}", stats);
+  assert!(
+    stats.contains("performed 1 read and 2 write operations"),
+    "{}",
+    stats
+  );
+}
+
+#[test]
+fn the_evict_hook_sees_each_eviction_once() {
+  let image = TempImage::new("evict_hook");
+  let evicted = Rc::new(RefCell::new(Vec::new()));
+  let seen = Rc::clone(&evicted);
+  let mut filesys = FilesysBuilder::new(&image.path, 128)
+    .cache(BufferCacheStrategy::Fifo { capacity: 8 })
+    .evict_hook(Box::new(move |block, dirty| {
+      seen.borrow_mut().push((block, dirty))
+    }))
+    .build();
+
+  let data = pattern(20 * BLOCK_SIZE as usize);
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  let stats = filesys.cache_stats().unwrap();
+  assert!(stats.evictions > 0);
+  assert_eq!(evicted.borrow().len(), stats.evictions);
+  assert!(evicted.borrow().iter().any(|&(_, dirty)| dirty));
+  assert!(evicted.borrow().iter().all(|&(block, _)| block < 128));
+
+  /* The sync writes the free map through the cache, but its write-back alone evicts nothing */
+  assert!(filesys.sync());
+  let stats = filesys.cache_stats().unwrap();
+  assert_eq!(evicted.borrow().len(), stats.evictions);
+}
+
+#[test]
+fn the_evict_hook_needs_a_buffer_cache() {
+  let image = TempImage::new("evict_hook_uncached");
+  let mut filesys = image.build(64);
+  assert!(!filesys.set_evict_hook(Box::new(|_, _| {})));
+}
+
+#[test]
+fn the_trace_records_accesses_in_order_and_keeps_the_latest() {
+  let image = TempImage::new("trace");
+  let mut filesys = image.build(64);
+  filesys.reset_counters();
+  filesys.set_trace(3);
+
+  let mut buffer = block::EMPTY_BLOCK;
+  assert!(filesys.write_block(40, &buffer));
+  assert!(filesys.read_block(40, &mut buffer));
+  assert!(filesys.write_block(41, &buffer));
+  assert!(filesys.read_block(41, &mut buffer));
+
+  let event = |op, block, seq| block::TraceEvent { op, block, seq };
+  assert_eq!(
+    filesys.trace_log(),
+    vec![
+      event(block::TraceOp::Read, 40, 1),
+      event(block::TraceOp::Write, 41, 2),
+      event(block::TraceOp::Read, 41, 3),
+    ]
+  );
+
+  filesys.set_trace(0);
+  assert!(filesys.read_block(40, &mut buffer));
+  assert!(filesys.trace_log().is_empty());
+}
+
+/* Host writes made by `op` after formatting and a first sync, on an image without faults */
+fn writes_during(op: impl FnOnce(&mut Filesys)) -> (usize, usize) {
+  let image = TempImage::new("fault_dry_run");
+  let mut filesys = image.build(64);
+  assert!(filesys.sync());
+  let before = filesys.host_counts().unwrap().writes;
+  op(&mut filesys);
+  (before, filesys.host_counts().unwrap().writes)
+}
+
+#[test]
+fn a_fault_while_creating_a_file_leaks_no_blocks() {
+  let create = |filesys: &mut Filesys| filesys.create_file("a", 6 * BLOCK_SIZE).is_ok();
+  let (before, after) = writes_during(|filesys| assert!(create(filesys)));
+  assert!(after > before);
+
+  for nth in before + 1..=after {
+    let image = TempImage::new("fault_create");
+    let mut filesys = Filesys::init();
+    filesys.new_faulty_disk(&image.path, 64, Fault::NthWrite(nth));
+    filesys.init_free_map();
+    assert!(filesys.sync());
+
+    let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| create(&mut filesys)));
+    assert!(outcome.is_err(), "write {} did not fault", nth);
+    drop(filesys);
+
+    let report = Filesys::init().load_disk_verified(&image.path);
+    assert!(report.bad_inodes.is_empty(), "write {}: {:?}", nth, report);
+    assert!(
+      report.leaked_blocks.is_empty(),
+      "write {}: {:?}",
+      nth,
+      report
+    );
+  }
+}
+
+#[test]
+fn file_checksum_follows_the_contents() {
+  let image = TempImage::new("checksum");
+  let mut filesys = image.build(64);
+  let mut data = pattern(3 * BLOCK_SIZE as usize + 100);
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  let digest = filesys.file_checksum("a").unwrap();
+
+  assert!(filesys.remove_file("a"));
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  assert_eq!(filesys.file_checksum("a"), Some(digest));
+
+  data[BLOCK_SIZE as usize + 7] ^= 1;
+  assert!(filesys.remove_file("a"));
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  assert_ne!(filesys.file_checksum("a"), Some(digest));
+
+  /* The FNV-1a reference value for "a" */
+  assert!(filesys.create_file_with_data("b", b"a").is_ok());
+  assert_eq!(filesys.file_checksum("b"), Some(0xaf63dc4c8601ec8c));
+  assert_eq!(filesys.file_checksum("missing"), None);
+}
+
+#[test]
+fn a_loaded_image_can_sit_behind_a_buffer_cache() {
+  let image = TempImage::new("load_cached");
+  let data = pattern(3 * BLOCK_SIZE as usize);
+  {
+    let mut filesys = image.build(64);
+    assert!(filesys.create_file_with_data("a", &data).is_ok());
+    assert!(filesys.sync());
+  }
+
+  for strategy in [
+    BufferCacheStrategy::Lfu { capacity: 8 },
+    BufferCacheStrategy::Fifo { capacity: 8 },
+  ] {
+    let mut filesys = Filesys::init();
+    filesys.load_disk(&image.path, strategy);
+    assert_eq!(
+      filesys.read_range("a", 0, data.len() as Size),
+      Some(data.clone())
+    );
+    assert_eq!(
+      filesys.read_range("a", 0, data.len() as Size),
+      Some(data.clone())
+    );
+
+    let stats = filesys.cache_stats().unwrap();
+    assert!(stats.hits > 0, "{:?}", strategy);
+    let display = filesys.display_disk_stats();
+    assert!(display.contains("cache hits"), "{}", display);
+    assert!(display.contains("reached the host"), "{}", display);
+  }
+
+  let mut filesys = reload(&image);
+  assert_eq!(filesys.cache_stats(), None);
+}
+
+#[test]
+fn open_count_tracks_each_open_and_close() {
+  let image = TempImage::new("open_count");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("a", 10).is_ok());
+  assert_eq!(shared.fs().open_count("a"), Some(0));
+
+  let first = shared.fs().open_file("a").unwrap();
+  let second = shared.fs().open_file("a").unwrap();
+  assert_eq!(shared.fs().open_count("a"), Some(2));
+  /* Asking does not count as an open */
+  assert_eq!(shared.fs().open_count("a"), Some(2));
+
+  shared.fs().close_file(first);
+  assert_eq!(shared.fs().open_count("a"), Some(1));
+  shared.fs().close_file(second);
+  assert_eq!(shared.fs().open_count("a"), Some(0));
+  assert_eq!(shared.fs().open_count("missing"), None);
+}
+
+#[test]
+fn sizes_round_trip_through_a_block() {
+  let mut buffer = block::EMPTY_BLOCK;
+  block::write_size_le(&mut buffer, 13, 0x0123_4567_89ab_cdef);
+  assert_eq!(block::read_size_le(&buffer, 13), 0x0123_4567_89ab_cdef);
+  assert_eq!(block::read_size_le(&buffer, 5), 0);
+  assert!(buffer[..13].iter().chain(&buffer[21..]).all(|&b| b == 0));
+}
+
+#[test]
+fn sizes_are_decoded_little_endian() {
+  let mut buffer = block::EMPTY_BLOCK;
+  buffer[8..16].copy_from_slice(&[0x01, 0x02, 0, 0, 0, 0, 0, 0x80]);
+  assert_eq!(block::read_size_le(&buffer, 8), 0x8000_0000_0000_0201);
+
+  block::write_size_le(&mut buffer, 0, 258);
+  assert_eq!(&buffer[..8], &[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn create_file_with_data_writes_in_one_call_or_leaves_nothing() {
+  let image = TempImage::new("create_with_data");
+  let mut filesys = image.build(64);
+  let data = pattern(2 * BLOCK_SIZE as usize + 5);
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  assert_eq!(filesys.read_range("a", 0, data.len() as Size), Some(data));
+  assert_eq!(filesys.open_count("a"), Some(0));
+
+  let free = filesys.free_blocks();
+  let too_large = vec![1; (free as usize + 1) * BLOCK_SIZE as usize];
+  assert_eq!(
+    filesys.create_file_with_data("b", &too_large),
+    Err(DirError::OutOfSpace)
+  );
+  assert_eq!(filesys.file_type("b"), None);
+  assert_eq!(filesys.free_blocks(), free);
+}
+
+#[test]
+fn file_type_reports_each_kind() {
+  let image = TempImage::new("file_type");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("a", 10).is_ok());
+  add_directory(&mut filesys, "d");
+
+  assert_eq!(filesys.file_type("a"), Some(FileType::Regular));
+  assert_eq!(filesys.file_type("d"), Some(FileType::Directory));
+  assert_eq!(filesys.file_type("missing"), None);
+  assert!(filesys.is_file("a") && !filesys.is_dir("a"));
+  assert!(filesys.is_dir("d") && !filesys.is_file("d"));
+}
+
+#[test]
+fn a_rolled_back_transaction_leaves_the_disk_untouched() {
+  let image = TempImage::new("tx_rollback");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("keep", 0).is_ok());
+  let free = filesys.free_blocks();
+
+  filesys.begin();
+  filesys.stage_create("a", &pattern(3000));
+  filesys.stage_create("b", b"bee");
+  filesys.stage_remove("keep");
+  filesys.rollback();
+
+  assert_eq!(filesys.file_type("a"), None);
+  assert_eq!(filesys.file_type("b"), None);
+  assert!(filesys.is_file("keep"));
+  assert_eq!(filesys.free_blocks(), free);
+}
+
+#[test]
+fn a_committed_transaction_applies_its_operations_in_order() {
+  let image = TempImage::new("tx_commit");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("old", b"gone").is_ok());
+
+  filesys.begin();
+  filesys.stage_create("a", b"0123456789");
+  filesys.stage_write("a", 8, b"xyz");
+  filesys.stage_remove("old");
+  filesys.stage_create("old", b"new");
+  assert!(filesys.commit());
+
+  assert_eq!(filesys.cat("a").as_deref(), Some("01234567xyz"));
+  assert_eq!(filesys.cat("old").as_deref(), Some("new"));
+}
+
+#[test]
+fn a_staged_write_past_the_end_zeroes_the_gap_over_stale_blocks() {
+  let image = TempImage::new("tx_write_gap");
+  let mut filesys = image.build(64);
+  let stale = vec![0xaa; 4 * BLOCK_SIZE as usize];
+  assert!(filesys.create_file_with_data("old", &stale).is_ok());
+  assert!(filesys.remove_file("old"));
+  assert!(filesys.create_file_with_data("a", b"abc").is_ok());
+
+  let offset = 3 * BLOCK_SIZE as usize;
+  filesys.begin();
+  filesys.stage_write("a", offset as Ofs, b"end");
+  assert!(filesys.commit());
+
+  let mut expected = b"abc".to_vec();
+  expected.resize(offset, 0);
+  expected.extend_from_slice(b"end");
+  let len = expected.len() as Size;
+  assert_eq!(filesys.read_range("a", 0, len), Some(expected));
+}
+
+#[test]
+fn a_commit_with_an_invalid_operation_applies_none() {
+  let image = TempImage::new("tx_invalid");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("exists", 0).is_ok());
+  let free = filesys.free_blocks();
+
+  for bad in [
+    StagedCase::WriteMissing,
+    StagedCase::CreateExisting,
+    StagedCase::RemoveTwice,
+    StagedCase::BadName,
+  ] {
+    filesys.begin();
+    filesys.stage_create("a", &pattern(2000));
+    match bad {
+      StagedCase::WriteMissing => filesys.stage_write("missing", 0, b"x"),
+      StagedCase::CreateExisting => filesys.stage_create("exists", b"x"),
+      StagedCase::RemoveTwice => {
+        filesys.stage_remove("exists");
+        filesys.stage_remove("exists");
+      }
+      StagedCase::BadName => filesys.stage_create("", b"x"),
+    }
+    assert!(!filesys.commit(), "{:?}", bad);
+
+    assert_eq!(filesys.file_type("a"), None, "{:?}", bad);
+    assert!(filesys.is_file("exists"), "{:?}", bad);
+    assert_eq!(filesys.free_blocks(), free, "{:?}", bad);
+  }
+}
+
+#[derive(Debug)]
+enum StagedCase {
+  WriteMissing,
+  CreateExisting,
+  RemoveTwice,
+  BadName,
+}
+
+#[test]
+fn a_commit_that_runs_out_of_space_undoes_what_it_applied() {
+  let image = TempImage::new("tx_undo");
+  let mut filesys = image.build(64);
+  let original = pattern(2 * BLOCK_SIZE as usize);
+  assert!(filesys.create_file_with_data("edited", &original).is_ok());
+  assert!(filesys
+    .create_file_with_data("removed", b"still here")
+    .is_ok());
+  let free = filesys.free_blocks();
+
+  filesys.begin();
+  filesys.stage_create("a", b"new file");
+  filesys.stage_write("edited", 100, &vec![7; 3 * BLOCK_SIZE as usize]);
+  filesys.stage_remove("removed");
+  filesys.stage_create("huge", &vec![1; 2 * free as usize * BLOCK_SIZE as usize]);
+  assert!(!filesys.commit());
+
+  assert_eq!(filesys.file_type("a"), None);
+  assert_eq!(filesys.file_type("huge"), None);
+  assert_eq!(
+    filesys.read_range("edited", 0, 10 * BLOCK_SIZE),
+    Some(original)
+  );
+  assert_eq!(filesys.cat("removed").as_deref(), Some("still here"));
+  assert_eq!(filesys.free_blocks(), free);
+}
+
+#[test]
+fn direct_changes_wait_for_the_transaction_to_close() {
+  let image = TempImage::new("tx_direct");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"abc").is_ok());
+
+  filesys.begin();
+  assert_eq!(filesys.create_file("b", 0), Err(DirError::InTransaction));
+  assert_eq!(
+    filesys.create_file_with_data("b", b"x"),
+    Err(DirError::InTransaction)
+  );
+  assert_eq!(filesys.touch("b"), Err(DirError::InTransaction));
+  assert!(!filesys.set_file_len("a", 0));
+  assert!(!filesys.rewrite_file("a", b"x"));
+  assert!(!filesys.remove_file("a"));
+  assert_eq!(
+    filesys.rename("a", "c", false),
+    Err(RenameError::InTransaction)
+  );
+  assert_eq!(filesys.cat("a").as_deref(), Some("abc"));
+  filesys.rollback();
+
+  assert!(filesys.create_file("b", 0).is_ok());
+  assert!(filesys.remove_file("a"));
+}
+
+#[test]
+fn a_readahead_window_keeps_sequential_reads_in_the_cache() {
+  let image = TempImage::new("readahead");
+  let data = pattern(20 * BLOCK_SIZE as usize);
+  {
+    let mut filesys = image.build(128);
+    assert!(filesys.create_file_with_data("a", &data).is_ok());
+    assert!(filesys.sync());
+  }
+
+  for (window, strategy) in [
+    (16, BufferCacheStrategy::Lfu { capacity: 40 }),
+    (16, BufferCacheStrategy::Fifo { capacity: 40 }),
+    (0, BufferCacheStrategy::Lfu { capacity: 40 }),
+  ] {
+    let mut filesys = Filesys::init();
+    filesys.load_disk(&image.path, strategy);
+    let shared = Shared::new(filesys);
+
+    let mut file = shared.fs().open_file("a").unwrap();
+    file.set_readahead(window);
+    /* The window runs past the first pointer block, so the reads after it resolve no new ones */
+    let mut buffer = vec![0; BLOCK_SIZE as usize];
+    assert_eq!(
+      shared.fs().file_read(&mut file, &mut buffer, 0),
+      BLOCK_SIZE as Ofs
+    );
+
+    shared.fs().reset_counters();
+    let mut buffer = vec![0; 8 * BLOCK_SIZE as usize];
+    assert_eq!(
+      shared.fs().file_read(&mut file, &mut buffer, 0),
+      buffer.len() as Ofs
+    );
+    assert_eq!(buffer, &data[BLOCK_SIZE as usize..9 * BLOCK_SIZE as usize]);
+
+    let misses = shared.fs().cache_stats().unwrap().misses;
+    if window > 0 {
+      assert_eq!(misses, 0, "{:?}", strategy);
+    } else {
+      assert!(misses >= 8, "{}", misses);
+    }
+    shared.fs().close_file(file);
+  }
+}
+
+#[test]
+fn crossing_the_full_threshold_raises_a_warning_once() {
+  let image = TempImage::new("full_threshold");
+  let mut filesys = image.build(100);
+  assert!(filesys.usage_ratio() < 0.1);
+
+  filesys.set_full_threshold(Some(0.9));
+  let target = (filesys.disk_info().total_blocks as f64 * 0.85) as Size;
+  let used = filesys.disk_info().used_blocks;
+  assert!(filesys
+    .create_file("bulk", (target - used - 2) * BLOCK_SIZE)
+    .is_ok());
+  assert!(filesys.usage_ratio() < 0.9);
+  assert!(!filesys.take_full_warning());
+
+  assert!(filesys.create_file("more", 8 * BLOCK_SIZE).is_ok());
+  assert!(filesys.usage_ratio() > 0.9);
+  assert!(filesys.take_full_warning());
+  assert!(!filesys.take_full_warning());
+
+  filesys.set_full_threshold(None);
+  assert!(filesys.create_file("last", BLOCK_SIZE).is_ok());
+  assert!(!filesys.take_full_warning());
+}
+
+#[test]
+fn compacting_a_directory_reclaims_its_trailing_blocks() {
+  let image = TempImage::new("compact_dir");
+  let mut filesys = image.build(512);
+  let names: Vec<String> = (0..200).map(|i| format!("f{}", i)).collect();
+  for name in &names {
+    assert!(filesys.create_file(name, 0).is_ok());
+  }
+  for name in names.iter().step_by(2) {
+    assert!(filesys.remove_file(name));
+  }
+
+  let root_len = |filesys: &mut Filesys| filesys.stat_inumber(0).unwrap().len;
+  let before = root_len(&mut filesys);
+  let free = filesys.free_blocks();
+  assert!(filesys.compact_dir(""));
+
+  assert!(root_len(&mut filesys) < before);
+  assert!(filesys.free_blocks() > free);
+  for (i, name) in names.iter().enumerate() {
+    assert_eq!(filesys.is_file(name), i % 2 == 1, "{}", name);
+  }
+  assert_eq!(root_names(&mut filesys).len(), 100);
+}
+
+#[test]
+fn verify_free_map_names_the_drifted_blocks() {
+  let image = TempImage::new("verify_free_map");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("a", 2 * BLOCK_SIZE).is_ok());
+  assert!(filesys.verify_free_map().is_empty());
+
+  /* Marked without any inode referring to it */
+  let extra = filesys
+    .free_map
+    .as_ref()
+    .unwrap()
+    .free_extents()
+    .next()
+    .unwrap()
+    .0;
+  filesys.free_map.as_mut().unwrap().mark_allocated(extra);
+  assert_eq!(filesys.verify_free_map(), vec![extra]);
+
+  /* Referenced by `a`, but free */
+  let used = file_blocks(&mut filesys, "a")[0];
+  filesys.free_map.as_mut().unwrap().release(used);
+  let mut expected = vec![extra, used];
+  expected.sort_unstable();
+  assert_eq!(filesys.verify_free_map(), expected);
+
+  /* A reserved block is meant to be unreferenced */
+  filesys.free_map.as_mut().unwrap().mark_allocated(used);
+  filesys.free_map.as_mut().unwrap().release(extra);
+  let spare = filesys
+    .free_map
+    .as_ref()
+    .unwrap()
+    .free_extents()
+    .last()
+    .unwrap()
+    .0;
+  assert!(filesys.reserve_block(spare));
+  assert!(filesys.verify_free_map().is_empty());
+}
+
+#[test]
+fn directories_grow_a_whole_block_at_a_time() {
+  let image = TempImage::new("dir_growth");
+  let mut filesys = image.build(256);
+  let root = |filesys: &mut Filesys| {
+    let stat = filesys.stat_inumber(0).unwrap();
+    (stat.len, stat.blocks)
+  };
+
+  let mut growths = Vec::new();
+  for i in 0..100 {
+    let before = root(&mut filesys);
+    assert!(filesys.create_file(&format!("f{}", i), 0).is_ok());
+    let after = root(&mut filesys);
+    assert_eq!(after.0 % BLOCK_SIZE, 0);
+    if after != before {
+      assert_eq!(after.0, before.0 + BLOCK_SIZE);
+      growths.push(i);
+    }
+  }
+
+  /* Each new block holds many entries, so growth is rare and evenly spaced */
+  assert!(growths.len() > 1 && growths.len() < 10, "{:?}", growths);
+  let spacing = growths[1] - growths[0];
+  assert!(spacing > 1);
+  assert!(growths.windows(2).all(|pair| pair[1] - pair[0] == spacing));
+}
+
+#[test]
+fn reading_exactly_to_the_end_reaches_eof() {
+  let image = TempImage::new("at_eof");
+  let shared = Shared::new(image.build(64));
+  let data = pattern(BLOCK_SIZE as usize + 10);
+  assert!(shared.fs().create_file_with_data("a", &data).is_ok());
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  let mut buffer = vec![0; data.len()];
+  assert!(!file.at_eof());
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer[..10], 0), 10);
+  assert!(!file.at_eof());
+
+  /* The buffer is exactly the rest of the file, so the read is not short */
+  assert_eq!(
+    shared.fs().file_read(&mut file, &mut buffer[10..], 0),
+    BLOCK_SIZE as Ofs
+  );
+  assert_eq!(buffer, data);
+  assert!(file.at_eof());
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 0);
+  assert!(file.at_eof());
+
+  file.seek_start();
+  assert!(!file.at_eof());
+  shared.fs().close_file(file);
+}
+
+#[test]
+fn a_read_only_handle_refuses_writes() {
+  let image = TempImage::new("read_only");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"abc").is_ok());
+  let free = shared.fs().free_blocks();
+
+  let mut file = shared.fs().open_file_mode("a", false).unwrap();
+  assert!(!file.is_writable());
+  assert_eq!(shared.fs().file_write(&mut file, b"x", 0), 0);
+  assert_eq!(shared.fs().file_write(&mut file, &[1; 2048], 3), 0);
+
+  let mut buffer = [0; 3];
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 3);
+  assert_eq!(&buffer, b"abc");
+  shared.fs().close_file(file);
+
+  assert_eq!(shared.fs().stat("a").unwrap().len, 3);
+  assert_eq!(shared.fs().free_blocks(), free);
+  let writable = shared.fs().open_file("a").unwrap();
+  assert!(writable.is_writable());
+  shared.fs().close_file(writable);
+}
+
+#[test]
+fn a_session_creates_writes_and_reads_under_one_borrow() {
+  let image = TempImage::new("with_disk");
+  let mut filesys = image.build(64);
+
+  let read = filesys.with_disk(|session| {
+    assert!(session.create_file("a", 0).is_ok());
+    assert!(session.write_at("a", 2, b"xyz"));
+    session.read_range("a", 0, 5)
+  });
+  assert_eq!(read.flatten().as_deref(), Some(&b"\0\0xyz"[..]));
+
+  /* Seen outside the session too */
+  assert_eq!(filesys.stat("a").unwrap().len, 5);
+  assert_eq!(filesys.read_range("a", 2, 3).as_deref(), Some(&b"xyz"[..]));
+}
+
+#[test]
+fn a_session_is_refused_while_a_transaction_is_open() {
+  let image = TempImage::new("with_disk_tx");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"abc").is_ok());
+  let free = filesys.free_blocks();
+
+  filesys.begin();
+  filesys.stage_write("a", 0, b"staged");
+  let ran = filesys.with_disk(|session| {
+    assert!(session.create_file("b", 0).is_ok());
+    session.write_at("a", 0, b"direct")
+  });
+  assert_eq!(ran, None);
+  assert_eq!(filesys.file_type("b"), None);
+  assert_eq!(filesys.free_blocks(), free);
+
+  /* Nothing the refused session tried is left for the rollback to trip over */
+  filesys.rollback();
+  assert_eq!(filesys.cat("a").as_deref(), Some("abc"));
+  assert_eq!(
+    filesys.with_disk(|session| session.read_range("a", 0, 3)),
+    Some(Some(b"abc".to_vec()))
+  );
+}
+
+#[test]
+fn a_removed_file_stays_readable_until_its_last_handle_closes() {
+  let image = TempImage::new("unlink_open");
+  let shared = Shared::new(image.build(64));
+  let data = pattern(3 * BLOCK_SIZE as usize);
+  assert!(shared.fs().create_file_with_data("a", &data).is_ok());
+  let owned = shared.fs().stat("a").unwrap().blocks;
+  let free = shared.fs().free_blocks() + owned;
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  assert!(shared.fs().remove_file("a"));
+  assert_eq!(shared.fs().file_type("a"), None);
+  assert_eq!(shared.fs().free_blocks(), free - owned);
+
+  let mut buffer = vec![0; data.len()];
+  assert_eq!(
+    shared.fs().file_read(&mut file, &mut buffer, 0),
+    data.len() as Ofs
+  );
+  assert_eq!(buffer, data);
+
+  shared.fs().close_file(file);
+  assert_eq!(shared.fs().free_blocks(), free);
+}
+
+#[test]
+fn transfers_over_many_blocks_reuse_the_bounce_buffer_cleanly() {
+  let image = TempImage::new("bounce");
+  let mut filesys = image.build(256);
+  let mut model = pattern(100 * BLOCK_SIZE as usize + 300);
+  assert!(filesys.create_file_with_data("a", &model).is_ok());
+
+  /* Unaligned at both ends, so every chunk but the first and last is a whole block */
+  let patch = vec![0x5A; 40 * BLOCK_SIZE as usize + 17];
+  let offset = 3 * BLOCK_SIZE as usize + 700;
+  assert!(filesys.write_path("a", offset as Ofs, &patch));
+  model[offset..offset + patch.len()].copy_from_slice(&patch);
+
+  assert_eq!(
+    filesys.read_range("a", 0, model.len() as Size).unwrap(),
+    model
+  );
+  let (start, len) = (BLOCK_SIZE as usize - 1, 60 * BLOCK_SIZE as usize + 2);
+  assert_eq!(
+    filesys.read_range("a", start as Ofs, len as Size).unwrap(),
+    model[start..start + len]
+  );
+}
+
+#[test]
+fn iter_files_yields_each_regular_file_once() {
+  let image = TempImage::new("iter_files");
+  let mut filesys = image.build(128);
+  for i in 0..10 {
+    assert!(filesys
+      .create_file_with_data(&format!("f{}", i), &vec![1; i])
+      .is_ok());
+  }
+  add_directory(&mut filesys, "d");
+
+  let mut files: Vec<(String, Size)> = filesys
+    .iter_files()
+    .map(|(name, stat)| (name, stat.len))
+    .collect();
+  files.sort();
+  let expected: Vec<(String, Size)> = (0..10).map(|i| (format!("f{}", i), i as Size)).collect();
+  assert_eq!(files, expected);
+
+  /* Inodes are read as the iterator reaches them, not up front */
+  let one = reads_during(&mut filesys, |filesys| {
+    assert!(filesys.iter_files().next().is_some())
+  });
+  let all = reads_during(&mut filesys, |filesys| {
+    assert_eq!(filesys.iter_files().count(), 10)
+  });
+  assert!(one + 9 <= all, "{} then {} reads", one, all);
+}
+
+#[test]
+fn a_temp_file_is_unnamed_and_freed_on_close() {
+  let image = TempImage::new("create_temp");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("a", 0).is_ok());
+  let free = shared.fs().free_blocks();
+  let names = root_names(shared.fs());
+
+  let mut temp = shared.fs().create_temp().unwrap();
+  let data = pattern(3 * BLOCK_SIZE as usize);
+  assert_eq!(
+    shared.fs().file_write(&mut temp, &data, 0),
+    data.len() as Ofs
+  );
+  assert!(shared.fs().free_blocks() < free);
+  assert_eq!(root_names(shared.fs()), names);
+
+  temp.seek_start();
+  let mut buffer = vec![0; data.len()];
+  assert_eq!(
+    shared.fs().file_read(&mut temp, &mut buffer, 0),
+    data.len() as Ofs
+  );
+  assert_eq!(buffer, data);
+
+  shared.fs().close_file(temp);
+  assert_eq!(shared.fs().free_blocks(), free);
+  assert_eq!(root_names(shared.fs()), names);
+  assert!(shared.fs().verify_free_map().is_empty());
+}
+
+#[test]
+fn open_handles_lists_each_open_file_with_its_count() {
+  let image = TempImage::new("open_handles");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file("a", 0).is_ok());
+  assert!(shared.fs().create_file("b", 0).is_ok());
+  let (a, b) = (
+    shared.fs().stat("a").unwrap().inumber,
+    shared.fs().stat("b").unwrap().inumber,
+  );
+  let files = |shared: &Shared| {
+    let mut handles = shared.fs().open_handles();
+    handles.retain(|h| h.inumber != super::ROOT_INODE);
+    handles.sort_by_key(|h| h.inumber);
+    handles
+  };
+  let info = |inumber, open_count, path: Option<&str>| OpenHandleInfo {
+    inumber,
+    open_count,
+    path: path.map(String::from),
+  };
+  assert_eq!(files(&shared), vec![]);
+
+  let first = shared.fs().open_file("a").unwrap();
+  let second = shared.fs().dup_file(&first);
+  let other = shared.fs().open_file("b").unwrap();
+  assert_eq!(
+    files(&shared),
+    vec![info(a, 2, Some("a")), info(b, 1, Some("b"))]
+  );
+
+  /* Listing opens nothing, so a second listing matches */
+  assert_eq!(files(&shared), files(&shared));
+
+  assert!(shared.fs().remove_file("b"));
+  shared.fs().close_file(second);
+  assert_eq!(
+    files(&shared),
+    vec![info(a, 1, Some("a")), info(b, 1, None)]
+  );
+
+  shared.fs().close_file(first);
+  shared.fs().close_file(other);
+  assert_eq!(files(&shared), vec![]);
+}
+
+#[test]
+fn reading_the_tree_leaves_the_root_with_only_its_pin() {
+  let image = TempImage::new("root_count");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file_with_data("a", b"data").is_ok());
+  let a = filesys.stat("a").unwrap().inumber;
+  let root = |filesys: &mut Filesys| {
+    filesys
+      .open_handles()
+      .into_iter()
+      .find(|h| h.inumber == super::ROOT_INODE)
+      .unwrap()
+  };
+  assert_eq!(root(&mut filesys).open_count, 1);
+
+  for _ in 0..3 {
+    assert_eq!(filesys.path_of_inode(a).as_deref(), Some("a"));
+    assert_eq!(filesys.iter_files().count(), 1);
+    assert!(filesys.list_sorted("/", false).is_some());
+    assert!(filesys.list_with_stat("/").is_some());
+    assert_eq!(filesys.fragmentation_report().files.len(), 1);
+    assert!(filesys.resolve("a").is_some());
+    assert_eq!(filesys.cat("a").as_deref(), Some("data"));
+    assert!(filesys.is_file("a"));
+    assert_eq!(root_names(&mut filesys), vec!["a"]);
+  }
+  assert_eq!(root(&mut filesys).open_count, 1);
+  assert_eq!(root(&mut filesys).path.as_deref(), Some("/"));
+
+  /* Still pinned after a reload */
+  drop(filesys);
+  let mut filesys = reload(&image);
+  assert_eq!(filesys.cat("a").as_deref(), Some("data"));
+  assert_eq!(root(&mut filesys).open_count, 1);
+}
+
+#[test]
+fn a_removed_file_keeps_its_inode_while_a_handle_is_open() {
+  let image = TempImage::new("unlink_reuse");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"old").is_ok());
+  let inumber = shared.fs().stat("a").unwrap().inumber;
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  assert!(shared.fs().remove_file("a"));
+  assert!(shared.fs().create_file_with_data("b", b"new").is_ok());
+  assert_ne!(shared.fs().stat("b").unwrap().inumber, inumber);
+
+  let mut buffer = [0; 3];
+  assert!(!file.is_stale());
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, 0), 3);
+  assert_eq!(&buffer, b"old");
+  shared.fs().close_file(file);
+
+  /* Only now is the block free to be reused */
+  assert!(shared.fs().create_file("c", 0).is_ok());
+  assert_eq!(shared.fs().stat("c").unwrap().inumber, inumber);
+}
+
+#[test]
+fn offsets_that_overflow_the_seek_head_fail() {
+  let image = TempImage::new("seek_overflow");
+  let shared = Shared::new(image.build(64));
+  assert!(shared.fs().create_file_with_data("a", b"abc").is_ok());
+  let free = shared.fs().free_blocks();
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  assert!(file.seek(2));
+  assert!(!file.seek(Ofs::MAX));
+  assert_eq!(file.tell(), 2);
+
+  let mut buffer = [0; 4];
+  assert_eq!(shared.fs().file_read(&mut file, &mut buffer, Ofs::MAX), 0);
+  assert_eq!(shared.fs().file_write(&mut file, b"xy", Ofs::MAX), 0);
+  assert_eq!(shared.fs().file_write(&mut file, b"xy", Ofs::MIN), 0);
+  assert_eq!(file.tell(), 2);
+  assert_eq!(file.length(), 3);
+  assert_eq!(shared.fs().free_blocks(), free);
+  shared.fs().close_file(file);
+}
+
+#[test]
+fn host_counts_compare_cached_and_uncached_disks() {
+  let mut reads = Vec::new();
+  for strategy in [
+    BufferCacheStrategy::None,
+    BufferCacheStrategy::Lfu { capacity: 16 },
+  ] {
+    let image = TempImage::new("bypass_cost");
+    let mut filesys = cached(&image, 64, strategy);
+    let data = pattern(4 * BLOCK_SIZE as usize);
+    assert!(filesys.create_file_with_data("a", &data).is_ok());
+    filesys.reset_counters();
+
+    for _ in 0..5 {
+      assert_eq!(
+        filesys.read_range("a", 0, data.len() as Size),
+        Some(data.clone())
+      );
+    }
+    reads.push(filesys.host_counts().unwrap().reads);
+  }
+  assert!(reads[1] < reads[0], "{:?}", reads);
+  assert_eq!(reads[1], 0);
+}
+
+#[test]
+fn list_sorted_ignores_creation_order() {
+  let image = TempImage::new("list_sorted");
+  let mut filesys = image.build(64);
+  for name in ["delta", "Bravo", "alpha", "charlie"] {
+    assert!(filesys.create_file(name, 0).is_ok());
+  }
+  assert!(filesys.remove_file("alpha"));
+  assert!(filesys.create_file("echo", 0).is_ok());
+
+  assert_eq!(
+    filesys.list_sorted("", false).unwrap(),
+    vec!["Bravo", "charlie", "delta", "echo"]
+  );
+  assert!(filesys.create_file("apple", 0).is_ok());
+  assert_eq!(
+    filesys.list_sorted("", true).unwrap(),
+    vec!["apple", "Bravo", "charlie", "delta", "echo"]
+  );
+}
+
+#[test]
+fn path_of_inode_finds_the_name_from_stat() {
+  let image = TempImage::new("path_of_inode");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("a", 0).is_ok());
+  assert!(filesys.create_file("b", 100).is_ok());
+  let inumber = filesys.stat("b").unwrap().inumber;
+
+  assert_eq!(filesys.path_of_inode(inumber).as_deref(), Some("b"));
+  assert_eq!(filesys.path_of_inode(0).as_deref(), Some("/"));
+  assert!(filesys.remove_file("b"));
+  assert_eq!(filesys.path_of_inode(inumber), None);
+}
+
+#[test]
+fn raw_access_past_the_end_is_refused() {
+  let image = TempImage::new("raw_out_of_range");
+  let mut filesys = image.build(64);
+  let mut buffer = block::EMPTY_BLOCK;
+  assert!(!filesys.read_block(64, &mut buffer));
+  assert!(!filesys.write_block(1000, &buffer));
+  assert_eq!(
+    std::fs::metadata(&image.path).unwrap().len(),
+    64 * BLOCK_SIZE
+  );
+}
+
+#[test]
+#[should_panic(expected = "block out of range: 64 on device 'DISK' of 64 blocks")]
+fn device_access_past_the_end_names_the_block() {
+  let image = TempImage::new("device_out_of_range");
+  let mut filesys = image.build(64);
+  let disk = filesys.block_devs.get_by_role(DeviceType::Disk).unwrap();
+  let mut buffer = block::EMPTY_BLOCK;
+  disk.read(&mut buffer, 64);
+}
+
+#[test]
+fn resolve_reports_the_parent_leaf_and_type() {
+  let image = TempImage::new("resolve");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("c.txt", 10).is_ok());
+  add_directory(&mut filesys, "d");
+
+  let file = filesys.resolve("c.txt").unwrap();
+  assert_eq!(file.inumber, filesys.stat("c.txt").unwrap().inumber);
+  assert_eq!((file.parent, file.name.as_str()), (0, "c.txt"));
+  assert_eq!(file.file_type, FileType::Regular);
+
+  assert_eq!(filesys.resolve("d").unwrap().file_type, FileType::Directory);
+  assert!(filesys.resolve("missing").is_none());
+}
+
+#[test]
+fn chunks_rebuild_the_file_from_the_seek_head() {
+  let image = TempImage::new("chunks");
+  let shared = Shared::new(image.build(64));
+  let data = pattern(3 * BLOCK_SIZE as usize + 77);
+  assert!(shared.fs().create_file_with_data("a", &data).is_ok());
+
+  let mut file = shared.fs().open_file("a").unwrap();
+  let chunks: Vec<Vec<u8>> = shared.fs().file_chunks(&mut file).collect();
+  assert_eq!(chunks.len(), 4);
+  assert_eq!(chunks.last().unwrap().len(), 77);
+  assert_eq!(chunks.concat(), data);
+  assert!(file.at_eof());
+
+  /* A head part way into a block yields the rest of that block first */
+  file.seek_start();
+  assert!(file.seek(100));
+  let first = shared.fs().file_chunks(&mut file).next().unwrap();
+  assert_eq!(first.len(), BLOCK_SIZE as usize - 100);
+  assert_eq!(first, &data[100..BLOCK_SIZE as usize]);
+  shared.fs().close_file(file);
+}
+
+/* A disk behind an LFU cache whose host writes pass through a `FaultyDisk` */
+fn faulty_cached(image: &TempImage, fault: Fault) -> Filesys<'static> {
+  let vdisk = CountedDisk::new(VDisk::new(&image.path, 64));
+  let mut filesys = Filesys::init();
+  filesys.format_disk(LfuCacheDisk::new(FaultyDisk::new(vdisk, fault), 32), 64);
+  filesys.init_free_map();
+  assert!(filesys.sync());
+  filesys
+}
+
+/*
+  Crash at each host write `op` makes behind a cache, after `setup` has run without a fault, then
+  check the image left behind
+*/
+fn crash_at_each_write(
+  setup: impl Fn(&mut Filesys),
+  op: impl Fn(&mut Filesys),
+  check: impl Fn(&mut Filesys, usize),
+) {
+  let dry_run = TempImage::new("fault_cached_dry_run");
+  let mut filesys = faulty_cached(&dry_run, Fault::WriteTo(Size::MAX));
+  setup(&mut filesys);
+  let before = filesys.host_counts().unwrap().writes;
+  op(&mut filesys);
+  let after = filesys.host_counts().unwrap().writes;
+  assert!(after > before);
+
+  for nth in before + 1..=after {
+    let image = TempImage::new("fault_cached");
+    let mut filesys = faulty_cached(&image, Fault::NthWrite(nth));
+    setup(&mut filesys);
+    let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| op(&mut filesys)));
+    assert!(outcome.is_err(), "write {} did not fault", nth);
+    drop(filesys);
+
+    check(&mut reload(&image), nth);
+  }
+}
+
+#[test]
+fn a_crash_while_creating_a_file_never_leaves_a_dangling_entry() {
+  crash_at_each_write(
+    |_| {},
+    |filesys| {
+      assert!(filesys
+        .create_file_with_data("a", &pattern(2 * BLOCK_SIZE as usize))
+        .is_ok());
+      assert!(filesys.sync());
+    },
+    |filesys, nth| {
+      let report = filesys.integrity_report();
+      assert!(report.bad_inodes.is_empty(), "write {}: {:?}", nth, report);
+      assert_eq!(filesys.repair_dangling_entries(), 0, "write {}", nth);
+      if let Some(stat) = filesys.stat("a") {
+        assert_eq!(stat.file_type, FileType::Regular, "write {}", nth);
+      }
+    },
+  );
+}
+
+#[test]
+fn copy_range_handles_each_kind_of_overlap() {
+  let image = TempImage::new("copy_range");
+  let mut filesys = image.build(128);
+  let data = pattern(4 * BLOCK_SIZE as usize);
+  let block = BLOCK_SIZE as usize;
+
+  /* (source, destination, length) */
+  for (src, dst, len) in [
+    (0, 2 * block, block),
+    (100, 100 + block / 2, 2 * block),
+    (100 + block / 2, 100, 2 * block),
+  ] {
+    assert!(filesys.create_file_with_data("a", &data).is_ok());
+    assert_eq!(
+      filesys.copy_range("a", src as Ofs, dst as Ofs, len as Size),
+      len as Ofs
+    );
+
+    let mut expected = data.clone();
+    expected.copy_within(src..src + len, dst);
+    assert_eq!(
+      filesys.read_range("a", 0, data.len() as Size),
+      Some(expected),
+      "{} -> {}",
+      src,
+      dst
+    );
+    assert!(filesys.remove_file("a"));
+  }
+}
+
+#[test]
+fn copy_range_grows_the_file_and_stops_at_the_source_end() {
+  let image = TempImage::new("copy_range_grow");
+  let mut filesys = image.build(128);
+  assert!(filesys.create_file_with_data("a", b"0123456789").is_ok());
+
+  assert_eq!(filesys.copy_range("a", 6, 20, 100), 4);
+  let mut expected = b"0123456789".to_vec();
+  expected.resize(20, 0);
+  expected.extend_from_slice(b"6789");
+  assert_eq!(filesys.read_range("a", 0, 100), Some(expected));
+
+  assert_eq!(filesys.copy_range("a", -1, 0, 4), 0);
+  assert_eq!(filesys.copy_range("missing", 0, 0, 4), 0);
+}
+
+#[test]
+fn files_are_limited_to_what_an_inode_can_address() {
+  let image = TempImage::new("max_file_size");
+  let max_blocks = Filesys::max_file_size() / BLOCK_SIZE;
+  let mut filesys = image.build(max_blocks + 256);
+  let free = filesys.free_blocks();
+
+  assert_eq!(
+    filesys.create_file("over", Filesys::max_file_size() + 1),
+    Err(DirError::TooLarge)
+  );
+  assert_eq!(filesys.free_blocks(), free);
+  assert_eq!(filesys.file_type("over"), None);
+
+  assert!(filesys.create_file("max", Filesys::max_file_size()).is_ok());
+  assert_eq!(filesys.stat("max").unwrap().len, Filesys::max_file_size());
+  assert!(!filesys.set_file_len("max", Filesys::max_file_size() + 1));
+  assert_eq!(filesys.stat("max").unwrap().len, Filesys::max_file_size());
+}
+
+#[test]
+fn over_long_names_are_reported_by_every_naming_api() {
+  let image = TempImage::new("name_too_long");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("short", 0).is_ok());
+  let long = "n".repeat(NAME_MAX + 1);
+  let too_long = NameError::TooLong { len: NAME_MAX + 1 };
+
+  assert_eq!(filesys.create_file(&long, 0), Err(DirError::Name(too_long)));
+  assert_eq!(
+    filesys.create_file_with_data(&long, b"data"),
+    Err(DirError::Name(too_long))
+  );
+  assert_eq!(filesys.touch(&long), Err(DirError::Name(too_long)));
+  assert_eq!(
+    filesys.rename("short", &long, false),
+    Err(RenameError::Name(too_long))
+  );
+  assert_eq!(
+    filesys.rename(&long, "short", true),
+    Err(RenameError::Name(too_long))
+  );
+  assert_eq!(root_names(&mut filesys), vec!["short"]);
+
+  /* Exactly NAME_MAX bytes is still allowed */
+  assert!(filesys.touch(&long[1..]).is_ok());
+}
+
+#[test]
+fn multi_byte_names_round_trip() {
+  let image = TempImage::new("utf8_names");
+  let names = ["naïve-日本", "ünïcödé", "🦀crab"];
+  {
+    let mut filesys = image.build(64);
+    for name in names {
+      assert!(Filesys::check_name(name).is_ok(), "{}", name);
+      assert!(filesys.create_file_with_data(name, name.as_bytes()).is_ok());
+    }
+    assert_eq!(filesys.rename("🦀crab", "крабы", false), Ok(None));
+    assert!(filesys.sync());
+  }
+
+  let mut filesys = reload(&image);
+  let mut listed = root_names(&mut filesys);
+  listed.sort();
+  let mut expected = vec!["naïve-日本", "ünïcödé", "крабы"];
+  expected.sort();
+  assert_eq!(listed, expected);
+  assert_eq!(filesys.cat("naïve-日本").as_deref(), Some("naïve-日本"));
+  assert_eq!(filesys.cat("крабы").as_deref(), Some("🦀crab"));
+
+  /* Six characters, but eighteen bytes */
+  assert_eq!(
+    Filesys::check_name("日本語日本語"),
+    Err(NameError::TooLong { len: 18 })
+  );
+  assert_eq!(
+    filesys.create_file("日本語日本語", 0),
+    Err(DirError::Name(NameError::TooLong { len: 18 }))
+  );
+}
+
+#[test]
+fn a_clone_keeps_the_live_files_in_fewer_blocks() {
+  let image = TempImage::new("clone_source");
+  let copy = TempImage::new("clone_copy");
+  let mut filesys = image.build(256);
+  for i in 0..12 {
+    let data = pattern((i + 1) * 700);
+    assert!(filesys
+      .create_file_with_data(&format!("f{}", i), &data)
+      .is_ok());
+  }
+  assert!(filesys.create_file("high", 100 * BLOCK_SIZE).is_ok());
+  for i in (0..12).step_by(3) {
+    assert!(filesys.remove_file(&format!("f{}", i)));
+  }
+  assert!(filesys.remove_file("high"));
+  assert!(filesys
+    .create_file_with_data("late", b"written after the removals")
+    .is_ok());
+  let mut names = root_names(&mut filesys);
+  names.sort();
+
+  assert!(filesys.clone_to(&copy.path));
+  let host_len = |image: &TempImage| std::fs::metadata(&image.path).unwrap().len();
+  assert!(host_len(&copy) < host_len(&image));
+
+  let mut clone = reload(&copy);
+  let mut cloned = root_names(&mut clone);
+  cloned.sort();
+  assert_eq!(cloned, names);
+  for name in &names {
+    let len = filesys.stat(name).unwrap().len;
+    assert_eq!(
+      clone.read_range(name, 0, len),
+      filesys.read_range(name, 0, len),
+      "{}",
+      name
+    );
+  }
+  assert!(clone.verify_free_map().is_empty());
+}
+
+#[test]
+fn a_sequential_read_reads_each_pointer_block_once() {
+  let image = TempImage::new("ptr_cache");
+  let shared = Shared::new(image.build(512));
+  /* Past the single indirect block, so the doubly indirect block and its children are used */
+  let len = 400 * BLOCK_SIZE;
+  assert!(shared
+    .fs()
+    .create_file_with_data("big", &pattern(len as usize))
+    .is_ok());
+  let blocks = file_blocks(shared.fs(), "big");
+
+  let mut file = shared.fs().open_file("big").unwrap();
+  shared.fs().set_trace(100_000);
+  let mut buffer = vec![0; BLOCK_SIZE as usize];
+  while shared.fs().file_read(&mut file, &mut buffer, 0) > 0 {}
+  let reads: Vec<Size> = shared
+    .fs()
+    .trace_log()
+    .iter()
+    .filter(|event| event.op == block::TraceOp::Read)
+    .map(|event| event.block)
+    .collect();
+  shared.fs().set_trace(0);
+  shared.fs().close_file(file);
+
+  /* Every data and pointer block once, where each call used to re-read its pointer blocks */
+  let mut distinct = reads.clone();
+  distinct.sort_unstable();
+  distinct.dedup();
+  assert_eq!(distinct.len(), reads.len());
+  assert_eq!(distinct, blocks);
+}
+
+#[test]
+fn set_file_len_grows_and_shrinks_the_blocks_with_the_length() {
+  let image = TempImage::new("set_file_len");
+  let mut filesys = image.build(256);
+  let data = pattern(2 * BLOCK_SIZE as usize + 100);
+  assert!(filesys.create_file_with_data("a", &data).is_ok());
+  let free = filesys.free_blocks();
+  let blocks = filesys.stat("a").unwrap().blocks;
+
+  assert!(filesys.set_file_len("a", 10 * BLOCK_SIZE));
+  let grown = filesys.stat("a").unwrap();
+  assert_eq!(grown.len, 10 * BLOCK_SIZE);
+  assert_eq!(free - filesys.free_blocks(), grown.blocks - blocks);
+  let mut expected = data.clone();
+  expected.resize(10 * BLOCK_SIZE as usize, 0);
+  assert_eq!(filesys.read_range("a", 0, 10 * BLOCK_SIZE), Some(expected));
+
+  assert!(filesys.set_file_len("a", 50));
+  assert_eq!(filesys.stat("a").unwrap().len, 50);
+  assert_eq!(
+    filesys.free_blocks(),
+    free + blocks - filesys.stat("a").unwrap().blocks
+  );
+
+  /* The bytes cut off do not come back when it grows again */
+  assert!(filesys.set_file_len("a", 200));
+  let mut expected = data[..50].to_vec();
+  expected.resize(200, 0);
+  assert_eq!(filesys.read_range("a", 0, 200), Some(expected));
+
+  assert!(!filesys.set_file_len("missing", 10));
+  assert!(filesys.verify_free_map().is_empty());
+}
+
+#[test]
+fn the_free_map_inode_is_closed_between_uses() {
+  let image = TempImage::new("free_map_inode");
+  let free_map_open = |filesys: &Filesys| filesys.inodes.open_count(super::FREE_MAP_INODE);
+  {
+    let mut filesys = image.build(128);
+    assert_eq!(free_map_open(&filesys), 0);
+    assert!(filesys.create_file_with_data("a", b"data").is_ok());
+    assert!(filesys.sync());
+    assert!(filesys.sync());
+    assert_eq!(free_map_open(&filesys), 0);
+    assert!(filesys.shrink_disk());
+    assert_eq!(free_map_open(&filesys), 0);
+  }
+
+  let mut filesys = reload(&image);
+  assert_eq!(free_map_open(&filesys), 0);
+  assert_eq!(filesys.cat("a").as_deref(), Some("data"));
+  assert!(filesys.sync());
+  assert_eq!(free_map_open(&filesys), 0);
+  assert!(filesys
+    .open_handles()
+    .iter()
+    .all(|handle| handle.inumber != super::FREE_MAP_INODE));
+}
+
+#[test]
+fn free_extents_show_a_hole_left_by_a_removal() {
+  let image = TempImage::new("free_extents");
+  let mut filesys = image.build(64);
+  assert!(filesys.create_file("a", 2 * BLOCK_SIZE).is_ok());
+  assert!(filesys.create_file("b", 3 * BLOCK_SIZE).is_ok());
+  assert!(filesys.create_file("c", 2 * BLOCK_SIZE).is_ok());
+  let tail = filesys.free_extents();
+  assert_eq!(tail.len(), 1);
+  assert_eq!(tail[0].0 + tail[0].1, 64);
+
+  let b = filesys.stat("b").unwrap();
+  let mut freed = file_blocks(&mut filesys, "b");
+  freed.push(b.inumber);
+  freed.sort_unstable();
+  assert!(contiguous(&freed));
+  assert!(filesys.remove_file("b"));
+
+  assert_eq!(filesys.free_extents(), vec![(freed[0], b.blocks), tail[0]]);
+  assert_eq!(filesys.free_blocks(), b.blocks + tail[0].1);
+}
+
+#[test]
+fn synchronous_directory_updates_survive_a_crash_before_any_flush() {
+  for sync_dirs in [false, true] {
+    let image = TempImage::new("sync_dirs");
+    {
+      let mut filesys = cached(&image, 64, BufferCacheStrategy::Lfu { capacity: 32 });
+      assert!(filesys.create_file_with_data("old", b"kept").is_ok());
+      assert!(filesys.create_file("gone", 0).is_ok());
+      assert!(filesys.sync());
+
+      filesys.set_sync_dirs(sync_dirs);
+      assert!(filesys
+        .create_file_with_data("new", &pattern(3 * BLOCK_SIZE as usize))
+        .is_ok());
+      assert!(filesys.rename_file("old", "moved"));
+      assert!(filesys.remove_file("gone"));
+
+      /* A crash: the cache's dirty blocks never reach the host */
+      std::mem::forget(filesys);
+    }
+
+    let mut filesys = reload(&image);
+    let mut expected = if sync_dirs {
+      vec!["moved", "new"]
+    } else {
+      vec!["gone", "old"]
+    };
+    expected.sort();
+    let mut names = root_names(&mut filesys);
+    names.sort();
+    assert_eq!(names, expected, "sync_dirs {}", sync_dirs);
+    assert!(
+      filesys.verify_free_map().is_empty(),
+      "sync_dirs {}",
+      sync_dirs
+    );
+    if sync_dirs {
+      assert_eq!(filesys.stat("new").unwrap().len, 3 * BLOCK_SIZE);
+      assert_eq!(filesys.cat("moved").as_deref(), Some("kept"));
+    }
+  }
+}
+
+#[test]
+fn a_crash_while_rewriting_leaves_the_old_or_the_new_contents() {
+  let old = pattern(2 * BLOCK_SIZE as usize + 10);
+  let new: Vec<u8> = pattern(3 * BLOCK_SIZE as usize)
+    .iter()
+    .map(|b| b ^ 0xff)
+    .collect();
+  let outcomes = RefCell::new((0, 0));
+
+  crash_at_each_write(
+    |filesys| {
+      assert!(filesys.create_file_with_data("a", &old).is_ok());
+      assert!(filesys.sync());
+    },
+    |filesys| {
+      assert!(filesys.rewrite_file("a", &new));
+      assert!(filesys.sync());
+      /* The cache writes back in no fixed order, so later writes make sure some crash follows it */
+      assert!(filesys.create_file_with_data("b", b"after").is_ok());
+      assert!(filesys.sync());
+    },
+    |filesys, nth| {
+      /* Without the flush before the repoint, the entry can reach the host ahead of its inode */
+      let report = filesys.integrity_report();
+      assert!(report.bad_inodes.is_empty(), "write {}: {:?}", nth, report);
+      let len = filesys.stat("a").expect("the name survives").len;
+      let contents = filesys.read_range("a", 0, len).unwrap();
+      let mut outcomes = outcomes.borrow_mut();
+      if contents == old {
+        outcomes.0 += 1;
+      } else {
+        assert!(contents == new, "write {}: neither old nor new", nth);
+        outcomes.1 += 1;
+      }
+    },
+  );
+
+  /* Crashes either side of the entry being repointed were both tried */
+  let (kept, replaced) = outcomes.into_inner();
+  assert!(kept > 0 && replaced > 0, "{} old, {} new", kept, replaced);
+}
@@ -8,6 +8,9 @@ use std::{
 
 pub struct VDisk {
   host: File,
+  /* Reads made to check a write landed, as debug builds do unless `set_debug_io` turned it off */
+  #[cfg(all(test, debug_assertions))]
+  verify_reads: usize,
 }
 
 impl VDisk {
@@ -27,7 +30,11 @@ impl VDisk {
     host
       .set_len(disk_block_count * block::BLOCK_SIZE)
       .expect("unable to set host file size");
-    VDisk { host }
+    VDisk {
+      host,
+      #[cfg(all(test, debug_assertions))]
+      verify_reads: 0,
+    }
   }
 
   pub fn identify(host_path: &str) -> (Self, Size) {
@@ -40,7 +47,12 @@ impl VDisk {
     let host_size = host.metadata().expect("unable to query metadata").len();
     assert_eq!(host_size % block::BLOCK_SIZE, 0);
 
-    (VDisk { host }, host_size / block::BLOCK_SIZE)
+    let vdisk = VDisk {
+      host,
+      #[cfg(all(test, debug_assertions))]
+      verify_reads: 0,
+    };
+    (vdisk, host_size / block::BLOCK_SIZE)
   }
 }
 
@@ -50,11 +62,14 @@ impl VDisk {
 const SEEK_ERR: &str = "could not seek file";
 const READ_ERR: &str = "could not read all bytes to buffer";
 const WRITE_ERR: &str = "could not write all bytes to file";
+const SYNC_ERR: &str = "could not sync host file";
 
 impl block::BlockOperations for VDisk {
   fn read(&mut self, buf: &mut [u8; block::BLOCK_USIZE], pos: Size) {
     #[cfg(feature = "debug")]
-    println!("Disk reading block {}", pos);
+    if super::debug_io() {
+      println!("Disk reading block {}", pos);
+    }
 
     self.host
       .seek(std::io::SeekFrom::Start(pos * block::BLOCK_SIZE))
@@ -66,7 +81,9 @@ impl block::BlockOperations for VDisk {
 
   fn write(&mut self, buf: &[u8; block::BLOCK_USIZE], pos: Size) {
     #[cfg(feature = "debug")]
-    println!("Disk writing block {}", pos);
+    if super::debug_io() {
+      println!("Disk writing block {}", pos);
+    }
 
     self.host
       .seek(std::io::SeekFrom::Start(pos * block::BLOCK_SIZE))
@@ -76,10 +93,52 @@ impl block::BlockOperations for VDisk {
       .expect(WRITE_ERR);
 
     #[cfg(debug_assertions)]
-    {
+    if super::debug_io() {
       let mut temp = [u8::MAX; block::BLOCK_USIZE];
       self.read(&mut temp, pos);
       assert_eq!(temp, *buf);
-    };
+
+      #[cfg(all(test, debug_assertions))]
+      {
+        self.verify_reads += 1;
+      }
+    }
+  }
+
+  fn flush(&mut self) {
+    self.host.sync_data().expect(SYNC_ERR);
+  }
+
+  fn resize(&mut self, blocks: Size) -> bool {
+    self.host.set_len(blocks * block::BLOCK_SIZE).is_ok()
+  }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+  use super::*;
+  use crate::filesys::{set_debug_io, tests::TempImage};
+  use block::BlockOperations;
+
+  #[test]
+  fn writes_are_only_read_back_while_debug_io_is_on() {
+    let image = TempImage::new("debug_io");
+    let mut vdisk = VDisk::new(&image.path, 4);
+    let mut buf = [7; block::BLOCK_USIZE];
+
+    vdisk.write(&buf, 0);
+    assert_eq!(vdisk.verify_reads, 1);
+
+    /* Process-wide, so put back for the tests running alongside */
+    set_debug_io(false);
+    vdisk.write(&buf, 1);
+    vdisk.write(&buf, 2);
+    set_debug_io(true);
+    assert_eq!(vdisk.verify_reads, 1);
+
+    vdisk.read(&mut buf, 2);
+    assert_eq!(buf, [7; block::BLOCK_USIZE]);
+    vdisk.write(&buf, 3);
+    assert_eq!(vdisk.verify_reads, 2);
   }
 }
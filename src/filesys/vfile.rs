@@ -3,13 +3,19 @@ use std::cell::RefCell;
 use crate::{Ofs, Size};
 
 use super::{
-  block::BlockDevice,
+  block::{self, BlockDevice},
+  free_map::FreeMap,
   inode::{Inode, InodeManager},
 };
 
 pub struct VFile<'a> {
   pos: Ofs,
   inode: RefCell<&'a mut Inode>,
+  /* Generation of the inode when opened. Reads and writes stop once it changes */
+  generation: Size,
+  writable: bool,
+  /* Blocks past the seek head loaded into the disk's cache after each read */
+  readahead: Size,
 }
 
 impl<'a> VFile<'a> {
@@ -18,36 +24,141 @@ impl<'a> VFile<'a> {
     Initialisation and closure
   */
 
-  pub fn open(inode: RefCell<&'a mut Inode>) -> Self {
-    VFile { pos: 0, inode }
+  pub fn open(inode: RefCell<&'a mut Inode>, writable: bool) -> Self {
+    let generation = inode.borrow().generation();
+    VFile {
+      pos: 0,
+      inode,
+      generation,
+      writable,
+      readahead: 0,
+    }
   }
 
   pub fn close(self, inodes: &mut InodeManager) {
     inodes.close(self.inode);
   }
 
-  /* Read is deferred to inode, seek head is moved */
+  /* Another handle on the same inode, starting at the same position but moving independently */
+  pub fn dup<'b>(&self, inodes: &'b mut InodeManager, disk: &mut BlockDevice) -> VFile<'b> {
+    let inumber = self.inode.borrow().inumber();
+    VFile {
+      pos: self.pos,
+      inode: inodes.open_inode(inumber, disk),
+      generation: self.generation,
+      writable: self.writable,
+      readahead: self.readahead,
+    }
+  }
+
+  /* Read is deferred to inode, seek head is moved. Nothing is read if the offset overflows */
   pub fn read(&mut self, buffer: &mut [u8], offset: Ofs, disk: &mut BlockDevice) -> Ofs {
-    let bytes_read = self
-      .inode
-      .borrow_mut()
-      .read_at(buffer, self.pos + offset, disk);
+    let Some(start) = self.pos.checked_add(offset) else {
+      return 0;
+    };
+    if self.is_stale() {
+      return 0;
+    }
+
+    let bytes_read = self.inode.borrow_mut().read_at(buffer, start, disk);
 
     self.seek(bytes_read);
+    if bytes_read > 0 && self.readahead > 0 {
+      let window = self.readahead * block::BLOCK_SIZE;
+      let blocks = self.inode.borrow().data_blocks(self.pos, window, disk);
+      blocks.into_iter().for_each(|block| disk.prefetch(block));
+    }
     bytes_read
   }
 
-  /* Write is deferred to inode, seek head is moved */
+  /* Read at the seek head without moving it */
+  pub fn peek(&self, buffer: &mut [u8], disk: &mut BlockDevice) -> Ofs {
+    self.read_at_abs(self.pos, buffer, disk)
+  }
+
+  /* Read at an offset from the start of the file, leaving the seek head alone */
+  pub fn read_at_abs(&self, offset: Ofs, buffer: &mut [u8], disk: &mut BlockDevice) -> Ofs {
+    if self.is_stale() {
+      return 0;
+    }
+    self.inode.borrow().read_at(buffer, offset, disk)
+  }
+
+  /* Bytes from the seek head up to the end of its block, or None at end of file. Moves the head */
+  pub fn next_chunk(&mut self, disk: &mut BlockDevice) -> Option<Vec<u8>> {
+    let block_left = block::BLOCK_SIZE as Ofs - self.pos.rem_euclid(block::BLOCK_SIZE as Ofs);
+    let mut chunk = vec![0; block_left as usize];
+
+    let bytes_read = self.read(&mut chunk, 0, disk);
+    if bytes_read <= 0 {
+      return None;
+    }
+
+    chunk.truncate(bytes_read as usize);
+    Some(chunk)
+  }
+
+  /* Successive chunks to the end of the file, the last of which may be short */
+  pub fn chunks<'d, 'b>(
+    &'d mut self,
+    disk: &'d mut BlockDevice<'b>,
+  ) -> impl Iterator<Item = Vec<u8>> + use<'d, 'a, 'b> {
+    std::iter::from_fn(move || self.next_chunk(disk))
+  }
+
+  /* Write is deferred to inode, seek head is moved. Nothing is written if the offset overflows */
   pub fn write(&mut self, buffer: &[u8], offset: Ofs, disk: &mut BlockDevice) -> Ofs {
-    let bytes_written = self
-      .inode
-      .borrow_mut()
-      .write_at(buffer, self.pos + offset, disk);
+    let Some(start) = self.pos.checked_add(offset) else {
+      return 0;
+    };
+    if self.is_stale() || !self.writable {
+      return 0;
+    }
+
+    let bytes_written = self.inode.borrow_mut().write_at(buffer, start, disk);
 
     self.seek(bytes_written);
     bytes_written
   }
 
+  /*
+    Grow the file so that `len` bytes at `offset` past the seek head fit, zeroing any gap between
+    the old end and `offset`. False if out of space, the handle is read-only or the end overflows
+  */
+  pub fn extend(
+    &mut self,
+    len: Size,
+    offset: Ofs,
+    free_map: &mut FreeMap,
+    disk: &mut BlockDevice,
+  ) -> bool {
+    let Some(start) = self.pos.checked_add(offset) else {
+      return false;
+    };
+    if !self.writable {
+      return false;
+    }
+    if start < 0 || self.is_stale() {
+      return true;
+    }
+
+    let Some(end) = (start as Size).checked_add(len) else {
+      return false;
+    };
+    let mut inode = self.inode.borrow_mut();
+    let old_len = inode.length();
+    if end <= old_len {
+      return true;
+    }
+    if !inode.set_len(end, free_map, disk) {
+      return false;
+    }
+
+    /* The write covers everything from `start`, so only a gap before it needs zeroing */
+    inode.zero_range(old_len, std::cmp::max(start as Size, old_len), disk);
+    true
+  }
+
   /*
     Utility functions
     Public to users
@@ -61,14 +172,47 @@ impl<'a> VFile<'a> {
     self.pos = 0;
   }
 
-  pub fn seek(&mut self, offset: Ofs) {
-    self.pos += offset;
+  /* Move the seek head by `offset`. False, leaving it where it was, if that overflows */
+  pub fn seek(&mut self, offset: Ofs) -> bool {
+    match self.pos.checked_add(offset) {
+      Some(pos) => {
+        self.pos = pos;
+        true
+      }
+      None => false,
+    }
   }
 
   pub fn tell(&self) -> Ofs {
     self.pos
   }
 
+  /* Whether the seek head is at or past the end, so a read from it returns nothing */
+  pub fn at_eof(&self) -> bool {
+    self.pos >= self.length() as Ofs
+  }
+
+  pub fn is_writable(&self) -> bool {
+    self.writable
+  }
+
+  /*
+    After each read, load the `blocks` following the seek head into the disk's buffer cache, so a
+    sequential reader finds them there. Zero, the default, turns it off. Does nothing without a
+    cache
+  */
+  pub fn set_readahead(&mut self, blocks: usize) {
+    self.readahead = blocks as Size;
+  }
+
+  /*
+    The inode's block was given to another file since this handle was opened. `unlink` holds the
+    block of an open inode until its last handle closes, so this only guards that guarantee
+  */
+  pub fn is_stale(&self) -> bool {
+    self.inode.borrow().generation() != self.generation
+  }
+
   pub fn compare(&self, other: &VFile) -> bool {
     self.inode.borrow().inumber() == other.inode.borrow().inumber()
   }